@@ -0,0 +1,121 @@
+//! Measures the throughput of handle_commands, the locking and dispatch hot path, over large synthetic command streams
+//!
+//! The streams are generated from a fixed seed, so every run measures the same commands.
+//! They are built to succeed, so the measurement is not dominated by logging failures.
+//!
+//! Run with `cargo bench --bench handler`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rust_decimal::prelude::Decimal;
+
+use transaction_parser::client_data::{ClientData, ClientID, TransactionID};
+use transaction_parser::command::{Command, CommandType};
+use transaction_parser::command_handler::HandlerOptions;
+
+// The count of commands in each stream
+const COMMANDS: usize = 100_000;
+
+// The count of clients the commands are spread across
+const CLIENTS: u64 = 1_000;
+
+const SEED: u64 = 0x5EED_1234_ABCD_0001;
+
+// A xorshift generator, so the streams are deterministic without depending on a random number crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+// Mostly deposits, with small withdrawals which the deposits before them cover
+fn deposit_heavy() -> Vec<Command> {
+    let mut rng = Rng(SEED);
+    let mut deposited: HashMap<ClientID, u64> = HashMap::new();
+    (0..COMMANDS).map(|i| {
+        let client_id = rng.below(CLIENTS) as ClientID;
+        let transaction_id = i as TransactionID + 1;
+        let balance = deposited.entry(client_id).or_default();
+        if *balance > 0 && rng.below(10) == 0 {
+            let ammount = 1 + rng.below(*balance);
+            *balance -= ammount;
+            Command::new(CommandType::Withdraw, client_id, transaction_id, Some(Decimal::new(ammount as i64, 2)))
+        }
+        else {
+            let ammount = 1 + rng.below(100_000);
+            *balance += ammount;
+            Command::new(CommandType::Deposit, client_id, transaction_id, Some(Decimal::new(ammount as i64, 2)))
+        }
+    }).collect()
+}
+
+// Deposits, most of which are disputed and then resolved, interleaved across clients
+fn dispute_heavy() -> Vec<Command> {
+    let mut rng = Rng(SEED);
+    let mut undisputed: Vec<(ClientID, TransactionID)> = Vec::new();
+    let mut disputed: Vec<(ClientID, TransactionID)> = Vec::new();
+    let mut next_tx: TransactionID = 1;
+    (0..COMMANDS).map(|_| {
+        match rng.below(3) {
+            1 if !undisputed.is_empty() => {
+                let (client_id, transaction_id) = undisputed.swap_remove(rng.below(undisputed.len() as u64) as usize);
+                disputed.push((client_id, transaction_id));
+                Command::new(CommandType::Dispute, client_id, transaction_id, None)
+            },
+            2 if !disputed.is_empty() => {
+                let (client_id, transaction_id) = disputed.swap_remove(rng.below(disputed.len() as u64) as usize);
+                undisputed.push((client_id, transaction_id));
+                Command::new(CommandType::Resolve, client_id, transaction_id, None)
+            },
+            _ => {
+                let client_id = rng.below(CLIENTS) as ClientID;
+                let transaction_id = next_tx;
+                next_tx += 1;
+                undisputed.push((client_id, transaction_id));
+                Command::new(CommandType::Deposit, client_id, transaction_id, Some(Decimal::new(1 + rng.below(100_000) as i64, 2)))
+            },
+        }
+    }).collect()
+}
+
+// Sends the commands through a channel to handle_commands, as the binary does
+async fn handle(commands: Vec<Command>) {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let data: Arc<Mutex<HashMap<ClientID, Box<ClientData>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let handler = tokio::spawn(transaction_parser::handle_commands(data, rx, HandlerOptions::default()));
+    for cmd in commands {
+        tx.send(cmd).await.unwrap();
+    }
+    drop(tx);
+    let stats = handler.await.unwrap().unwrap();
+    assert_eq!(0, stats.failed);
+}
+
+fn bench_handler(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("handle_commands");
+    group.throughput(Throughput::Elements(COMMANDS as u64));
+    group.sample_size(20);
+
+    for (name, generate) in [("deposit_heavy", deposit_heavy as fn() -> Vec<Command>), ("dispute_heavy", dispute_heavy)] {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter_batched(generate, handle, BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_handler);
+criterion_main!(benches);
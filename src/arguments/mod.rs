@@ -0,0 +1,759 @@
+//! # arguments module
+//! This module separates logic for interpreting the program's command line arguments.
+//! 
+//! The transactions csv file path is the only positional argument; flags may appear before or after it.
+//! It is omitted under --listen, when the transactions are read from a TCP connection instead, and under --dir, when they are read from a directory of files.
+//! 
+//! # Flags
+//! 
+//! --with-counts       adds a tx_count column to the output
+//! --delimiter <char>  the delimiter of the input csv, a single ascii character; `tab` or `\t` may be given for tab delimited files
+//! --with-totals     appends a summary row, with the client TOTAL, of the balances summed across all clients
+//! --clamp-negative  reports negative available and total balances as zero, warning of their true value
+//! --locked-only     outputs only the locked (frozen) accounts, such as for a compliance review
+//! --format <csv|fixed>  whether the output is csv, or fixed-width columns for systems which cannot read csv; csv by default
+//! --bool-format <text|int>  whether the locked column is written true and false (text), or 1 and 0 (int); text by default
+//! --columns <list>    writes only the output columns listed, in the order listed, such as `client,total,locked`; of client, available, held, total, locked, and tx_count
+//! --match-delimiter   uses the input delimiter for the output csv as well
+//! --statements <path> writes each client's retained deposits to a statement csv at the path
+//! --tee <path>        writes the output to a file at the path as well as to stdout
+//! --manifest <path>   writes a JSON summary of the run, with its input, command counts, warnings, and exit status, to the path
+//! --quiet-rounding    skips warnings about balances which lose precision when rounded for output
+//! --max-amount <decimal>  skips deposits and withdrawals above the amount
+//! --stream-output     copies the fields written from the client data under its lock, then formats the output without it, rather than formatting under the lock
+//! --max-chargebacks <count>  halts processing once the count of chargebacks succeed
+//! --max-open-disputes <count>  rejects a client's disputes while they already have the count of disputes open; unlimited by default
+//! --reorder-window <count>   buffers the count of commands, dispatching them in tx order to smooth minor out-of-order arrivals
+//! --reorder-by-time   orders the commands within the reorder window by their time column rather than by tx
+//! --audit             checks that client totals reconcile against the money moved by commands
+//! --audit-log <path>  writes a csv line per successful command, with the client's balances before and after it, to the path
+//! --column-map <from=to,...>  renames input headers to the canonical type, client, tx, and amount, such as `kind=type,cust=client`
+//! --anomaly-report    warns about clients with several chargebacks, or who dispute more than half as often as they deposit
+//! --stats           logs the count, sum, min, max, and mean of the deposit and withdrawal amounts received
+//! --dedupe-deposits  treats a deposit resent with the tx and amount of an earlier deposit as a retry, rather than a duplicate
+//! --allow-negative-adjustment  lets adjustment debits leave an account's available funds negative
+//! --disable <type>    skips commands of the type, such as dispute, entirely; may be repeated to disable several types
+//! --include-empty     outputs a zero-balance row for every client referenced in the input, even by failed commands
+//! --parallel-parse    deserializes shards of the file concurrently, using every core, before dispatching them in file order
+//! --mode <strict|lenient>  whether any anomaly, such as a malformed row or a rejected command, aborts the run (strict) or is logged and skipped (lenient); lenient by default
+//! --strict            the same as --mode strict
+//! --max-warnings <count>  writes at most the count of warnings, then one line noting further warnings are suppressed; unlimited by default
+//! --progress          logs the count of commands processed every 100,000 commands
+//! --checkpoint <path> saves the client data to a checkpoint at the path periodically, and when the run ends
+//! --resume <path>     starts from the client data in a checkpoint, skipping the commands it already reflects
+//! --seed-from <path>  starts from the balances in a previous run's output csv, such as yesterday's; not with --resume
+//! --client-range <min>..<max>  skips commands for clients outside the inclusive range, such as 1..65535 to reserve client 0
+//! --listen <addr>    reads the transactions from a single TCP connection to the address, such as 127.0.0.1:7878, rather than a file
+//! --dir <path>        reads the transactions from every csv file in the directory, in order of file name, rather than a file; not with --resume
+//! --open-retries <count>  retries opening the transactions csv the count of times, with exponential backoff, unless it is missing
+//! --max-record-bytes <count>  rejects input records longer than the count of bytes, skipping them unless strict; 65536 by default
+//!                     Under --parallel-parse the whole file is read into memory first, so this does not bound memory there
+//! --trim <all|headers|fields|none>  which of the input headers and fields have surrounding whitespace trimmed; all by default
+//! --strip-currency    removes currency symbols and codes around amounts, such as `$5.00` or `5.00 USD`, before parsing them
+//! --schema <exact|allow-extras|unchecked>  whether the headers must be type, client, tx, amount, and optionally time, in any order, or may also have other columns; unchecked by default
+//! --units <dollars|cents>  whether the amount column is decimal dollars, or integer cents; dollars by default
+//! --frozen-policy <block-all|allow-deposits>  whether frozen accounts still accept deposits; block-all by default
+//! --threads <count>  the count of worker threads the runtime uses, at least 1; the count of cores by default.
+//!                     Commands are handled by a single task, so this only matters once the handler is parallelized
+//! --amount-policy <require|zero|reject>  whether a deposit, withdrawal, or adjustment without an amount is warned of and skipped (require),
+//!                     treated as zero (zero), or halts processing (reject); require by default
+//! --strict-tx         reports disputes, resolves, and chargebacks which name a client other than the tx's owner
+//! --strict-frozen     treats a deposit to a frozen account as an error which halts processing, rather than warning of it and skipping it
+//! 
+
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use rust_decimal::prelude::Decimal;
+
+use crate::client_data::{ClientID, FrozenPolicy};
+use crate::command::CommandType;
+use crate::command_handler::AmountPolicy;
+use crate::mode::Mode;
+use crate::transaction_csv::{BoolFormat, Column, OutputFormat, Schema, Units, FIXED_WIDTHS, MAX_RECORD_BYTES};
+
+const USAGE: &str = "Example: `./transaction_parser \"C:\\input.csv\"`";
+
+/// The options requested on the command line
+#[derive(Debug, PartialEq)]
+pub struct Arguments {
+    /// the transactions csv; empty under --listen or --dir
+    pub file_path: String,
+    /// the address to read the transactions from, rather than a file
+    pub listen: Option<String>,
+    /// the directory of transactions csv files to read, rather than a file
+    pub dir: Option<String>,
+    pub with_counts: bool,
+    pub with_totals: bool,
+    pub clamp_negative: bool,
+    pub locked_only: bool,
+    pub format: OutputFormat,
+    pub bool_format: BoolFormat,
+    /// the output columns, in order; None writes them all
+    pub columns: Option<Vec<Column>>,
+    pub delimiter: u8,
+    pub match_delimiter: bool,
+    pub statements: Option<String>,
+    pub tee: Option<String>,
+    pub quiet_rounding: bool,
+    pub max_amount: Option<Decimal>,
+    pub stream_output: bool,
+    pub strict_tx: bool,
+    pub strict_frozen: bool,
+    pub max_chargebacks: Option<u64>,
+    pub max_open_disputes: Option<u64>,
+    pub reorder_window: usize,
+    pub reorder_by_time: bool,
+    pub max_record_bytes: usize,
+    pub strip_currency: bool,
+    pub audit: bool,
+    pub audit_log: Option<String>,
+    pub max_warnings: Option<u64>,
+    pub manifest: Option<String>,
+    pub column_map: HashMap<String, String>,
+    pub include_empty: bool,
+    pub anomaly_report: bool,
+    pub amount_stats: bool,
+    pub dedupe_deposits: bool,
+    pub allow_negative_adjustment: bool,
+    pub disabled: HashSet<CommandType>,
+    pub parallel_parse: bool,
+    pub mode: Mode,
+    pub progress: bool,
+    pub checkpoint: Option<String>,
+    pub resume: Option<String>,
+    pub seed_from: Option<String>,
+    pub frozen_policy: FrozenPolicy,
+    pub amount_policy: AmountPolicy,
+    pub threads: Option<usize>,
+    pub units: Units,
+    pub schema: Schema,
+    pub trim: csv_async::Trim,
+    pub client_range: Option<RangeInclusive<ClientID>>,
+    pub open_retries: u32,
+}
+
+impl Default for Arguments {
+    fn default() -> Self {
+        Arguments {
+            file_path: String::new(),
+            listen: None,
+            dir: None,
+            with_counts: false,
+            with_totals: false,
+            clamp_negative: false,
+            locked_only: false,
+            format: OutputFormat::Csv,
+            bool_format: BoolFormat::Text,
+            columns: None,
+            delimiter: b',',
+            match_delimiter: false,
+            statements: None,
+            tee: None,
+            quiet_rounding: false,
+            max_amount: None,
+            stream_output: false,
+            strict_tx: false,
+            strict_frozen: false,
+            max_chargebacks: None,
+            max_open_disputes: None,
+            reorder_window: 0,
+            reorder_by_time: false,
+            max_record_bytes: MAX_RECORD_BYTES,
+            strip_currency: false,
+            audit: false,
+            audit_log: None,
+            max_warnings: None,
+            manifest: None,
+            column_map: HashMap::new(),
+            include_empty: false,
+            anomaly_report: false,
+            amount_stats: false,
+            dedupe_deposits: false,
+            allow_negative_adjustment: false,
+            disabled: HashSet::new(),
+            parallel_parse: false,
+            mode: Mode::Lenient,
+            progress: false,
+            checkpoint: None,
+            resume: None,
+            seed_from: None,
+            frozen_policy: FrozenPolicy::BlockAll,
+            amount_policy: AmountPolicy::RequireForMonetary,
+            threads: None,
+            units: Units::Dollars,
+            schema: Schema::Unchecked,
+            trim: csv_async::Trim::All,
+            client_range: None,
+            open_retries: 0,
+        }
+    }
+}
+
+/// Interprets command line arguments
+/// 
+/// # Arguments
+/// 
+/// args                the program's arguments, excluding the program name
+/// 
+/// # Return Value
+/// 
+/// Err(String)         a description of the problem with the arguments, suitable for logging
+/// Ok(Arguments)
+/// 
+pub fn parse(args: &[String]) -> Result<Arguments, String> {
+
+    let mut arguments = Arguments::default();
+    let mut file_path: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--with-counts" => arguments.with_counts = true,
+            "--with-totals" => arguments.with_totals = true,
+            "--clamp-negative" => arguments.clamp_negative = true,
+            "--locked-only" => arguments.locked_only = true,
+            "--delimiter" => arguments.delimiter = parse_delimiter(value(&mut args, arg)?)?,
+            "--match-delimiter" => arguments.match_delimiter = true,
+            "--statements" => arguments.statements = Some(value(&mut args, arg)?.clone()),
+            "--tee" => arguments.tee = Some(value(&mut args, arg)?.clone()),
+            "--checkpoint" => arguments.checkpoint = Some(value(&mut args, arg)?.clone()),
+            "--listen" => arguments.listen = Some(value(&mut args, arg)?.clone()),
+            "--dir" => arguments.dir = Some(value(&mut args, arg)?.clone()),
+            "--resume" => arguments.resume = Some(value(&mut args, arg)?.clone()),
+            "--audit-log" => arguments.audit_log = Some(value(&mut args, arg)?.clone()),
+            "--manifest" => arguments.manifest = Some(value(&mut args, arg)?.clone()),
+            "--seed-from" => arguments.seed_from = Some(value(&mut args, arg)?.clone()),
+            "--quiet-rounding" => arguments.quiet_rounding = true,
+            "--stream-output" => arguments.stream_output = true,
+            "--strict-tx" => arguments.strict_tx = true,
+            "--strict-frozen" => arguments.strict_frozen = true,
+            "--audit" => arguments.audit = true,
+            "--reorder-by-time" => arguments.reorder_by_time = true,
+            "--strip-currency" => arguments.strip_currency = true,
+            "--include-empty" => arguments.include_empty = true,
+            "--anomaly-report" => arguments.anomaly_report = true,
+            "--stats" => arguments.amount_stats = true,
+            "--dedupe-deposits" => arguments.dedupe_deposits = true,
+            "--allow-negative-adjustment" => arguments.allow_negative_adjustment = true,
+            "--parallel-parse" => arguments.parallel_parse = true,
+            "--strict" => arguments.mode = Mode::Strict,
+            "--mode" => arguments.mode = parse_mode(value(&mut args, arg)?)?,
+            "--progress" => arguments.progress = true,
+            "--max-amount" => arguments.max_amount = Some(parse_decimal(value(&mut args, arg)?, arg)?),
+            "--max-warnings" => arguments.max_warnings = Some(parse_count(value(&mut args, arg)?, arg)?),
+            "--max-chargebacks" => arguments.max_chargebacks = Some(parse_count(value(&mut args, arg)?, arg)?),
+            "--threads" => arguments.threads = Some(parse_threads(value(&mut args, arg)?)?),
+            "--max-open-disputes" => arguments.max_open_disputes = Some(parse_count(value(&mut args, arg)?, arg)?),
+            "--reorder-window" => arguments.reorder_window = parse_count(value(&mut args, arg)?, arg)?,
+            "--column-map" => arguments.column_map = parse_column_map(value(&mut args, arg)?)?,
+            "--client-range" => arguments.client_range = Some(parse_client_range(value(&mut args, arg)?)?),
+            "--max-record-bytes" => arguments.max_record_bytes = parse_count(value(&mut args, arg)?, arg)?,
+            "--open-retries" => arguments.open_retries = parse_count(value(&mut args, arg)?, arg)?,
+            "--disable" => { arguments.disabled.insert(parse_command_type(value(&mut args, arg)?)?); },
+            "--trim" => arguments.trim = parse_trim(value(&mut args, arg)?)?,
+            "--units" => arguments.units = parse_units(value(&mut args, arg)?)?,
+            "--format" => arguments.format = parse_format(value(&mut args, arg)?)?,
+            "--bool-format" => arguments.bool_format = parse_bool_format(value(&mut args, arg)?)?,
+            "--columns" => arguments.columns = Some(parse_columns(value(&mut args, arg)?)?),
+            "--schema" => arguments.schema = parse_schema(value(&mut args, arg)?)?,
+            "--frozen-policy" => arguments.frozen_policy = parse_frozen_policy(value(&mut args, arg)?)?,
+            "--amount-policy" => arguments.amount_policy = parse_amount_policy(value(&mut args, arg)?)?,
+            flag if flag.starts_with("--") => {
+                return Err(format!("Transaction Parser does not recognize the flag {}.  {}", flag, USAGE));
+            },
+            path => {
+                if file_path.is_some() {
+                    return Err(format!("Transaction Parser expects a single file path, but also found {}.  {}", path, USAGE));
+                }
+                file_path = Some(path.to_owned());
+            },
+        }
+    }
+
+    if let (Some(_), Some(seed_from)) = (&arguments.resume, &arguments.seed_from) {
+        return Err(format!("Transaction Parser starts from either a checkpoint or --seed-from {}, not both.  {}", seed_from, USAGE));
+    }
+
+    // each fixed width belongs to its column, so the widths follow the columns selected
+    if let (OutputFormat::Fixed(widths), Some(columns)) = (&mut arguments.format, &arguments.columns) {
+        *widths = columns.iter().map(|column| FIXED_WIDTHS[*column as usize]).collect();
+    }
+
+    // a checkpoint counts the commands it reflects, which only identifies them within a single source
+    if let (Some(_), Some(dir)) = (&arguments.resume, &arguments.dir) {
+        return Err(format!("Transaction Parser resumes from a checkpoint with a single file, not --dir {}.  {}", dir, USAGE));
+    }
+
+    match (file_path, &arguments.listen, &arguments.dir) {
+        (_, Some(addr), Some(dir)) => Err(format!("Transaction Parser reads either --dir {} or --listen {}, not both.  {}", dir, addr, USAGE)),
+        (Some(_), Some(addr), None) => Err(format!("Transaction Parser reads either a file or --listen {}, not both.  {}", addr, USAGE)),
+        (Some(_), None, Some(dir)) => Err(format!("Transaction Parser reads either a file or --dir {}, not both.  {}", dir, USAGE)),
+        (None, Some(_), None) | (None, None, Some(_)) => Ok(arguments),
+        (Some(file_path), None, None) => Ok(Arguments {
+            file_path,
+            ..arguments
+        }),
+        (None, None, None) => Err(format!("Transaction Parser expects a file path for the transactions csv file.  {}", USAGE)),
+    }
+}
+
+// Gets the value following a flag
+fn value<'a>(args: &mut std::slice::Iter<'a, String>, flag: &str) -> Result<&'a String, String> {
+    args.next().ok_or_else(|| format!("Transaction Parser expects a value after {}.  {}", flag, USAGE))
+}
+
+fn parse_decimal(value: &str, flag: &str) -> Result<Decimal, String> {
+    Decimal::from_str(value).map_err(|err| format!("Transaction Parser expects a decimal after {}, but found {}: {}", flag, value, err))
+}
+
+fn parse_count<T: FromStr>(value: &str, flag: &str) -> Result<T, String> where T::Err: std::fmt::Display {
+    value.parse::<T>().map_err(|err| format!("Transaction Parser expects a whole number after {}, but found {}: {}", flag, value, err))
+}
+
+fn parse_threads(value: &str) -> Result<usize, String> {
+    match parse_count(value, "--threads")? {
+        0 => Err("Transaction Parser expects --threads to be at least 1, but found 0.".to_owned()),
+        threads => Ok(threads),
+    }
+}
+
+fn parse_column_map(value: &str) -> Result<HashMap<String, String>, String> {
+    value.split(',').map(|pair| match pair.split_once('=') {
+        Some((from, to)) if ["type", "client", "tx", "amount"].contains(&to.trim()) => Ok((from.trim().to_owned(), to.trim().to_owned())),
+        _ => Err(format!("Transaction Parser expects --column-map pairs such as kind=type, naming type, client, tx, or amount, but found {}.", pair)),
+    }).collect()
+}
+
+fn parse_mode(value: &str) -> Result<Mode, String> {
+    match value {
+        "strict" => Ok(Mode::Strict),
+        "lenient" => Ok(Mode::Lenient),
+        value => Err(format!("Transaction Parser expects --mode to be strict or lenient, but found {}.", value)),
+    }
+}
+
+fn parse_client_range(value: &str) -> Result<RangeInclusive<ClientID>, String> {
+    let (min, max) = value.split_once("..")
+        .ok_or_else(|| format!("Transaction Parser expects --client-range to be such as 1..65535, but found {}.", value))?;
+    let (min, max): (ClientID, ClientID) = (parse_count(min.trim(), "--client-range")?, parse_count(max.trim(), "--client-range")?);
+    if min > max {
+        return Err(format!("Transaction Parser expects the --client-range minimum, {}, to be no greater than the maximum, {}.", min, max));
+    }
+    Ok(min..=max)
+}
+
+fn parse_command_type(value: &str) -> Result<CommandType, String> {
+    value.parse().map_err(|_| format!("Transaction Parser expects --disable to name a type of command, such as dispute, but found {}.", value))
+}
+
+fn parse_trim(value: &str) -> Result<csv_async::Trim, String> {
+    match value {
+        "all" => Ok(csv_async::Trim::All),
+        "headers" => Ok(csv_async::Trim::Headers),
+        "fields" => Ok(csv_async::Trim::Fields),
+        "none" => Ok(csv_async::Trim::None),
+        value => Err(format!("Transaction Parser expects --trim to be all, headers, fields, or none, but found {}.", value)),
+    }
+}
+
+fn parse_units(value: &str) -> Result<Units, String> {
+    match value {
+        "dollars" => Ok(Units::Dollars),
+        "cents" => Ok(Units::Cents),
+        value => Err(format!("Transaction Parser expects --units to be dollars or cents, but found {}.", value)),
+    }
+}
+
+fn parse_columns(value: &str) -> Result<Vec<Column>, String> {
+    value.split(',').map(|name| Column::from_name(name.trim()).ok_or_else(|| {
+        let names: Vec<&str> = Column::ALL.iter().map(|column| column.name()).collect();
+        format!("Transaction Parser expects --columns to list columns among {}, but found {}.", names.join(", "), name)
+    })).collect()
+}
+
+fn parse_format(value: &str) -> Result<OutputFormat, String> {
+    match value {
+        "csv" => Ok(OutputFormat::Csv),
+        "fixed" => Ok(OutputFormat::Fixed(FIXED_WIDTHS.to_vec())),
+        value => Err(format!("Transaction Parser expects --format to be csv or fixed, but found {}.", value)),
+    }
+}
+
+fn parse_bool_format(value: &str) -> Result<BoolFormat, String> {
+    match value {
+        "text" => Ok(BoolFormat::Text),
+        "int" => Ok(BoolFormat::Int),
+        value => Err(format!("Transaction Parser expects --bool-format to be text or int, but found {}.", value)),
+    }
+}
+
+fn parse_schema(value: &str) -> Result<Schema, String> {
+    match value {
+        "exact" => Ok(Schema::Exact),
+        "allow-extras" => Ok(Schema::AllowExtras),
+        "unchecked" => Ok(Schema::Unchecked),
+        value => Err(format!("Transaction Parser expects --schema to be exact, allow-extras, or unchecked, but found {}.", value)),
+    }
+}
+
+fn parse_frozen_policy(value: &str) -> Result<FrozenPolicy, String> {
+    match value {
+        "block-all" => Ok(FrozenPolicy::BlockAll),
+        "allow-deposits" => Ok(FrozenPolicy::AllowDeposits),
+        value => Err(format!("Transaction Parser expects --frozen-policy to be block-all or allow-deposits, but found {}.", value)),
+    }
+}
+
+fn parse_amount_policy(value: &str) -> Result<AmountPolicy, String> {
+    match value {
+        "require" => Ok(AmountPolicy::RequireForMonetary),
+        "zero" => Ok(AmountPolicy::DefaultZero),
+        "reject" => Ok(AmountPolicy::Reject),
+        value => Err(format!("Transaction Parser expects --amount-policy to be require, zero, or reject, but found {}.", value)),
+    }
+}
+
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        value if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        value => Err(format!("Transaction Parser expects the delimiter to be a single ascii character, but found {}.", value)),
+    }
+}
+
+#[cfg(test)]
+mod arguments_tests {
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = super::parse(&args(&["input.csv"])).unwrap();
+        assert_eq!("input.csv", parsed.file_path);
+        assert!(!parsed.with_counts);
+
+        let parsed = super::parse(&args(&["--with-counts", "input.csv"])).unwrap();
+        assert_eq!("input.csv", parsed.file_path);
+        assert!(parsed.with_counts);
+        assert!(!parsed.with_totals);
+
+        assert!(super::parse(&args(&["input.csv", "--with-totals"])).unwrap().with_totals);
+        assert!(super::parse(&args(&["input.csv", "--clamp-negative"])).unwrap().clamp_negative);
+        assert!(!parsed.locked_only);
+        assert!(super::parse(&args(&["input.csv", "--locked-only"])).unwrap().locked_only);
+
+        assert!(super::parse(&args(&[])).is_err());
+        assert!(super::parse(&args(&["--with-counts"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "other.csv"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--unknown"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(b',', super::parse(&args(&["input.csv"])).unwrap().delimiter);
+        assert_eq!(b';', super::parse(&args(&["input.csv", "--delimiter", ";"])).unwrap().delimiter);
+        assert_eq!(b'\t', super::parse(&args(&["--delimiter", "tab", "input.csv"])).unwrap().delimiter);
+        assert_eq!(b'\t', super::parse(&args(&["--delimiter", "\\t", "input.csv"])).unwrap().delimiter);
+        assert_eq!(b'\t', super::parse(&args(&["--delimiter", "\t", "input.csv"])).unwrap().delimiter);
+        assert!(super::parse(&args(&["--match-delimiter", "input.csv"])).unwrap().match_delimiter);
+        assert!(super::parse(&args(&["--quiet-rounding", "input.csv"])).unwrap().quiet_rounding);
+        assert!(super::parse(&args(&["--stream-output", "input.csv"])).unwrap().stream_output);
+        assert!(super::parse(&args(&["--strict-tx", "input.csv"])).unwrap().strict_tx);
+        assert!(super::parse(&args(&["--strict-frozen", "input.csv"])).unwrap().strict_frozen);
+        assert!(super::parse(&args(&["--audit", "input.csv"])).unwrap().audit);
+        assert!(super::parse(&args(&["--include-empty", "input.csv"])).unwrap().include_empty);
+        assert!(super::parse(&args(&["--anomaly-report", "input.csv"])).unwrap().anomaly_report);
+        assert!(super::parse(&args(&["--stats", "input.csv"])).unwrap().amount_stats);
+        assert!(super::parse(&args(&["--dedupe-deposits", "input.csv"])).unwrap().dedupe_deposits);
+        assert!(super::parse(&args(&["--allow-negative-adjustment", "input.csv"])).unwrap().allow_negative_adjustment);
+        assert!(super::parse(&args(&["--parallel-parse", "input.csv"])).unwrap().parallel_parse);
+        assert!(super::parse(&args(&["--progress", "input.csv"])).unwrap().progress);
+
+        assert!(super::parse(&args(&["input.csv", "--delimiter"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--delimiter", ";;"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--delimiter", "é"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_listen() {
+        let parsed = super::parse(&args(&["--listen", "127.0.0.1:7878"])).unwrap();
+        assert_eq!(Some("127.0.0.1:7878".to_owned()), parsed.listen);
+        assert_eq!("", parsed.file_path);
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().listen);
+        assert!(super::parse(&args(&["--listen", "127.0.0.1:7878", "input.csv"])).is_err());
+        assert!(super::parse(&args(&["--listen"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_dir() {
+        let parsed = super::parse(&args(&["--dir", "./txns/"])).unwrap();
+        assert_eq!(Some("./txns/".to_owned()), parsed.dir);
+        assert_eq!("", parsed.file_path);
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().dir);
+        assert!(super::parse(&args(&["--dir", "./txns/", "input.csv"])).is_err());
+        assert!(super::parse(&args(&["--dir", "./txns/", "--listen", "127.0.0.1:7878"])).is_err());
+        assert!(super::parse(&args(&["--dir", "./txns/", "--resume", "in.json"])).is_err());
+        assert!(super::parse(&args(&["--dir"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().statements);
+        assert_eq!(Some("out.csv".to_owned()), super::parse(&args(&["input.csv", "--statements", "out.csv"])).unwrap().statements);
+        assert!(super::parse(&args(&["input.csv", "--statements"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_tee() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().tee);
+        assert_eq!(Some("balances.csv".to_owned()), super::parse(&args(&["input.csv", "--tee", "balances.csv"])).unwrap().tee);
+        assert!(super::parse(&args(&["input.csv", "--tee"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_checkpoint() {
+        let parsed = super::parse(&args(&["input.csv"])).unwrap();
+        assert_eq!(None, parsed.checkpoint);
+        assert_eq!(None, parsed.resume);
+
+        let parsed = super::parse(&args(&["--resume", "in.json", "input.csv", "--checkpoint", "out.json"])).unwrap();
+        assert_eq!(Some("out.json".to_owned()), parsed.checkpoint);
+        assert_eq!(Some("in.json".to_owned()), parsed.resume);
+        assert!(super::parse(&args(&["input.csv", "--resume"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_audit_log() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().audit_log);
+        assert_eq!(Some("audit.csv".to_owned()), super::parse(&args(&["input.csv", "--audit-log", "audit.csv"])).unwrap().audit_log);
+        assert!(super::parse(&args(&["input.csv", "--audit-log"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().manifest);
+        assert_eq!(Some("manifest.json".to_owned()), super::parse(&args(&["input.csv", "--manifest", "manifest.json"])).unwrap().manifest);
+        assert!(super::parse(&args(&["input.csv", "--manifest"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_seed_from() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().seed_from);
+        assert_eq!(Some("yesterday.csv".to_owned()), super::parse(&args(&["input.csv", "--seed-from", "yesterday.csv"])).unwrap().seed_from);
+        assert!(super::parse(&args(&["input.csv", "--seed-from"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--seed-from", "yesterday.csv", "--resume", "in.json"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        use crate::mode::Mode;
+
+        assert_eq!(Mode::Lenient, super::parse(&args(&["input.csv"])).unwrap().mode);
+        assert_eq!(Mode::Strict, super::parse(&args(&["input.csv", "--mode", "strict"])).unwrap().mode);
+        assert_eq!(Mode::Lenient, super::parse(&args(&["--mode", "lenient", "input.csv"])).unwrap().mode);
+        assert_eq!(Mode::Strict, super::parse(&args(&["--strict", "input.csv"])).unwrap().mode);
+        assert!(super::parse(&args(&["input.csv", "--mode", "fussy"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--mode"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_client_range() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().client_range);
+        assert_eq!(Some(1..=65535), super::parse(&args(&["input.csv", "--client-range", "1..65535"])).unwrap().client_range);
+        assert_eq!(Some(7..=7), super::parse(&args(&["--client-range", "7..7", "input.csv"])).unwrap().client_range);
+        assert!(super::parse(&args(&["input.csv", "--client-range", "9..1"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--client-range", "1-9"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--client-range", "1..70000"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--client-range"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_units() {
+        use crate::transaction_csv::Units;
+
+        assert_eq!(Units::Dollars, super::parse(&args(&["input.csv"])).unwrap().units);
+        assert_eq!(Units::Cents, super::parse(&args(&["input.csv", "--units", "cents"])).unwrap().units);
+        assert_eq!(Units::Dollars, super::parse(&args(&["--units", "dollars", "input.csv"])).unwrap().units);
+        assert!(super::parse(&args(&["input.csv", "--units", "pennies"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_format() {
+        use crate::transaction_csv::{OutputFormat, FIXED_WIDTHS};
+
+        assert_eq!(OutputFormat::Csv, super::parse(&args(&["input.csv"])).unwrap().format);
+        assert_eq!(OutputFormat::Fixed(FIXED_WIDTHS.to_vec()), super::parse(&args(&["input.csv", "--format", "fixed"])).unwrap().format);
+        assert_eq!(OutputFormat::Csv, super::parse(&args(&["input.csv", "--format", "csv"])).unwrap().format);
+        assert!(super::parse(&args(&["input.csv", "--format", "xml"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_bool_format() {
+        use crate::transaction_csv::BoolFormat;
+
+        assert_eq!(BoolFormat::Text, super::parse(&args(&["input.csv"])).unwrap().bool_format);
+        assert_eq!(BoolFormat::Int, super::parse(&args(&["input.csv", "--bool-format", "int"])).unwrap().bool_format);
+        assert_eq!(BoolFormat::Text, super::parse(&args(&["--bool-format", "text", "input.csv"])).unwrap().bool_format);
+        assert!(super::parse(&args(&["input.csv", "--bool-format", "yes"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--bool-format"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        use crate::transaction_csv::{Column, OutputFormat};
+
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().columns);
+        let parsed = super::parse(&args(&["input.csv", "--columns", "client, total,locked"])).unwrap();
+        assert_eq!(Some(vec![Column::Client, Column::Total, Column::Locked]), parsed.columns);
+        assert_eq!(Err("Transaction Parser expects --columns to list columns among client, available, held, total, locked, tx_count, but found balance.".to_owned()),
+            super::parse(&args(&["input.csv", "--columns", "client,balance"])));
+        assert!(super::parse(&args(&["input.csv", "--columns"])).is_err());
+
+        // fixed widths follow the columns selected, whichever flag comes first
+        let parsed = super::parse(&args(&["input.csv", "--format", "fixed", "--columns", "total,client"])).unwrap();
+        assert_eq!(OutputFormat::Fixed(vec![21, 7]), parsed.format);
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        use crate::transaction_csv::Schema;
+
+        assert_eq!(Schema::Unchecked, super::parse(&args(&["input.csv"])).unwrap().schema);
+        assert_eq!(Schema::Exact, super::parse(&args(&["input.csv", "--schema", "exact"])).unwrap().schema);
+        assert_eq!(Schema::AllowExtras, super::parse(&args(&["input.csv", "--schema", "allow-extras"])).unwrap().schema);
+        assert!(super::parse(&args(&["input.csv", "--schema", "loose"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--schema"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_trim() {
+        assert_eq!(csv_async::Trim::All, super::parse(&args(&["input.csv"])).unwrap().trim);
+        assert_eq!(csv_async::Trim::None, super::parse(&args(&["input.csv", "--trim", "none"])).unwrap().trim);
+        assert_eq!(csv_async::Trim::Headers, super::parse(&args(&["--trim", "headers", "input.csv"])).unwrap().trim);
+        assert_eq!(csv_async::Trim::Fields, super::parse(&args(&["input.csv", "--trim", "fields"])).unwrap().trim);
+        assert!(super::parse(&args(&["input.csv", "--trim", "both"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_threads() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().threads);
+        assert_eq!(Some(2), super::parse(&args(&["input.csv", "--threads", "2"])).unwrap().threads);
+        assert!(super::parse(&args(&["input.csv", "--threads", "0"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--threads", "-1"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--threads"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_amount_policy() {
+        use crate::command_handler::AmountPolicy;
+
+        assert_eq!(AmountPolicy::RequireForMonetary, super::parse(&args(&["input.csv"])).unwrap().amount_policy);
+        assert_eq!(AmountPolicy::RequireForMonetary, super::parse(&args(&["input.csv", "--amount-policy", "require"])).unwrap().amount_policy);
+        assert_eq!(AmountPolicy::DefaultZero, super::parse(&args(&["--amount-policy", "zero", "input.csv"])).unwrap().amount_policy);
+        assert_eq!(AmountPolicy::Reject, super::parse(&args(&["input.csv", "--amount-policy", "reject"])).unwrap().amount_policy);
+        assert!(super::parse(&args(&["input.csv", "--amount-policy", "sometimes"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--amount-policy"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_frozen_policy() {
+        use crate::client_data::FrozenPolicy;
+
+        assert_eq!(FrozenPolicy::BlockAll, super::parse(&args(&["input.csv"])).unwrap().frozen_policy);
+        assert_eq!(FrozenPolicy::BlockAll, super::parse(&args(&["input.csv", "--frozen-policy", "block-all"])).unwrap().frozen_policy);
+        assert_eq!(FrozenPolicy::AllowDeposits, super::parse(&args(&["--frozen-policy", "allow-deposits", "input.csv"])).unwrap().frozen_policy);
+        assert!(super::parse(&args(&["input.csv", "--frozen-policy", "sometimes"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--frozen-policy"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_amount() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().max_amount);
+        assert_eq!(Some(rust_decimal_macros::dec!(1000.5)), super::parse(&args(&["input.csv", "--max-amount", "1000.5"])).unwrap().max_amount);
+        assert!(super::parse(&args(&["input.csv", "--max-amount", "lots"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_chargebacks() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().max_chargebacks);
+        assert_eq!(Some(2), super::parse(&args(&["input.csv", "--max-chargebacks", "2"])).unwrap().max_chargebacks);
+        assert!(super::parse(&args(&["input.csv", "--max-chargebacks", "-1"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--max-chargebacks"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_open_disputes() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().max_open_disputes);
+        assert_eq!(Some(3), super::parse(&args(&["input.csv", "--max-open-disputes", "3"])).unwrap().max_open_disputes);
+        assert!(super::parse(&args(&["input.csv", "--max-open-disputes", "-1"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--max-open-disputes"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_warnings() {
+        assert_eq!(None, super::parse(&args(&["input.csv"])).unwrap().max_warnings);
+        assert_eq!(Some(100), super::parse(&args(&["input.csv", "--max-warnings", "100"])).unwrap().max_warnings);
+        assert!(super::parse(&args(&["input.csv", "--max-warnings", "many"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--max-warnings"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_reorder_window() {
+        assert_eq!(0, super::parse(&args(&["input.csv"])).unwrap().reorder_window);
+        assert_eq!(8, super::parse(&args(&["input.csv", "--reorder-window", "8"])).unwrap().reorder_window);
+        assert!(super::parse(&args(&["input.csv", "--reorder-window", "some"])).is_err());
+
+        assert!(!super::parse(&args(&["input.csv"])).unwrap().reorder_by_time);
+        assert!(super::parse(&args(&["input.csv", "--reorder-window", "8", "--reorder-by-time"])).unwrap().reorder_by_time);
+    }
+
+    #[test]
+    fn test_parse_disable() {
+        use std::collections::HashSet;
+        use crate::command::CommandType;
+
+        assert!(super::parse(&args(&["input.csv"])).unwrap().disabled.is_empty());
+        let arguments = super::parse(&args(&["--disable", "dispute", "input.csv", "--disable", "Chargeback"])).unwrap();
+        assert_eq!(HashSet::from([CommandType::Dispute, CommandType::Chargeback]), arguments.disabled);
+        assert!(super::parse(&args(&["input.csv", "--disable", "refund"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_strip_currency() {
+        assert!(!super::parse(&args(&["input.csv"])).unwrap().strip_currency);
+        assert!(super::parse(&args(&["input.csv", "--strip-currency"])).unwrap().strip_currency);
+    }
+
+    #[test]
+    fn test_parse_max_record_bytes() {
+        assert_eq!(crate::transaction_csv::MAX_RECORD_BYTES, super::parse(&args(&["input.csv"])).unwrap().max_record_bytes);
+        assert_eq!(1024, super::parse(&args(&["input.csv", "--max-record-bytes", "1024"])).unwrap().max_record_bytes);
+        assert!(super::parse(&args(&["input.csv", "--max-record-bytes", "lots"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_open_retries() {
+        assert_eq!(0, super::parse(&args(&["input.csv"])).unwrap().open_retries);
+        assert_eq!(3, super::parse(&args(&["input.csv", "--open-retries", "3"])).unwrap().open_retries);
+        assert!(super::parse(&args(&["input.csv", "--open-retries", "-1"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_column_map() {
+        assert!(super::parse(&args(&["input.csv"])).unwrap().column_map.is_empty());
+
+        let column_map = super::parse(&args(&["input.csv", "--column-map", "kind=type, cust=client,ref=tx,value=amount"])).unwrap().column_map;
+        assert_eq!(4, column_map.len());
+        assert_eq!("type", column_map["kind"]);
+        assert_eq!("client", column_map["cust"]);
+        assert_eq!("tx", column_map["ref"]);
+        assert_eq!("amount", column_map["value"]);
+
+        assert!(super::parse(&args(&["input.csv", "--column-map", "kind"])).is_err());
+        assert!(super::parse(&args(&["input.csv", "--column-map", "kind=sort"])).is_err());
+    }
+}
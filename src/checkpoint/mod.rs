@@ -0,0 +1,149 @@
+//! # checkpoint module
+//! This module separates logic for saving client data to, and resuming it from, a checkpoint file.
+//! 
+//! A checkpoint is JSON holding every client's data along with the count of commands it reflects.
+//! Commands are dispatched in a deterministic order for a given file and options, so a resumed run skips that many commands and carries on from there.
+//! A checkpoint should therefore only be resumed against the same file, with the same options, as the run which saved it.
+//! 
+
+use std::collections::HashMap;
+use std::io;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::client_data::{ClientData, ClientID};
+use crate::client_store::ClientStore;
+
+/// Client data saved part way through a run
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    /// the count of commands the client data reflects
+    pub commands: u64,
+    pub clients: HashMap<ClientID, Box<ClientData>>,
+}
+
+// The form of a Checkpoint which is saved, borrowing the client data rather than copying it
+#[derive(Serialize)]
+struct CheckpointRef<'a, S: ClientStore + ?Sized> {
+    commands: u64,
+    #[serde(serialize_with = "serialize_store")]
+    clients: &'a S,
+}
+
+// Serializes a store as a map of client to client data, the same as the HashMap a Checkpoint is loaded into
+fn serialize_store<S: ClientStore + ?Sized, Z: Serializer>(clients: &&S, serializer: Z) -> Result<Z::Ok, Z::Error> {
+    serializer.collect_map(clients.sorted_clients())
+}
+
+/// Saves client data to a checkpoint file, replacing any checkpoint already there
+/// 
+/// The checkpoint is written beside the file and then renamed over it, so an interrupted save leaves the previous checkpoint intact.
+/// 
+/// # Arguments
+/// 
+/// path                the path of the checkpoint file
+/// commands            the count of commands the client data reflects
+/// clients             data for all client accounts
+/// 
+/// # Return Value
+/// 
+/// Err(std::io::Error)     serializing or writing the checkpoint failed
+/// Ok(())
+/// 
+pub fn save<S: ClientStore + ?Sized>(path: &str, commands: u64, clients: &S) -> Result<(), io::Error> {
+    let content = serde_json::to_vec(&CheckpointRef { commands, clients })?;
+    let partial = format!("{}.partial", path);
+    std::fs::write(&partial, content)?;
+    std::fs::rename(&partial, path)
+}
+
+/// Loads client data from a checkpoint file
+/// 
+/// # Arguments
+/// 
+/// path                the path of the checkpoint file
+/// 
+/// # Return Value
+/// 
+/// Err(std::io::Error)     reading or deserializing the checkpoint failed
+/// Ok(Checkpoint)
+/// 
+pub fn load(path: &str) -> Result<Checkpoint, io::Error> {
+    let content = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&content)?)
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tempfile::tempdir;
+
+    use crate::client_data::{ClientData, ClientID};
+    use crate::command_handler::HandlerOptions;
+    use crate::transaction_csv::ParseOptions;
+
+    const TRANSACTIONS: &str = concat!(
+        "type, client, tx, amount\n",
+        "deposit, 1, 1, 10.0\n",
+        "deposit, 2, 2, 5.5\n",
+        "withdrawal, 1, 3, 2.5\n",
+        "dispute, 2, 2,\n",
+        "deposit, 1, 4, 1.25\n",
+        "dispute, 1, 4,\n",
+        "resolve, 2, 2,\n",
+        "chargeback, 1, 4,\n",
+        "deposit, 3, 5, 7\n",
+        "withdrawal, 3, 6, 8\n",
+    );
+
+    // Parses and handles the file, starting from the client data and skipping the commands it already reflects
+    async fn run(file_path: &str, clients: HashMap<ClientID, Box<ClientData>>, skip_commands: u64) -> HashMap<ClientID, Box<ClientData>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let data = Arc::new(Mutex::new(clients));
+
+        let parse = tokio::spawn(crate::transaction_csv::parse_csv(file_path.to_owned(), tx, ParseOptions { skip_commands, ..ParseOptions::default() }));
+        let handle = tokio::spawn(crate::command_handler::handle_commands(data.clone(), rx, HandlerOptions { resumed_commands: skip_commands, ..HandlerOptions::default() }));
+        parse.await.unwrap().unwrap();
+        handle.await.unwrap().unwrap();
+
+        Arc::try_unwrap(data).ok().unwrap().into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let dir = tempdir().unwrap();
+        let full_path = dir.path().join("transactions.csv");
+        std::fs::write(&full_path, TRANSACTIONS).unwrap();
+        let full_path = full_path.to_str().unwrap();
+
+        // the first half of the file, as though the run was interrupted
+        let half_path = dir.path().join("half_transactions.csv");
+        std::fs::write(&half_path, TRANSACTIONS.lines().take(6).collect::<Vec<&str>>().join("\n")).unwrap();
+
+        let half = run(half_path.to_str().unwrap(), HashMap::new(), 0).await;
+        let checkpoint_path = dir.path().join("checkpoint.json");
+        let checkpoint_path = checkpoint_path.to_str().unwrap();
+        super::save(checkpoint_path, 5, &half).unwrap();
+
+        let checkpoint = super::load(checkpoint_path).unwrap();
+        assert_eq!(5, checkpoint.commands);
+        let resumed = run(full_path, checkpoint.clients, checkpoint.commands).await;
+
+        let single = run(full_path, HashMap::new(), 0).await;
+        assert_eq!(single, resumed);
+        assert!(resumed[&1].is_locked());
+        assert_eq!(resumed[&1].get_transaction_count(), 5);
+    }
+
+    #[test]
+    fn test_load_error() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("checkpoint.json");
+        assert!(super::load(path.to_str().unwrap()).is_err());
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(super::load(path.to_str().unwrap()).is_err());
+    }
+}
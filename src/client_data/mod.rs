@@ -4,6 +4,12 @@
 //!  > held wealth
 //!  > frozen
 //!  > deposit_history
+//!  > withdrawal_history
+//!  > transaction_count
+//!  > open_disputes
+//! 
+//! Each client also carries a frozen_policy, set when the account is opened, which decides whether a frozen account still accepts deposits,
+//! and a fee_policy, which decides the fee charged on each withdrawal; the fees are tallied in fees_collected.
 //! 
 //! These, along with the keys used to store client data, are sufficient to calculate desired output records (which is done in the transaction_csv module)
 //! 
@@ -12,12 +18,33 @@
 //! Why is deposit_history only for deposits?
 //! 
 //! Reading the documentation, I got the impression that disputes were only in regards to deposits.
+//! Withdrawals turned out to be disputable as well; they are kept in their own withdrawal_history because their funds move the opposite way.
+//! 
+//! # Disputes on deposits vs withdrawals
+//! 
+//! A disputed deposit is funds which may not belong to the client, so they are moved out of available funds until the dispute settles.
+//! A disputed withdrawal is a claim by the client on funds which already left the account, so the claim is held without touching available funds.
+//! 
+//! | operation  | deposit                       | withdrawal                                |
+//! |------------|-------------------------------|-------------------------------------------|
+//! | dispute    | available -= amt, held += amt | held += amt                               |
+//! | resolve    | available += amt, held -= amt | held -= amt                               |
+//! | chargeback | held -= amt, frozen           | held -= amt, available += amt, frozen     |
+//! | reverse    | held += amt, unfrozen*1       | held += amt, available -= amt, unfrozen*1 |
+//! 
+//! *1 unless another of the account's transactions remains charged back
+//! 
+//! # Disputes are keyed by (client, tx)
+//! 
+//! A dispute, resolve, or chargeback looks up its tx in the history of the client the command names, so a transaction is identified by the pair.
+//! The spec says tx ids are globally unique, but some feeds reuse them across clients; two clients may each have a deposit with tx 7,
+//! and each client's disputes reach only their own.  A tx is still unique within a client, as a repeated tx is rejected as a duplicate.
 //! 
 //! Why is deposit_history stored per-client rather than in a unified hashmap relying on tx ids as keys?  It would improve locality if it were in a unified hashmap...
 //! 
 //! It is because such gains are probably marginal with disputes hopefully not being the norm and because rather than just calling `dispute`, `resolve`, or `chargeback` methods, as is, each method would also need a copy of the unified deposit_history hashmap.  In short, I think it reads a little easier this way.
 //! 
-//! Why don't I just keep a history of the commands in order to role back deposits, rather than keeping a history of deposits?
+//! Why don't I just keep a history of the commands in order to role back deposits, rather than keeping a history of deposits and withdrawals?
 //! 
 //! At the moment, that would be the only application of the command history.  Comparably, the command history would take more space.
 //! 
@@ -30,54 +57,327 @@
 //! 
 //! I could manually solve this possible issue; however, rust_decimal gets a lot of traffic and should handle it for us
 //!     'a 96 bit integer, a 1 bit sign, and a scaling factor'
+//! 
+//! Equal ammounts may carry different scaling factors, such as `5` and `5.0000`.  Deposits and withdrawals normalize their ammounts before they are stored,
+//! so the history and the balances derived from it use a consistent scale.  Normalizing only drops trailing zeros, so no precision is lost.
 
 use std::collections::HashMap;
+use std::fmt;
 
+use chrono::{DateTime, Utc};
 use rust_decimal::prelude::Decimal;
 use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Command;
+use crate::logger;
 
 pub type ClientID = u16;
 pub type TransactionID = u32;
 
+/// Client data is (de)serializable, so it can be saved to, and resumed from, a checkpoint
+/// 
+/// It is cloneable and comparable as well, so a snapshot can be taken before an operation and compared, or restored, after it.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct ClientData {
     wealth: Decimal,
     held_wealth: Decimal,
     frozen: bool,
     deposit_history: HashMap<TransactionID, Box<Deposit>>,
+    withdrawal_history: HashMap<TransactionID, Box<Deposit>>,
+    transaction_count: u64,
+    #[serde(default)]
+    frozen_policy: FrozenPolicy,
+    #[serde(default)]
+    fee_policy: FeePolicy,
+    #[serde(default)]
+    fees_collected: Decimal,
+    /// the deposits and withdrawals currently under dispute, so a limit on them can be enforced without scanning the history;
+    /// a checkpoint saved before they were counted resumes with none, so freeing a slot stops at zero
+    #[serde(default)]
+    open_disputes: u64,
+}
+
+/// Which operations a frozen account still accepts
+/// 
+/// Withdrawals, disputes, resolves, and chargebacks are rejected on a frozen account under every policy.
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum FrozenPolicy {
+    /// every operation is rejected
+    #[default]
+    BlockAll,
+    /// deposits still succeed, so funds sent to a frozen account are not turned away
+    AllowDeposits,
 }
 
+/// The fee charged on each withdrawal, on top of the ammount withdrawn
+/// 
+/// Fees are kept when a withdrawal is disputed or charged back; only the ammount withdrawn is held or returned.
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum FeePolicy {
+    #[default]
+    NoFee,
+    /// a percentage of the ammount withdrawn, such as 1 for 1%, rounded to command::MAX_SCALE digits after the decimal
+    Percentage(Decimal),
+    /// the same fee for every withdrawal
+    Fixed(Decimal),
+}
+
+impl FeePolicy {
+    /// The fee for withdrawing the ammount
+    pub fn fee(&self, wealth: Decimal) -> Decimal {
+        match self {
+            FeePolicy::NoFee => Decimal::ZERO,
+            FeePolicy::Percentage(percentage) => (wealth * percentage / Decimal::ONE_HUNDRED).round_dp(crate::command::MAX_SCALE),
+            FeePolicy::Fixed(fee) => *fee,
+        }
+    }
+}
+
+/// A client's balances at a moment in time
+/// 
+/// available is what the client can spend right now; total is what they own, including held funds.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct NetPosition {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+// A deposit, or withdrawal, retained in case of a later dispute
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 struct Deposit {
-    disputed: bool,
+    state: DisputeState,
     ammount: Decimal,
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
 }
 
-// TODO: should I use Error instead?
-#[derive(PartialEq, Debug)]
+/// Where a deposit, or withdrawal, stands in the dispute process.
+/// Charged back transactions are retained so that their tx can neither be disputed again nor reused by a later transaction.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+enum DisputeState {
+    Undisputed,
+    Disputed,
+    ChargedBack,
+}
+
+/// Reasons an account update was rejected
+/// 
+/// The Display messages complete a sentence such as "the deposit did not succeed because ...".
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum AccountUpdateFailure {
     Frozen,
     TXNotFound,
     TXUndisputed,
     InsufficientFunds,
+    /// the available funds are short, but would cover the withdrawal if the funds held in dispute were released
+    FundsHeld,
     DuplicateDepositTX,
+    DuplicateWithdrawalTX,
     RedundantDispute,
+    AlreadyChargedBack,
+    NotChargedBack,
+    HeldUnderflow,
+    MissingAmount,
+    /// an assert found the available funds differ from its ammount; the available funds it found
+    BalanceMismatch(Decimal),
+    /// the account already has as many open disputes as it is allowed
+    DisputeLimitReached,
+}
+
+impl fmt::Display for AccountUpdateFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccountUpdateFailure::BalanceMismatch(actual) => return write!(f, "the available balance is {}", actual),
+            AccountUpdateFailure::Frozen => "the account is frozen",
+            AccountUpdateFailure::TXNotFound => "the transaction did not correspond to a known deposit or withdrawal for the client",
+            AccountUpdateFailure::TXUndisputed => "the transaction is not under dispute",
+            AccountUpdateFailure::InsufficientFunds => "the account has insufficient funds",
+            AccountUpdateFailure::FundsHeld => "the funds are held in dispute",
+            AccountUpdateFailure::DuplicateDepositTX => "the deposit tx id is a duplicate",
+            AccountUpdateFailure::DuplicateWithdrawalTX => "the withdrawal tx id is a duplicate",
+            AccountUpdateFailure::RedundantDispute => "the dispute was redundant",
+            AccountUpdateFailure::AlreadyChargedBack => "the transaction was already charged back",
+            AccountUpdateFailure::NotChargedBack => "the transaction was not charged back",
+            AccountUpdateFailure::HeldUnderflow => "the held funds are less than the disputed amount",
+            AccountUpdateFailure::MissingAmount => "the command does not have an amount",
+            AccountUpdateFailure::DisputeLimitReached => "the account has reached its limit of open disputes",
+        })
+    }
+}
+
+impl std::error::Error for AccountUpdateFailure {}
+
+/// Builds a ClientData with initial balances, such as when loading saved state
+/// 
+/// # Example
+/// 
+/// '''
+/// let client = ClientData::builder()
+///     .wealth(dec!(30.0))
+///     .held_wealth(dec!(2.0))
+///     .frozen(true)
+///     .build()?;
+/// '''
+/// 
+/// Funds built this way have no deposit history, so they cannot be disputed.
+pub struct ClientDataBuilder {
+    wealth: Decimal,
+    held_wealth: Decimal,
+    frozen: bool,
+    frozen_policy: FrozenPolicy,
+    fee_policy: FeePolicy,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ClientDataBuildFailure {
+    NegativeHeldWealth,
 }
 
 // accessors and constructor
 impl ClientData {
     pub fn is_locked(&self) -> bool { self.frozen }
-    pub fn get_total(&self) -> Decimal { self.wealth + self.held_wealth }
+    /// The funds the client owns, including held funds; saturates at Decimal::MAX, or Decimal::MIN, with a warning, rather than overflowing
+    pub fn get_total(&self) -> Decimal {
+        self.try_total().unwrap_or_else(|| {
+            let total = self.wealth.saturating_add(self.held_wealth);
+            logger::warning(&format!("The total of available funds {} and held funds {} overflows, so it is reported as {}.", self.wealth, self.held_wealth, total));
+            total
+        })
+    }
+    /// The funds the client owns, including held funds, or None if the sum overflows
+    pub fn try_total(&self) -> Option<Decimal> { self.wealth.checked_add(self.held_wealth) }
     pub fn get_held_wealth(&self) -> Decimal { self.held_wealth }
     pub fn get_wealth(&self) -> Decimal { self.wealth }
+    /// The funds the client can spend right now; the same as get_wealth
+    pub fn available(&self) -> Decimal { self.wealth }
+    /// The funds the client owns, including held funds; the same as get_total
+    pub fn total(&self) -> Decimal { self.get_total() }
+    /// The available, held, and total balances together
+    pub fn position(&self) -> NetPosition {
+        NetPosition {
+            available: self.available(),
+            held: self.held_wealth,
+            total: self.total(),
+        }
+    }
+    /// The number of transactions which have successfully updated the account
+    pub fn get_transaction_count(&self) -> u64 { self.transaction_count }
+    /// The ammount of the deposit with the tx, if the account has one
+    pub fn deposit_ammount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.deposit_history.get(&transaction_id).map(|deposit| deposit.ammount)
+    }
+    /// Whether the deposit with the tx is under dispute, or None if the account has no deposit with the tx; a charged back deposit is no longer disputed
+    pub fn is_disputed(&self, transaction_id: TransactionID) -> Option<bool> {
+        self.deposit_history.get(&transaction_id).map(|deposit| deposit.state == DisputeState::Disputed)
+    }
+    /// The deposits retained for disputes, as (tx, ammount, disputed, timestamp), ordered by tx
+    pub fn deposit_records(&self) -> Vec<(TransactionID, Decimal, bool, Option<DateTime<Utc>>)> {
+        let mut records: Vec<(TransactionID, Decimal, bool, Option<DateTime<Utc>>)> = self.deposit_history.iter()
+            .map(|(transaction_id, deposit)| (*transaction_id, deposit.ammount, deposit.state == DisputeState::Disputed, deposit.timestamp))
+            .collect();
+        records.sort_by_key(|record| record.0);
+        records
+    }
+    pub fn get_frozen_policy(&self) -> FrozenPolicy { self.frozen_policy }
+    pub fn get_fee_policy(&self) -> FeePolicy { self.fee_policy }
+    /// The sum of the fees charged on withdrawals
+    pub fn get_fees_collected(&self) -> Decimal { self.fees_collected }
+    /// The number of deposits and withdrawals currently under dispute
+    pub fn get_open_disputes(&self) -> u64 { self.open_disputes }
     pub fn new() -> ClientData {
+        ClientData::with_policy(FrozenPolicy::default())
+    }
+    /// Creates an empty account, which treats operations while frozen according to the policy
+    pub fn with_policy(frozen_policy: FrozenPolicy) -> ClientData {
         ClientData {
             wealth: dec!(0.0),
             held_wealth: dec!(0.0),
             frozen: false,
             deposit_history: HashMap::new(),
+            withdrawal_history: HashMap::new(),
+            transaction_count: 0,
+            frozen_policy,
+            fee_policy: FeePolicy::default(),
+            fees_collected: dec!(0.0),
+            open_disputes: 0,
+        }
+    }
+}
+
+impl Default for ClientData {
+    fn default() -> Self { ClientData::new() }
+}
+
+impl ClientData {
+    pub fn builder() -> ClientDataBuilder { ClientDataBuilder::new() }
+}
+
+impl ClientDataBuilder {
+    pub fn new() -> ClientDataBuilder {
+        ClientDataBuilder {
+            wealth: dec!(0.0),
+            held_wealth: dec!(0.0),
+            frozen: false,
+            frozen_policy: FrozenPolicy::default(),
+            fee_policy: FeePolicy::default(),
+        }
+    }
+    pub fn wealth(mut self, wealth: Decimal) -> ClientDataBuilder {
+        self.wealth = wealth;
+        self
+    }
+    pub fn held_wealth(mut self, held_wealth: Decimal) -> ClientDataBuilder {
+        self.held_wealth = held_wealth;
+        self
+    }
+    pub fn frozen(mut self, frozen: bool) -> ClientDataBuilder {
+        self.frozen = frozen;
+        self
+    }
+    pub fn frozen_policy(mut self, frozen_policy: FrozenPolicy) -> ClientDataBuilder {
+        self.frozen_policy = frozen_policy;
+        self
+    }
+    pub fn fee_policy(mut self, fee_policy: FeePolicy) -> ClientDataBuilder {
+        self.fee_policy = fee_policy;
+        self
+    }
+    /// Builds the client data
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(ClientDataBuildFailure::NegativeHeldWealth)     Held funds cannot be negative
+    /// Ok(ClientData)
+    /// 
+    /// Available funds may be negative, as they can be after a dispute following a withdrawal.
+    /// 
+    pub fn build(self) -> Result<ClientData, ClientDataBuildFailure> {
+        if self.held_wealth < dec!(0.0) {
+            Err(ClientDataBuildFailure::NegativeHeldWealth)
+        }
+        else {
+            Ok(ClientData {
+                wealth: self.wealth,
+                held_wealth: self.held_wealth,
+                frozen: self.frozen,
+                deposit_history: HashMap::new(),
+                withdrawal_history: HashMap::new(),
+                transaction_count: 0,
+                frozen_policy: self.frozen_policy,
+                fee_policy: self.fee_policy,
+                fees_collected: dec!(0.0),
+                open_disputes: 0,
+            })
         }
     }
 }
 
+impl Default for ClientDataBuilder {
+    fn default() -> Self { ClientDataBuilder::new() }
+}
+
 // This is controller logic, arguably.
 // On the other hand, it enforces the only means in which this data is meant to be used, so I feel packaging it with the model is appropriate.
 impl ClientData {
@@ -92,14 +392,20 @@ impl ClientData {
     /// 
     /// # Return Value
     /// 
-    /// false      the user's account is locked, which occurs when a chargeback happens on their account
-    /// true
+    /// Err(AccountUpdateFailure::Frozen)               The account is locked, which occurs when a chargeback happens on the account, and its policy blocks deposits
+    /// Err(AccountUpdateFailure::DuplicateDepositTX)   The tx was already used by a deposit or withdrawal on the account
+    /// Ok(())
     /// 
     pub fn deposit(&mut self, transaction_id: TransactionID, wealth: Decimal) -> Result<(), AccountUpdateFailure> {
-        if self.frozen {
+        self.deposit_at(transaction_id, wealth, None)
+    }
+    /// Deposits money in the account, as deposit does, also remembering when the deposit occurred for statements
+    pub fn deposit_at(&mut self, transaction_id: TransactionID, wealth: Decimal, timestamp: Option<DateTime<Utc>>) -> Result<(), AccountUpdateFailure> {
+        let wealth = wealth.normalize();
+        if self.frozen && self.frozen_policy == FrozenPolicy::BlockAll {
             Err(AccountUpdateFailure::Frozen)
         }
-        else if self.deposit_history.contains_key(&transaction_id) {
+        else if self.deposit_history.contains_key(&transaction_id) || self.withdrawal_history.contains_key(&transaction_id) {
             Err(AccountUpdateFailure::DuplicateDepositTX)
         }
         else {
@@ -107,137 +413,351 @@ impl ClientData {
             self.deposit_history.insert(
                 transaction_id, 
                 Box::new(Deposit { 
-                    disputed: false,
-                    ammount: wealth 
+                    state: DisputeState::Undisputed,
+                    ammount: wealth,
+                    timestamp,
                 })
             );
+            self.transaction_count += 1;
             Ok(())
         }
     }
-    /// Withdraws money from the account
+    /// Withdraws money from the account, along with the fee of its fee_policy; remembers the event in case of a later dispute.
     /// 
     /// # Return Value
     /// 
-    /// Err(AccountUpdateFailure::Frozen)               The account is locked, which occurs when a chargeback happens on the account
-    /// Err(AccountUpdateFailure::InsufficientFunds)    The account does not have sufficient funds*1 to cover the withdrawal
+    /// Err(AccountUpdateFailure::Frozen)                   The account is locked, which occurs when a chargeback happens on the account
+    /// Err(AccountUpdateFailure::DuplicateWithdrawalTX)    The tx was already used by a deposit or withdrawal on the account
+    /// Err(AccountUpdateFailure::FundsHeld)                The available funds*1 are short, but together with the disputed deposits they would cover the withdrawal and its fee
+    /// Err(AccountUpdateFailure::InsufficientFunds)        The account does not have sufficient funds*1 to cover the withdrawal and its fee
     /// Ok(())
     /// 
     /// *1 Held funds are not considered available for withdrawal.
+    ///    The hold on a disputed withdrawal is never returned to available funds, so it cannot make the funds held rather than insufficient.
     /// 
-    pub fn withdraw(&mut self, wealth: Decimal)-> Result<(),AccountUpdateFailure> {
+    pub fn withdraw(&mut self, transaction_id: TransactionID, wealth: Decimal)-> Result<(),AccountUpdateFailure> {
+        let wealth = wealth.normalize();
+        let fee = self.fee_policy.fee(wealth);
         if self.frozen {
             Err(AccountUpdateFailure::Frozen)
         }
-        else if self.wealth < wealth {
+        else if self.deposit_history.contains_key(&transaction_id) || self.withdrawal_history.contains_key(&transaction_id) {
+            Err(AccountUpdateFailure::DuplicateWithdrawalTX)
+        }
+        else if self.wealth < wealth + fee && self.wealth + self.held_deposits() >= wealth + fee {
+            Err(AccountUpdateFailure::FundsHeld)
+        }
+        else if self.wealth < wealth + fee {
             Err(AccountUpdateFailure::InsufficientFunds)
         }
         else {
-            self.wealth-=wealth;
+            self.wealth-=wealth + fee;
+            self.fees_collected += fee;
+            self.withdrawal_history.insert(
+                transaction_id, 
+                Box::new(Deposit { 
+                    state: DisputeState::Undisputed,
+                    ammount: wealth,
+                    timestamp: None,
+                })
+            );
+            self.transaction_count += 1;
             Ok(())
         }
     }
-    /// Submits a dispute on a deposit into the account, putting a hold on the associated funds
+    /// Credits a manual adjustment to the account's available funds
+    /// 
+    /// Adjustments are corrections posted by an operator, so they are not recorded for disputes, and they apply to frozen accounts as well.
+    /// 
+    pub fn adjust_credit(&mut self, wealth: Decimal) {
+        self.wealth += wealth.normalize();
+        self.transaction_count += 1;
+    }
+    /// Debits a manual adjustment from the account's available funds
+    /// 
+    /// Adjustments are corrections posted by an operator, so they are not recorded for disputes, and they apply to frozen accounts as well.
+    /// 
+    /// # Arguments
+    /// 
+    /// wealth              the ammount to debit
+    /// allow_negative      whether the debit may leave the available funds negative
     /// 
     /// # Return Value
     /// 
+    /// Err(AccountUpdateFailure::InsufficientFunds)    The account does not have sufficient available funds, and allow_negative is not set
+    /// Ok(())
+    /// 
+    pub fn adjust_debit(&mut self, wealth: Decimal, allow_negative: bool) -> Result<(), AccountUpdateFailure> {
+        let wealth = wealth.normalize();
+        if !allow_negative && self.wealth < wealth {
+            Err(AccountUpdateFailure::InsufficientFunds)
+        }
+        else {
+            self.wealth -= wealth;
+            self.transaction_count += 1;
+            Ok(())
+        }
+    }
+    /// Submits a dispute on a deposit or withdrawal into the account, putting a hold on the associated funds
+    /// 
+    /// See the module documentation for how deposits and withdrawals differ.
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(AccountUpdateFailure::AlreadyChargedBack)   The transaction was already charged back, even if the account is locked
     /// Err(AccountUpdateFailure::Frozen)               The account is locked, which occurs when a chargeback happens on the account
     /// Err(AccountUpdateFailure::RedundantDispute)     The transaction has already been disputed
-    /// Err(AccountUpdateFailure::TXNotFound)           The deposit to be disputed was not made to this user account
+    /// Err(AccountUpdateFailure::TXNotFound)           The transaction to be disputed was not made to this user account
     /// Ok(())
     /// 
     pub fn dispute(&mut self, transaction: TransactionID) -> Result<(),AccountUpdateFailure> {
-        if self.frozen {
+        self.dispute_with_limit(transaction, None)
+    }
+    /// Submits a dispute, as dispute does, unless the account already has the most open disputes it is allowed
+    /// 
+    /// Resolving or charging back a dispute frees its slot; reversing a chargeback takes the slot again, whatever the limit.
+    /// 
+    /// # Arguments
+    /// 
+    /// transaction         the tx of the deposit or withdrawal to dispute
+    /// max_open_disputes   the most disputes the account may have open at once; None enforces no limit
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(AccountUpdateFailure::DisputeLimitReached)  The account already has max_open_disputes open disputes
+    /// Err(AccountUpdateFailure)                       The dispute failed as it does for dispute
+    /// Ok(())
+    /// 
+    pub fn dispute_with_limit(&mut self, transaction: TransactionID, max_open_disputes: Option<u64>) -> Result<(),AccountUpdateFailure> {
+        let limit_reached = max_open_disputes.is_some_and(|max| self.open_disputes >= max);
+        if self.is_charged_back(transaction) {
+            Err(AccountUpdateFailure::AlreadyChargedBack)
+        }
+        else if self.frozen {
             Err(AccountUpdateFailure::Frozen)
         }
         else if let Some(transaction) = self.deposit_history.get_mut(&transaction) {
-            if transaction.disputed {
-                Err(AccountUpdateFailure::RedundantDispute)
-            }
-            else {
-                transaction.disputed = true;
+            match transaction.state {
+                DisputeState::Disputed => Err(AccountUpdateFailure::RedundantDispute),
+                DisputeState::ChargedBack => Err(AccountUpdateFailure::AlreadyChargedBack),
+                DisputeState::Undisputed if limit_reached => Err(AccountUpdateFailure::DisputeLimitReached),
+                DisputeState::Undisputed => {
+                    transaction.state = DisputeState::Disputed;
 // TODO: what if withdrawals have taken place, leaving insufficient funds for this dispute?  As is, account 'wealth' will become negative.
-                self.wealth-=transaction.ammount;
-                self.held_wealth+=transaction.ammount;
-                Ok(())
+                    self.wealth-=transaction.ammount;
+                    self.held_wealth+=transaction.ammount;
+                    self.open_disputes += 1;
+                    self.transaction_count += 1;
+                    Ok(())
+                },
+            }
+        }
+        else if let Some(transaction) = self.withdrawal_history.get_mut(&transaction) {
+            match transaction.state {
+                DisputeState::Disputed => Err(AccountUpdateFailure::RedundantDispute),
+                DisputeState::ChargedBack => Err(AccountUpdateFailure::AlreadyChargedBack),
+                DisputeState::Undisputed if limit_reached => Err(AccountUpdateFailure::DisputeLimitReached),
+                DisputeState::Undisputed => {
+                    // the withdrawn funds already left the account, so the hold is a claim on them rather than a move out of available funds
+                    transaction.state = DisputeState::Disputed;
+                    self.held_wealth+=transaction.ammount;
+                    self.open_disputes += 1;
+                    self.transaction_count += 1;
+                    Ok(())
+                },
             }
         }
         else {
             Err(AccountUpdateFailure::TXNotFound)
         }
     } 
-    /// Submits a chargeback on a dispute into the account, freezing the account, removing the funds put on hold by the dispute, and removing the deposit from the account's history
+    /// Submits a chargeback on a dispute into the account, freezing the account and settling the funds put on hold by the dispute
+    /// 
+    /// For a deposit, the held funds are removed; for a withdrawal, the held funds are returned to available funds.
+    /// The transaction is kept in the account's history, marked as charged back, so the tx cannot be disputed again or reused by a later transaction.
     /// 
     /// # Return Value
     /// 
+    /// Err(AccountUpdateFailure::AlreadyChargedBack)   The transaction was already charged back, even if the account is frozen
     /// Err(AccountUpdateFailure::Frozen)               The account is locked, which occurs when a chargeback happens on the account
     /// Err(AccountUpdateFailure::TXUndisputed)         The transaction was not under dispute, so a chargeback does not make since
-    /// Err(AccountUpdateFailure::TXNotFound)           The deposit to be disputed was not made to this user account
+    /// Err(AccountUpdateFailure::TXNotFound)           The transaction to be disputed was not made to this user account
+    /// Err(AccountUpdateFailure::HeldUnderflow)        The held funds are less than the disputed ammount*1, so the chargeback would leave them negative
     /// Ok(())
     /// 
+    /// *1 The dispute holds the whole ammount, so this only occurs when the held funds were changed otherwise, such as in a corrupt checkpoint.
+    /// 
     pub fn chargeback(&mut self, transaction: TransactionID) -> Result<(), AccountUpdateFailure> {
-        if self.frozen {
+        if self.is_charged_back(transaction) {
+            Err(AccountUpdateFailure::AlreadyChargedBack)
+        }
+        else if self.frozen {
             Err(AccountUpdateFailure::Frozen)
         }
         else if let Some(transaction_event) = self.deposit_history.get_mut(&transaction) {
-            if transaction_event.disputed {
-                self.held_wealth -= transaction_event.ammount;
-                self.frozen = true;
-                // The deposit which was disputed has been overturned.
-                // Since that is the case, we can lose this transaction.
-                // An alternative might be to change disputed to a trinary state variable.
-                //  Then, transactions which are chargeback, we would ensure did not fall again under dispute.
-                //  For the problem as currently described, there is no known need to do so.
-                //  That would be different if:
-                //   we had to keep a history of such activities,
-                //   we could undo chargebacks
-                //   etc.
-                self.deposit_history.remove(&transaction);
-                
-                Ok(())
+            match transaction_event.state {
+                DisputeState::Disputed if self.held_wealth < transaction_event.ammount => Err(AccountUpdateFailure::HeldUnderflow),
+                DisputeState::Disputed => {
+                    self.held_wealth -= transaction_event.ammount;
+                    self.frozen = true;
+                    transaction_event.state = DisputeState::ChargedBack;
+                    self.open_disputes = self.open_disputes.saturating_sub(1);
+                    self.transaction_count += 1;
+                    Ok(())
+                },
+                DisputeState::ChargedBack => Err(AccountUpdateFailure::AlreadyChargedBack),
+                DisputeState::Undisputed => Err(AccountUpdateFailure::TXUndisputed),
             }
-            else {
-                Err(AccountUpdateFailure::TXUndisputed)
+        }
+        else if let Some(transaction_event) = self.withdrawal_history.get_mut(&transaction) {
+            match transaction_event.state {
+                DisputeState::Disputed if self.held_wealth < transaction_event.ammount => Err(AccountUpdateFailure::HeldUnderflow),
+                DisputeState::Disputed => {
+                    self.held_wealth -= transaction_event.ammount;
+                    self.wealth += transaction_event.ammount;
+                    self.frozen = true;
+                    transaction_event.state = DisputeState::ChargedBack;
+                    self.open_disputes = self.open_disputes.saturating_sub(1);
+                    self.transaction_count += 1;
+                    Ok(())
+                },
+                DisputeState::ChargedBack => Err(AccountUpdateFailure::AlreadyChargedBack),
+                DisputeState::Undisputed => Err(AccountUpdateFailure::TXUndisputed),
             }
         }
         else {
             Err(AccountUpdateFailure::TXNotFound)
         }
     }
+    /// Reverses a chargeback, as when the payment processor reverses it, returning the funds to their disputed state
+    /// 
+    /// For a deposit, the funds are held again; for a withdrawal, the funds returned to available funds are held again.
+    /// The account is unfrozen, unless another of its transactions remains charged back.
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(AccountUpdateFailure::NotChargedBack)       The transaction was not charged back, so there is nothing to reverse
+    /// Err(AccountUpdateFailure::TXNotFound)           The transaction was not made to this user account
+    /// Ok(())
+    /// 
+    pub fn reverse_chargeback(&mut self, transaction: TransactionID) -> Result<(), AccountUpdateFailure> {
+        if let Some(transaction_event) = self.deposit_history.get_mut(&transaction) {
+            match transaction_event.state {
+                DisputeState::ChargedBack => {
+                    self.held_wealth += transaction_event.ammount;
+                    transaction_event.state = DisputeState::Disputed;
+                },
+                DisputeState::Undisputed | DisputeState::Disputed => return Err(AccountUpdateFailure::NotChargedBack),
+            }
+        }
+        else if let Some(transaction_event) = self.withdrawal_history.get_mut(&transaction) {
+            match transaction_event.state {
+                DisputeState::ChargedBack => {
+                    self.wealth -= transaction_event.ammount;
+                    self.held_wealth += transaction_event.ammount;
+                    transaction_event.state = DisputeState::Disputed;
+                },
+                DisputeState::Undisputed | DisputeState::Disputed => return Err(AccountUpdateFailure::NotChargedBack),
+            }
+        }
+        else {
+            return Err(AccountUpdateFailure::TXNotFound);
+        }
+
+        self.frozen = self.deposit_history.values().chain(self.withdrawal_history.values())
+            .any(|transaction_event| transaction_event.state == DisputeState::ChargedBack);
+        self.open_disputes += 1;
+        self.transaction_count += 1;
+        Ok(())
+    }
     /// Submits a resolve on a dispute into the account, releasing the funds held in dispute
     /// 
+    /// For a deposit, the held funds return to available funds; for a withdrawal, the claim on them is dropped.
+    /// 
     /// # Return Value
     /// 
+    /// Err(AccountUpdateFailure::AlreadyChargedBack)   The transaction was already charged back, even if the account is frozen
     /// Err(AccountUpdateFailure::Frozen)               The account is locked, which occurs when a chargeback happens on the account
     /// Err(AccountUpdateFailure::TXUndisputed)         The transaction was not under dispute, so a resolve does not make since
-    /// Err(AccountUpdateFailure::TXNotFound)           The deposit to be disputed was not made to this user account
+    /// Err(AccountUpdateFailure::TXNotFound)           The transaction to be disputed was not made to this user account
     /// Ok(())
     /// 
     pub fn resolve(&mut self, transaction: TransactionID) -> Result<(), AccountUpdateFailure> {
-        if self.frozen {
+        // a charged back tx is reported as such, rather than as the frozen account its chargeback left, so it is not mistaken for any other tx
+        if self.is_charged_back(transaction) {
+            Err(AccountUpdateFailure::AlreadyChargedBack)
+        }
+        else if self.frozen {
             Err(AccountUpdateFailure::Frozen)
         }
         else if let Some(transaction) = self.deposit_history.get_mut(&transaction) {
-            if transaction.disputed {
-                transaction.disputed = false;
-                self.wealth += transaction.ammount;
-                self.held_wealth -= transaction.ammount;
-                Ok(())
+            match transaction.state {
+                DisputeState::Disputed => {
+                    transaction.state = DisputeState::Undisputed;
+                    self.wealth += transaction.ammount;
+                    self.held_wealth -= transaction.ammount;
+                    self.open_disputes = self.open_disputes.saturating_sub(1);
+                    self.transaction_count += 1;
+                    Ok(())
+                },
+                DisputeState::ChargedBack => Err(AccountUpdateFailure::AlreadyChargedBack),
+                DisputeState::Undisputed => Err(AccountUpdateFailure::TXUndisputed),
             }
-            else {
-                Err(AccountUpdateFailure::TXUndisputed)
+        }
+        else if let Some(transaction) = self.withdrawal_history.get_mut(&transaction) {
+            match transaction.state {
+                DisputeState::Disputed => {
+                    transaction.state = DisputeState::Undisputed;
+                    self.held_wealth -= transaction.ammount;
+                    self.open_disputes = self.open_disputes.saturating_sub(1);
+                    self.transaction_count += 1;
+                    Ok(())
+                },
+                DisputeState::ChargedBack => Err(AccountUpdateFailure::AlreadyChargedBack),
+                DisputeState::Undisputed => Err(AccountUpdateFailure::TXUndisputed),
             }
         }
         else {
             Err(AccountUpdateFailure::TXNotFound)
         }
     }
+    // The sum of the disputed deposits, which resolving would return to available funds
+    fn held_deposits(&self) -> Decimal {
+        self.deposit_history.values()
+            .filter(|deposit| deposit.state == DisputeState::Disputed)
+            .map(|deposit| deposit.ammount)
+            .sum()
+    }
+    // Whether the deposit or withdrawal with the tx was charged back
+    fn is_charged_back(&self, transaction: TransactionID) -> bool {
+        self.deposit_history.get(&transaction).or_else(|| self.withdrawal_history.get(&transaction))
+            .is_some_and(|transaction_event| transaction_event.state == DisputeState::ChargedBack)
+    }
+    /// Applies a command to the account, calling the method matching its type
+    /// 
+    /// The mapping is the command's executable form (see Command::to_executable), which the command_handler dispatches as well.
+    /// Adjustment debits may not leave the available funds negative.  Creating the account of an unknown client is left to the caller.
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(AccountUpdateFailure::MissingAmount)    A deposit, withdrawal, or adjustment does not have an ammount
+    /// Err(AccountUpdateFailure)                   The method matching the command's type failed
+    /// Ok(())
+    /// 
+    pub fn apply(&mut self, cmd: &Command) -> Result<(), AccountUpdateFailure> {
+        match cmd.to_executable(false, None) {
+            Some(executable) => executable.execute(self),
+            None => Err(AccountUpdateFailure::MissingAmount),
+        }
+    }
 }
 
 #[cfg(test)]
 mod client_data_tests {
-    use crate::client_data::AccountUpdateFailure;
+    use crate::client_data::{AccountUpdateFailure, ClientDataBuildFailure};
 
-    use super::ClientData;
+    use super::{ClientData, FeePolicy, FrozenPolicy, NetPosition, TransactionID};
+    use crate::test_support::assert_balances_eq;
     use rust_decimal_macros::dec;
 
     #[test]
@@ -259,20 +779,46 @@ mod client_data_tests {
         assert_eq!(client.get_wealth(), dec!(0.0000));
         assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
 
-        assert_eq!(Ok(()), client.withdraw(dec!(10.0)));
+        assert_eq!(Ok(()), client.withdraw(101, dec!(10.0)));
         assert_eq!(client.get_wealth(), dec!(10.0));
         
         client.frozen = true;
-        let result = client.withdraw(dec!(5.0));
+        let result = client.withdraw(102, dec!(5.0));
         assert_eq!(result, Err(AccountUpdateFailure::Frozen));
         client.frozen = false;
 
-        let result = client.withdraw(dec!(500.0));
+        let result = client.withdraw(103, dec!(500.0));
         assert_eq!(result, Err(AccountUpdateFailure::InsufficientFunds));
 
+        // the dispute holds more than the withdrawal, so it is the hold which blocks it
         assert_eq!(Ok(()), client.dispute(1));
-        let result = client.withdraw(dec!(5.0));
-        assert_eq!(result, Err(AccountUpdateFailure::InsufficientFunds));
+        let result = client.withdraw(104, dec!(5.0));
+        assert_eq!(result, Err(AccountUpdateFailure::FundsHeld));
+    }
+
+    #[test]
+    fn test_withdraw_funds_held() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10.0)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(5.0)));
+        assert_eq!(Ok(()), client.dispute(2));
+
+        // 10 is available and 5 held, so a withdrawal of 12 is only blocked by the hold
+        assert_eq!(Err(AccountUpdateFailure::FundsHeld), client.withdraw(3, dec!(12.0)));
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.withdraw(3, dec!(15.01)));
+        assert_balances_eq(&client, dec!(10), dec!(5), dec!(15));
+
+        // once the dispute is resolved, the same withdrawal succeeds
+        assert_eq!(Ok(()), client.resolve(2));
+        assert_eq!(Ok(()), client.withdraw(3, dec!(12.0)));
+        assert_balances_eq(&client, dec!(3), dec!(0), dec!(3));
+
+        // the hold on a disputed withdrawal is never returned to available funds, so the funds are insufficient rather than held
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10.0)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(8.0)));
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.withdraw(3, dec!(5.0)));
     }
 
     #[test]
@@ -282,18 +828,17 @@ mod client_data_tests {
         assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
         
         assert_eq!(Ok(()), client.dispute(1));
-        assert_eq!(client.get_wealth(), dec!(0.0000));
-        assert_eq!(client.get_held_wealth(), dec!(20.0));
+        assert_balances_eq(&client, dec!(0), dec!(20), dec!(20));
 
         assert_eq!(Err(AccountUpdateFailure::RedundantDispute), client.dispute(1));
 
         // to verify disput can be done again after resolve
         assert_eq!(Ok(()), client.resolve(1));
         // to verify disputing insufficient funds forces available balance negative
-        assert_eq!(Ok(()), client.withdraw(dec!(5.0)));
+        assert_eq!(Ok(()), client.withdraw(105, dec!(5.0)));
 
         assert_eq!(Ok(()), client.dispute(1));
-        assert_eq!(client.get_wealth(), dec!(-5.0));
+        assert_balances_eq(&client, dec!(-5), dec!(20), dec!(15));
 
         assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.dispute(42));
         
@@ -301,6 +846,66 @@ mod client_data_tests {
         assert_eq!(Err(AccountUpdateFailure::Frozen), client.dispute(1));
     }
 
+    #[test]
+    fn test_is_disputed() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(5.0)));
+
+        assert_eq!(Some(false), client.is_disputed(1));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Some(true), client.is_disputed(1));
+        assert_eq!(Ok(()), client.resolve(1));
+        assert_eq!(Some(false), client.is_disputed(1));
+
+        // only deposits are reported
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(None, client.is_disputed(2));
+        assert_eq!(None, client.is_disputed(42));
+
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.chargeback(1));
+        assert_eq!(Some(false), client.is_disputed(1));
+    }
+
+    #[test]
+    fn test_dispute_limit() {
+        const MAX: u64 = 2;
+        let mut client = ClientData::new();
+        for tx in 1..=MAX as TransactionID + 1 {
+            assert_eq!(Ok(()), client.deposit(tx, dec!(10.0)));
+        }
+
+        assert_eq!(Ok(()), client.dispute_with_limit(1, Some(MAX)));
+        assert_eq!(Ok(()), client.dispute_with_limit(2, Some(MAX)));
+        assert_eq!(MAX, client.get_open_disputes());
+
+        // the N+1th open dispute is rejected, leaving the account as it was
+        let before = client.clone();
+        assert_eq!(Err(AccountUpdateFailure::DisputeLimitReached), client.dispute_with_limit(3, Some(MAX)));
+        assert_eq!(before, client);
+
+        // a redundant dispute is reported as such, even at the limit
+        assert_eq!(Err(AccountUpdateFailure::RedundantDispute), client.dispute_with_limit(1, Some(MAX)));
+
+        // resolving frees a slot
+        assert_eq!(Ok(()), client.resolve(1));
+        assert_eq!(Ok(()), client.dispute_with_limit(3, Some(MAX)));
+        assert_balances_eq(&client, dec!(10), dec!(20), dec!(30));
+
+        // so does charging back, and reversing the chargeback takes the slot again
+        assert_eq!(Ok(()), client.chargeback(2));
+        assert_eq!(1, client.get_open_disputes());
+        assert_eq!(Ok(()), client.reverse_chargeback(2));
+        assert_eq!(MAX, client.get_open_disputes());
+
+        // without a limit, disputes are unlimited
+        assert_eq!(Ok(()), client.resolve(3));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.dispute(3));
+        assert_eq!(3, client.get_open_disputes());
+    }
+
     #[test]
     fn test_resolve() {
         let mut client = ClientData::new();
@@ -308,12 +913,10 @@ mod client_data_tests {
         assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
 
         assert_eq!(Ok(()), client.dispute(1));
-        assert_eq!(client.get_wealth(), dec!(0.0000));
-        assert_eq!(client.get_held_wealth(), dec!(20.0));
+        assert_balances_eq(&client, dec!(0), dec!(20), dec!(20));
 
         assert_eq!(Ok(()), client.resolve(1));
-        assert_eq!(client.get_held_wealth(), dec!(0.0000));
-        assert_eq!(client.get_wealth(), dec!(20.0));
+        assert_balances_eq(&client, dec!(20), dec!(0), dec!(20));
 
         assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.resolve(42));
 
@@ -323,6 +926,28 @@ mod client_data_tests {
         assert_eq!(Err(AccountUpdateFailure::Frozen), client.resolve(1));
     }
 
+    #[test]
+    fn test_resolve_charged_back() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.chargeback(1));
+
+        // the account is frozen by the chargeback, but disputing or resolving the tx reports that it was charged back
+        assert!(client.is_locked());
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.dispute(1));
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.resolve(1));
+        assert_balances_eq(&client, dec!(0), dec!(0), dec!(0));
+
+        // as it does when the frozen account still accepts deposits
+        let mut client = ClientData::builder().frozen_policy(FrozenPolicy::AllowDeposits).build().unwrap();
+        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.chargeback(1));
+        assert!(client.is_locked());
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.dispute(1));
+    }
+
     #[test]
     fn test_chargeback() {
         let mut client = ClientData::new();
@@ -330,30 +955,524 @@ mod client_data_tests {
         assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
 
         assert_eq!(Ok(()), client.dispute(1));
-        assert_eq!(client.get_wealth(), dec!(0.0000));
-        assert_eq!(client.get_held_wealth(), dec!(20.0));
+        assert_balances_eq(&client, dec!(0), dec!(20), dec!(20));
 
         assert_eq!(Ok(()), client.chargeback(1));
-        assert_eq!(client.get_wealth(), dec!(0.0000));
-        assert_eq!(client.get_held_wealth(), dec!(0.0000));
+        assert_balances_eq(&client, dec!(0), dec!(0), dec!(0));
 
-        // client should be frozen after chargeback
-        assert_eq!(Err(AccountUpdateFailure::Frozen), client.chargeback(1));
+        // client should be frozen after chargeback, though the charged back tx reports its own state
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.chargeback(1));
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.resolve(1));
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.chargeback(2));
         client.frozen = false;
-        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
+
+        // the charged back tx is retained, so it can neither be reused nor charged back again
+        assert_eq!(Err(AccountUpdateFailure::DuplicateDepositTX), client.deposit(1, dec!(20.0)));
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.chargeback(1));
+        assert_eq!(Ok(()), client.deposit(2, dec!(20.0)));
 
         // to verify chargeback of insufficient funds forces available balance negative
-        assert_eq!(Ok(()), client.withdraw(dec!(5.0)));
+        assert_eq!(Ok(()), client.withdraw(106, dec!(5.0)));
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(Ok(()), client.chargeback(2));
+        assert_balances_eq(&client, dec!(-5), dec!(0), dec!(-5));
+        client.frozen = false;
+
+        assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.chargeback(42));
+
+        assert_eq!(Ok(()), client.deposit(3, dec!(20.0)));
+        assert_eq!(Err(AccountUpdateFailure::TXUndisputed), client.chargeback(3));
+    }
+
+    #[test]
+    fn test_dispute_after_chargeback_and_redeposit() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
         assert_eq!(Ok(()), client.dispute(1));
         assert_eq!(Ok(()), client.chargeback(1));
-        assert_eq!(client.get_wealth(), dec!(-5.0000));
-        assert_eq!(client.get_held_wealth(), dec!(0.0000));
+
+        // the account is unfrozen and a new deposit legitimately uses a different tx
         client.frozen = false;
+        assert_eq!(Ok(()), client.deposit(2, dec!(7.5)));
 
-        assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.chargeback(42));
+        // disputing the old, charged back tx must not touch the new deposit
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.dispute(1));
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.resolve(1));
+        assert_balances_eq(&client, dec!(7.5), dec!(0), dec!(7.5));
+        assert!(!client.is_locked());
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut client = ClientData::builder()
+            .wealth(dec!(10.0))
+            .held_wealth(dec!(4.5))
+            .frozen(true)
+            .build()
+            .unwrap();
+        assert_balances_eq(&client, dec!(10), dec!(4.5), dec!(14.5));
+        assert!(client.is_locked());
+
+        // a pre-frozen client behaves as though it was charged back
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.deposit(1, dec!(1.0)));
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.withdraw(107, dec!(1.0)));
+
+        // held funds have no deposit history to dispute
+        client.frozen = false;
+        assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.resolve(1));
+
+        assert_eq!(Some(ClientDataBuildFailure::NegativeHeldWealth), ClientData::builder().held_wealth(dec!(-0.01)).build().err());
+    }
+
+    #[test]
+    fn test_transaction_count() {
+        let mut client = ClientData::new();
+        assert_eq!(0, client.get_transaction_count());
+
+        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(5.0)));
+        assert_eq!(Ok(()), client.withdraw(108, dec!(10.0)));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.resolve(1));
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(Ok(()), client.chargeback(2));
+        assert_eq!(7, client.get_transaction_count());
+
+        // failed transactions are not counted
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.deposit(3, dec!(1.0)));
+        client.frozen = false;
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.withdraw(109, dec!(500.0)));
+        assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.dispute(42));
+        assert_eq!(7, client.get_transaction_count());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10.0)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(5.0)));
+        let snapshot = client.clone();
+        assert_eq!(snapshot, client);
+
+        // the dispute changes the client, and restoring the snapshot rolls it back
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_ne!(snapshot, client);
+        client = snapshot.clone();
+        assert_eq!(snapshot, client);
+        assert_eq!(Ok(()), client.withdraw(3, dec!(15.0)));
+
+        // a rejected operation leaves the client equal to a snapshot taken before it
+        let snapshot = client.clone();
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.withdraw(4, dec!(1.0)));
+        assert_eq!(Err(AccountUpdateFailure::DuplicateDepositTX), client.deposit(1, dec!(1.0)));
+        assert_eq!(snapshot, client);
+    }
+
+    // An operation on a ClientData, for property tests
+    #[derive(Clone, Debug)]
+    enum Operation {
+        Deposit(u32, i64),
+        Withdraw(u32, i64),
+        Dispute(u32),
+        Resolve(u32),
+        Chargeback(u32),
+        ReverseChargeback(u32),
+        AdjustCredit(i64),
+        AdjustDebit(i64),
+    }
+
+    // Few txs, so that operations often refer to the same ones; ammounts are in cents
+    fn operation() -> impl proptest::strategy::Strategy<Value = Operation> {
+        use proptest::prelude::*;
+
+        let tx = 0..6u32;
+        let ammount = 1..10_000i64;
+        prop_oneof![
+            3 => (tx.clone(), ammount.clone()).prop_map(|(tx, ammount)| Operation::Deposit(tx, ammount)),
+            2 => (tx.clone(), ammount.clone()).prop_map(|(tx, ammount)| Operation::Withdraw(tx, ammount)),
+            2 => tx.clone().prop_map(Operation::Dispute),
+            2 => tx.clone().prop_map(Operation::Resolve),
+            1 => tx.clone().prop_map(Operation::Chargeback),
+            1 => tx.prop_map(Operation::ReverseChargeback),
+            1 => ammount.clone().prop_map(Operation::AdjustCredit),
+            1 => ammount.prop_map(Operation::AdjustDebit),
+        ]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_invariants(operations in proptest::collection::vec(operation(), 1..64)) {
+            use std::collections::HashMap;
+            use rust_decimal::prelude::Decimal;
+            use proptest::prelude::*;
+
+            let mut client = ClientData::new();
+            // the change in total when each tx was last disputed, which its resolve undoes
+            let mut disputed: HashMap<u32, Decimal> = HashMap::new();
+
+            for operation in operations {
+                let snapshot = client.clone();
+                let before = client.position();
+                let was_frozen = client.is_locked();
+
+                let result = match operation {
+                    Operation::Deposit(tx, ammount) => client.deposit(tx, Decimal::new(ammount, 2)),
+                    Operation::Withdraw(tx, ammount) => client.withdraw(tx, Decimal::new(ammount, 2)),
+                    Operation::Dispute(tx) => client.dispute(tx),
+                    Operation::Resolve(tx) => client.resolve(tx),
+                    Operation::Chargeback(tx) => client.chargeback(tx),
+                    Operation::ReverseChargeback(tx) => client.reverse_chargeback(tx),
+                    Operation::AdjustCredit(ammount) => { client.adjust_credit(Decimal::new(ammount, 2)); Ok(()) },
+                    Operation::AdjustDebit(ammount) => client.adjust_debit(Decimal::new(ammount, 2), false),
+                };
+                let after = client.position();
+
+                // the total is always the available and held funds, and held funds are never negative
+                prop_assert_eq!(after.total, after.available + after.held);
+                prop_assert!(after.held >= dec!(0), "{:?} left held funds of {}", operation, after.held);
+
+                // a rejected operation changes nothing, including the account's history
+                if result.is_err() {
+                    prop_assert_eq!(&snapshot, &client);
+                }
+
+                // held funds only grow when a tx is disputed, or its chargeback is reversed back into dispute
+                if after.held > before.held {
+                    prop_assert!(matches!(operation, Operation::Dispute(_) | Operation::ReverseChargeback(_)), "{:?} increased held funds", operation);
+                }
+
+                // available funds only fall below zero when funds already spent are disputed, charged back, or returned to dispute;
+                // deposits, withdrawals, and adjustment debits never overdraw the account
+                if after.available < dec!(0) && after.available < before.available {
+                    prop_assert!(matches!(operation, Operation::Dispute(_) | Operation::Chargeback(_) | Operation::ReverseChargeback(_)), "{:?} overdrew the account", operation);
+                }
+
+                // a frozen account rejects everything but reversing its chargebacks and operator adjustments,
+                // though disputing, resolving, or charging back a charged back tx reports that instead
+                if was_frozen && matches!(operation, Operation::Deposit(..) | Operation::Withdraw(..) | Operation::Dispute(_) | Operation::Resolve(_) | Operation::Chargeback(_)) {
+                    let charged_back = matches!(operation, Operation::Dispute(tx) | Operation::Resolve(tx) | Operation::Chargeback(tx) if snapshot.is_charged_back(tx));
+                    prop_assert_eq!(Err(if charged_back { AccountUpdateFailure::AlreadyChargedBack } else { AccountUpdateFailure::Frozen }), result);
+                }
+
+                // resolving a dispute restores the total from before it
+                match (operation, result) {
+                    (Operation::Dispute(tx), Ok(())) => { disputed.insert(tx, after.total - before.total); },
+                    (Operation::Resolve(tx), Ok(())) => prop_assert_eq!(-disputed[&tx], after.total - before.total),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_total_overflow() {
+        use rust_decimal::prelude::Decimal;
+
+        let client = ClientData::builder().wealth(Decimal::MAX).held_wealth(dec!(1)).build().unwrap();
+        assert_eq!(None, client.try_total());
+
+        crate::logger::capture::start();
+        assert_eq!(Decimal::MAX, client.get_total());
+        let logs = crate::logger::capture::take();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
+
+        let client = ClientData::builder().wealth(Decimal::MIN).held_wealth(dec!(0)).build().unwrap();
+        assert_eq!(Some(Decimal::MIN), client.try_total());
+        let client = ClientData::builder().wealth(Decimal::MIN).held_wealth(dec!(1)).build().unwrap();
+        assert_eq!(Some(Decimal::MIN + dec!(1)), client.try_total());
+
+        let client = ClientData::builder().wealth(Decimal::MAX - dec!(1)).held_wealth(dec!(1)).build().unwrap();
+        assert_eq!(Some(Decimal::MAX), client.try_total());
+        assert_eq!(Decimal::MAX, client.get_total());
+    }
+
+    #[test]
+    fn test_apply() {
+        use crate::command::{Command, CommandType};
+
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Open, 1, 1, None)));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Deposit, 1, 2, Some(dec!(10.0)))));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Withdraw, 1, 3, Some(dec!(2.0)))));
+        assert_balances_eq(&client, dec!(8), dec!(0), dec!(8));
 
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Dispute, 1, 2, None)));
+        assert_balances_eq(&client, dec!(-2), dec!(10), dec!(8));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Resolve, 1, 2, None)));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Dispute, 1, 2, None)));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Chargeback, 1, 2, None)));
+        assert_balances_eq(&client, dec!(-2), dec!(0), dec!(-2));
+        assert!(client.is_locked());
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::ReverseChargeback, 1, 2, None)));
+        assert_balances_eq(&client, dec!(-2), dec!(10), dec!(8));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::Resolve, 1, 2, None)));
+        assert_balances_eq(&client, dec!(8), dec!(0), dec!(8));
+
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::AdjustmentCredit, 1, 4, Some(dec!(1.5)))));
+        assert_eq!(Ok(()), client.apply(&Command::new(CommandType::AdjustmentDebit, 1, 5, Some(dec!(0.5)))));
+        assert_balances_eq(&client, dec!(9), dec!(0), dec!(9));
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.apply(&Command::new(CommandType::AdjustmentDebit, 1, 6, Some(dec!(20)))));
+
+        // failures are those of the matching method
+        assert_eq!(Err(AccountUpdateFailure::DuplicateDepositTX), client.apply(&Command::new(CommandType::Deposit, 1, 2, Some(dec!(1.0)))));
+        assert_eq!(Err(AccountUpdateFailure::MissingAmount), client.apply(&Command::new(CommandType::Deposit, 1, 7, None)));
+        assert_balances_eq(&client, dec!(9), dec!(0), dec!(9));
+    }
+
+    #[test]
+    fn test_deposit_records() {
+        let mut client = ClientData::new();
+        assert!(client.deposit_records().is_empty());
+
+        assert_eq!(Ok(()), client.deposit(9, dec!(3.0)));
+        assert_eq!(Ok(()), client.deposit(4, dec!(1.5)));
+        assert_eq!(Ok(()), client.dispute(9));
+
+        assert_eq!(vec![(4, dec!(1.5), false, None), (9, dec!(3.0), true, None)], client.deposit_records());
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_chargeback() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(8.0)));
+
+        // the claim is held without touching available funds
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(client.get_wealth(), dec!(12.0));
+        assert_eq!(client.get_held_wealth(), dec!(8.0));
+        assert_eq!(client.get_total(), dec!(20.0));
+        assert_eq!(Err(AccountUpdateFailure::RedundantDispute), client.dispute(2));
+
+        // the chargeback returns the withdrawn funds
+        assert_eq!(Ok(()), client.chargeback(2));
+        assert_eq!(client.get_wealth(), dec!(20.0));
+        assert_eq!(client.get_held_wealth(), dec!(0.0));
+        assert!(client.is_locked());
+
+        client.frozen = false;
+        assert_eq!(Err(AccountUpdateFailure::AlreadyChargedBack), client.dispute(2));
+        assert_eq!(Err(AccountUpdateFailure::DuplicateWithdrawalTX), client.withdraw(2, dec!(1.0)));
+        assert_eq!(Err(AccountUpdateFailure::DuplicateDepositTX), client.deposit(2, dec!(1.0)));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_resolve() {
+        let mut client = ClientData::new();
         assert_eq!(Ok(()), client.deposit(1, dec!(20.0)));
-        assert_eq!(Err(AccountUpdateFailure::TXUndisputed), client.chargeback(1));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(8.0)));
+        assert_eq!(Err(AccountUpdateFailure::TXUndisputed), client.resolve(2));
+
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(client.get_held_wealth(), dec!(8.0));
+
+        // the claim is dropped; the withdrawal stands
+        assert_eq!(Ok(()), client.resolve(2));
+        assert_eq!(client.get_wealth(), dec!(12.0));
+        assert_eq!(client.get_held_wealth(), dec!(0.0));
+        assert!(!client.is_locked());
+
+        // and may be disputed again
+        assert_eq!(Ok(()), client.dispute(2));
+    }
+
+    #[test]
+    fn test_deposit_scale() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(5)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(5.0000)));
+
+        let records = client.deposit_records();
+        assert_eq!(records[0].1.to_string(), records[1].1.to_string());
+
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(client.get_held_wealth().to_string(), dec!(10).to_string());
+        assert_eq!(client.get_wealth(), dec!(0));
+
+        assert_eq!(Ok(()), client.resolve(1));
+        assert_eq!(Ok(()), client.resolve(2));
+        assert_eq!(client.get_held_wealth(), dec!(0));
+        assert_eq!(client.get_wealth(), dec!(10));
+    }
+
+    #[test]
+    fn test_position() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(2.5)));
+        assert_eq!(Ok(()), client.dispute(2));
+
+        assert_eq!(NetPosition { available: dec!(10), held: dec!(2.5), total: dec!(12.5) }, client.position());
+        assert_eq!(client.available(), client.get_wealth());
+        assert_eq!(client.total(), client.get_total());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_failure_display() {
+        assert_eq!("the account is frozen", AccountUpdateFailure::Frozen.to_string());
+        assert_eq!("the transaction did not correspond to a known deposit or withdrawal for the client", AccountUpdateFailure::TXNotFound.to_string());
+        assert_eq!("the transaction is not under dispute", AccountUpdateFailure::TXUndisputed.to_string());
+        assert_eq!("the account has insufficient funds", AccountUpdateFailure::InsufficientFunds.to_string());
+        assert_eq!("the funds are held in dispute", AccountUpdateFailure::FundsHeld.to_string());
+        assert_eq!("the available balance is 2.5", AccountUpdateFailure::BalanceMismatch(dec!(2.5)).to_string());
+        assert_eq!("the deposit tx id is a duplicate", AccountUpdateFailure::DuplicateDepositTX.to_string());
+        assert_eq!("the withdrawal tx id is a duplicate", AccountUpdateFailure::DuplicateWithdrawalTX.to_string());
+        assert_eq!("the dispute was redundant", AccountUpdateFailure::RedundantDispute.to_string());
+        assert_eq!("the transaction was already charged back", AccountUpdateFailure::AlreadyChargedBack.to_string());
+        assert_eq!("the transaction was not charged back", AccountUpdateFailure::NotChargedBack.to_string());
+        assert_eq!("the account has reached its limit of open disputes", AccountUpdateFailure::DisputeLimitReached.to_string());
+
+        let err: Box<dyn std::error::Error> = Box::new(AccountUpdateFailure::Frozen);
+        assert_eq!("the account is frozen", err.to_string());
+    }
+
+    #[test]
+    fn test_reverse_chargeback() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(4)));
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_eq!(Ok(()), client.chargeback(2));
+        assert!(client.is_locked());
+        assert_eq!(dec!(10), client.get_total());
+
+        // the funds return to held, and the account is unfrozen
+        assert_eq!(Ok(()), client.reverse_chargeback(2));
+        assert!(!client.is_locked());
+        assert_eq!(dec!(10), client.get_wealth());
+        assert_eq!(dec!(4), client.get_held_wealth());
+
+        // the dispute can then be resolved as usual
+        assert_eq!(Ok(()), client.resolve(2));
+        assert_eq!(dec!(14), client.get_wealth());
+        assert_eq!(dec!(0), client.get_held_wealth());
+
+        // only charged back transactions can be reversed
+        assert_eq!(Err(AccountUpdateFailure::NotChargedBack), client.reverse_chargeback(1));
+        assert_eq!(Err(AccountUpdateFailure::NotChargedBack), client.reverse_chargeback(2));
+        assert_eq!(Err(AccountUpdateFailure::TXNotFound), client.reverse_chargeback(3));
+    }
+
+    #[test]
+    fn test_reverse_withdrawal_chargeback() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(5)));
+        assert_eq!(Ok(()), client.withdraw(3, dec!(3)));
+        assert_eq!(Ok(()), client.dispute(3));
+        assert_eq!(Ok(()), client.chargeback(3));
+        assert_eq!(dec!(15), client.get_wealth());
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.dispute(2));
+
+        assert_eq!(Ok(()), client.reverse_chargeback(3));
+        assert!(!client.is_locked());
+        assert_eq!(dec!(12), client.get_wealth());
+        assert_eq!(dec!(3), client.get_held_wealth());
+        assert_eq!(Ok(()), client.dispute(2));
+    }
+
+    #[test]
+    fn test_frozen_policy() {
+        // charges back a deposit, freezing the account
+        fn freeze(client: &mut ClientData) {
+            assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+            assert_eq!(Ok(()), client.dispute(1));
+            assert_eq!(Ok(()), client.chargeback(1));
+            assert!(client.is_locked());
+        }
+
+        let mut client = ClientData::new();
+        assert_eq!(FrozenPolicy::BlockAll, client.get_frozen_policy());
+        freeze(&mut client);
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.deposit(2, dec!(5)));
+        assert_eq!(dec!(0), client.get_total());
+
+        let mut client = ClientData::with_policy(FrozenPolicy::AllowDeposits);
+        freeze(&mut client);
+        assert_eq!(Ok(()), client.deposit(2, dec!(5)));
+        assert_eq!(dec!(5), client.get_wealth());
+        assert!(client.is_locked());
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.withdraw(3, dec!(1)));
+        assert_eq!(Err(AccountUpdateFailure::Frozen), client.dispute(2));
+
+        let client = ClientData::builder().frozen(true).frozen_policy(FrozenPolicy::AllowDeposits).build();
+        assert_eq!(FrozenPolicy::AllowDeposits, client.unwrap().get_frozen_policy());
+    }
+
+    #[test]
+    fn test_percentage_fee() {
+        let mut client = ClientData::builder().fee_policy(FeePolicy::Percentage(dec!(1))).build().unwrap();
+        assert_eq!(Ok(()), client.deposit(1, dec!(101)));
+
+        // 1% of 100 is 1, so the whole balance covers the withdrawal and its fee
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.withdraw(2, dec!(100.01)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(100)));
+        assert_balances_eq(&client, dec!(0), dec!(0), dec!(0));
+        assert_eq!(dec!(1), client.get_fees_collected());
+
+        // disputing the withdrawal holds the ammount withdrawn, not the fee
+        assert_eq!(Ok(()), client.dispute(2));
+        assert_balances_eq(&client, dec!(0), dec!(100), dec!(100));
+    }
+
+    #[test]
+    fn test_fixed_fee() {
+        let mut client = ClientData::builder().fee_policy(FeePolicy::Fixed(dec!(2.5))).build().unwrap();
+        assert_eq!(FeePolicy::Fixed(dec!(2.5)), client.get_fee_policy());
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.withdraw(2, dec!(7.5001)));
+        assert_eq!(dec!(0), client.get_fees_collected());
+        assert_eq!(Ok(()), client.withdraw(2, dec!(7.5)));
+        assert_balances_eq(&client, dec!(0), dec!(0), dec!(0));
+        assert_eq!(dec!(2.5), client.get_fees_collected());
+
+        // there is no fee by default
+        let mut client = ClientData::new();
+        assert_eq!(FeePolicy::NoFee, client.get_fee_policy());
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(10)));
+        assert_eq!(dec!(0), client.get_fees_collected());
+    }
+
+    #[test]
+    fn test_chargeback_held_underflow() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(3)));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.dispute(2));
+
+        // the held funds no longer cover either dispute, as though they were changed outside of disputes
+        client.held_wealth = dec!(2);
+        assert_eq!(Err(AccountUpdateFailure::HeldUnderflow), client.chargeback(1));
+        assert_eq!(Err(AccountUpdateFailure::HeldUnderflow), client.chargeback(2));
+        assert_balances_eq(&client, dec!(-3), dec!(2), dec!(-1));
+        assert!(!client.is_locked());
+
+        // the guard allows a chargeback of exactly the held funds
+        client.held_wealth = dec!(3);
+        assert_eq!(Ok(()), client.chargeback(2));
+        assert_balances_eq(&client, dec!(0), dec!(0), dec!(0));
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        client.adjust_credit(dec!(2.5));
+        assert_balances_eq(&client, dec!(12.5), dec!(0), dec!(12.5));
+        assert_eq!(Ok(()), client.adjust_debit(dec!(12.5), false));
+        assert_balances_eq(&client, dec!(0), dec!(0), dec!(0));
+
+        // adjustments are not disputable
+        assert_eq!(1, client.deposit_records().len());
+
+        assert_eq!(Err(AccountUpdateFailure::InsufficientFunds), client.adjust_debit(dec!(1), false));
+        assert_eq!(Ok(()), client.adjust_debit(dec!(1), true));
+        assert_balances_eq(&client, dec!(-1), dec!(0), dec!(-1));
+        assert_eq!(4, client.get_transaction_count());
+    }
+}
@@ -0,0 +1,60 @@
+//! # client_store module
+//! This module separates where client data lives from the logic which uses it.
+//! 
+//! The handler, checkpoints, and output only reach client data through the ClientStore trait, so an implementation backed by a database
+//! could stand in for the in-memory HashMap when there are too many clients to hold in memory.
+//! 
+
+use std::collections::HashMap;
+
+use crate::client_data::{ClientData, ClientID, FrozenPolicy};
+
+/// Storage for client accounts, keyed by client
+pub trait ClientStore {
+    /// The client's account, if it has one
+    fn get(&mut self, client_id: ClientID) -> Option<&mut ClientData>;
+    /// The client's account, opening an empty one under the frozen_policy if it has none
+    fn get_or_create(&mut self, client_id: ClientID, frozen_policy: FrozenPolicy) -> &mut ClientData;
+    /// Every client's account, in no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientID, &ClientData)> + '_>;
+    /// Every client's account, in ascending order of client
+    /// 
+    /// Output, checkpoints, and audits iterate clients through this, so they are deterministic whatever order the store keeps.
+    fn sorted_clients(&self) -> Vec<(ClientID, &ClientData)> {
+        let mut clients: Vec<(ClientID, &ClientData)> = self.iter().collect();
+        clients.sort_by_key(|(client_id, _)| *client_id);
+        clients
+    }
+}
+
+/// The in-memory store
+impl ClientStore for HashMap<ClientID, Box<ClientData>> {
+    fn get(&mut self, client_id: ClientID) -> Option<&mut ClientData> {
+        self.get_mut(&client_id).map(|client| client.as_mut())
+    }
+    fn get_or_create(&mut self, client_id: ClientID, frozen_policy: FrozenPolicy) -> &mut ClientData {
+        self.entry(client_id).or_insert_with(|| Box::new(ClientData::with_policy(frozen_policy)))
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientID, &ClientData)> + '_> {
+        Box::new(HashMap::iter(self).map(|(client_id, client)| (*client_id, client.as_ref())))
+    }
+}
+
+#[cfg(test)]
+mod client_store_tests {
+    use std::collections::HashMap;
+
+    use crate::client_data::{ClientData, ClientID, FrozenPolicy};
+    use crate::client_store::ClientStore;
+
+    #[test]
+    fn test_sorted_clients() {
+        let mut clients: HashMap<ClientID, Box<ClientData>> = HashMap::new();
+        for client_id in [42, 7, 65535, 0, 1000, 3, 9, 1] {
+            clients.get_or_create(client_id, FrozenPolicy::default());
+        }
+
+        let order: Vec<ClientID> = clients.sorted_clients().into_iter().map(|(client_id, _)| client_id).collect();
+        assert_eq!(vec![0, 1, 3, 7, 9, 42, 1000, 65535], order);
+    }
+}
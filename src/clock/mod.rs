@@ -0,0 +1,45 @@
+//! # clock module
+//! This module separates reading the time, so logic which depends on it, such as progress timing, can be tested deterministically.
+//! 
+//! Code which reads the time takes a Clock rather than calling Instant::now, and uses SystemClock outside of tests.
+//! 
+
+use std::time::Instant;
+
+/// A source of the current time
+pub trait Clock: Send + Sync {
+    /// The current time
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+}
+
+/// A clock for tests which advances by the same step each time it is read, so elapsed times are the same on every run
+#[cfg(test)]
+pub(crate) struct FixedClock {
+    start: Instant,
+    step: std::time::Duration,
+    reads: std::sync::atomic::AtomicU32,
+}
+
+#[cfg(test)]
+impl FixedClock {
+    /// A clock which first reads as the time it was created, then step later with each read
+    pub(crate) fn new(step: std::time::Duration) -> FixedClock {
+        FixedClock { start: Instant::now(), step, reads: std::sync::atomic::AtomicU32::new(0) }
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> Instant {
+        let reads = self.reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.start + self.step * reads
+    }
+}
@@ -5,32 +5,84 @@
 //!  > the potential to keep a command history and role back changes if needed, 
 //!  > the potential to (after solving race conditions which would occur), have more than one thread servicing commands for data processing
 //!  > ...
+use chrono::{DateTime, Utc};
 use rust_decimal::prelude::Decimal;
-use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Deserializer};
 
-use crate::client_data::{TransactionID, ClientID};
+use crate::client_data::{AccountUpdateFailure, ClientData, TransactionID, ClientID};
+use crate::logger;
 
-// TODO A more flexible sollution would be to have the command processor accept commands which implement a common trait, like 'execute'
 // TODO: what if disputed deposit should send acconut negative?
 //   TODO: verify disputes are on deposits... check examples' transaction numbers
 
-#[derive(Deserialize, Copy, Clone, PartialEq, Debug)]
+/// The kinds of commands
+/// 
+/// Besides its canonical name, each kind accepts common aliases found in real-world files, such as `withdraw` for `withdrawal`.
+/// Names are matched without regard to case when deserializing a Command.
+#[derive(Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum CommandType {
-    #[serde(rename = "withdrawal")]
+    #[serde(rename = "withdrawal", alias = "withdraw", alias = "debit")]
     Withdraw,
-    #[serde(rename = "deposit")]
+    #[serde(rename = "deposit", alias = "credit")]
     Deposit,
     #[serde(rename = "dispute")]
     Dispute,
     #[serde(rename = "resolve")]
     Resolve,
-    #[serde(rename = "chargeback")]
+    #[serde(rename = "chargeback", alias = "charge-back", alias = "charge_back")]
     Chargeback,
+    #[serde(rename = "reverse", alias = "reverse-chargeback", alias = "reverse_chargeback")]
+    ReverseChargeback,
+    /// opens an empty account for the client, as when onboarding it before any transactions; its tx is not recorded
+    #[serde(rename = "open")]
+    Open,
+    /// a manual correction crediting the account, which is not disputable
+    #[serde(rename = "adjustment-credit", alias = "adjustment_credit")]
+    AdjustmentCredit,
+    /// a manual correction debiting the account, which is not disputable
+    #[serde(rename = "adjustment-debit", alias = "adjustment_debit")]
+    AdjustmentDebit,
+    /// checks that the client's available funds equal its ammount, so a file can verify itself as it goes; it changes nothing
+    #[serde(rename = "assert")]
+    Assert,
+}
+
+impl std::str::FromStr for CommandType {
+    type Err = serde::de::value::Error;
+
+    /// Gets the kind of command by its canonical name or an alias, without regard to case, as when deserializing a Command
+    fn from_str(name: &str) -> Result<CommandType, Self::Err> {
+        deserialize_command_type(IntoDeserializer::<Self::Err>::into_deserializer(name))
+    }
+}
+
+impl CommandType {
+    /// Whether commands of the kind carry an ammount of their own, rather than referring to the ammount of an earlier tx
+    pub fn carries_ammount(self) -> bool {
+        matches!(self, CommandType::Deposit | CommandType::Withdraw | CommandType::AdjustmentCredit | CommandType::AdjustmentDebit | CommandType::Assert)
+    }
+
+    /// The canonical name of the kind, as in the type column of a transactions csv
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandType::Withdraw => "withdrawal",
+            CommandType::Deposit => "deposit",
+            CommandType::Dispute => "dispute",
+            CommandType::Resolve => "resolve",
+            CommandType::Chargeback => "chargeback",
+            CommandType::ReverseChargeback => "reverse",
+            CommandType::Open => "open",
+            CommandType::AdjustmentCredit => "adjustment-credit",
+            CommandType::AdjustmentDebit => "adjustment-debit",
+            CommandType::Assert => "assert",
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct Command {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", deserialize_with = "deserialize_command_type")]
     command_type: CommandType,
     #[serde(rename = "client")]
     client_id: ClientID,
@@ -38,9 +90,86 @@ pub struct Command {
     transaction_id: TransactionID,
     #[serde(rename = "amount")]
     wealth: Option<Decimal>,
+    /// when the transaction occurred, if the csv has a time column; it does not affect balances
+    #[serde(rename = "time", default, deserialize_with = "deserialize_timestamp")]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+/// The most digits after the decimal an ammount may have
+pub const MAX_SCALE: u32 = 4;
+
+// Symbols which may lead an ammount, after any sign
+const CURRENCY_SYMBOLS: [char; 7] = ['$', '€', '£', '¥', '₹', '₩', '¢'];
+
+/// Removes the currency decorations around an ammount, such as `$5.00` or `5.00 USD`, so it can be parsed as a Decimal
+/// 
+/// A leading currency symbol, which may follow a sign, and a trailing three letter currency code are removed, along with the whitespace around them.
+/// Anything else is left as it is, so an ambiguous ammount, such as `5,00`, still fails to parse.
+pub fn strip_currency(ammount: &str) -> String {
+    let ammount = ammount.trim();
+    let ammount = match ammount.len().checked_sub(3).and_then(|start| ammount.get(start..).map(|code| (start, code))) {
+        Some((start, code)) if code.chars().all(|c| c.is_ascii_uppercase()) => ammount[..start].trim_end(),
+        _ => ammount,
+    };
+    let (sign, ammount) = match ammount.strip_prefix(['-', '+']) {
+        Some(rest) => (&ammount[..1], rest),
+        None => ("", ammount),
+    };
+    format!("{}{}", sign, ammount.strip_prefix(CURRENCY_SYMBOLS).unwrap_or(ammount).trim_start())
+}
+
+/// Reasons a parsed command cannot be executed
+#[derive(PartialEq, Debug)]
+pub enum InvalidCommand {
+    MissingAmount,
+    /// the ammount has more than MAX_SCALE digits after the decimal; see Command::rounded
+    ExcessPrecision,
+    /// the ammount was given in cents, but is negative or not a whole number; see Command::in_dollars
+    InvalidCents,
+}
+
+// Lowercases the type column before matching it against CommandType's names and aliases
+fn deserialize_command_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<CommandType, D::Error> {
+    let name = String::deserialize(deserializer)?.to_lowercase();
+    CommandType::deserialize(IntoDeserializer::<D::Error>::into_deserializer(name))
+}
+
+// Parses the time column as an RFC3339 timestamp; an invalid one is dropped with a warning, rather than rejecting the whole row
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let time = Option::<String>::deserialize(deserializer)?;
+    Ok(time.filter(|time| !time.is_empty()).and_then(|time| match DateTime::parse_from_rfc3339(&time) {
+        Ok(timestamp) => Some(timestamp.with_timezone(&Utc)),
+        Err(err) => {
+            logger::warning(&format!("Ignoring the time {}, which is not an RFC3339 timestamp: {}", time, err));
+            None
+        },
+    }))
 }
 
 impl Command {
+    pub fn new(command_type: CommandType, client_id: ClientID, transaction_id: TransactionID, wealth: Option<Decimal>) -> Command {
+        Command {
+            command_type,
+            client_id,
+            transaction_id,
+            wealth,
+            timestamp: None,
+        }
+    }
+    /// Gets the command with the time its transaction occurred
+    pub fn with_timestamp(self, timestamp: DateTime<Utc>) -> Command {
+        Command {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+    /// Gets the command with the ammount given, as when a missing ammount is defaulted
+    pub fn with_wealth(self, wealth: Decimal) -> Command {
+        Command {
+            wealth: Some(wealth),
+            ..self
+        }
+    }
     pub fn get_type(&self) -> CommandType {
         self.command_type
     }
@@ -53,4 +182,318 @@ impl Command {
     pub fn get_wealth(&self) -> &Option<Decimal> {
         &self.wealth
     }
-}
\ No newline at end of file
+    pub fn get_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+    }
+}
+impl Command {
+    /// Checks that the command carries everything its type requires
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(InvalidCommand::MissingAmount)      a deposit, withdrawal, adjustment, or assert does not have an ammount
+    /// Err(InvalidCommand::ExcessPrecision)    a deposit, withdrawal, adjustment, or assert has more than MAX_SCALE digits after the decimal, ignoring trailing zeros
+    /// Ok(())
+    /// 
+    pub fn validate(&self) -> Result<(), InvalidCommand> {
+        match (self.command_type.carries_ammount(), self.wealth) {
+            (true, None) => Err(InvalidCommand::MissingAmount),
+            (true, Some(wealth)) if wealth.normalize().scale() > MAX_SCALE => Err(InvalidCommand::ExcessPrecision),
+            _ => Ok(()),
+        }
+    }
+    /// Gets the command with its ammount rounded to MAX_SCALE digits after the decimal
+    pub fn rounded(self) -> Command {
+        Command {
+            wealth: self.wealth.map(|wealth| wealth.round_dp(MAX_SCALE)),
+            ..self
+        }
+    }
+    /// Gets the command with its ammount, given in integer cents, converted to dollars
+    /// 
+    /// # Return Value
+    /// 
+    /// Err(InvalidCommand::InvalidCents)       the ammount is negative, or is not a whole number of cents
+    /// Ok(Command)                             commands without an ammount are unchanged
+    /// 
+    pub fn in_dollars(self) -> Result<Command, InvalidCommand> {
+        match self.wealth {
+            Some(cents) if cents.is_sign_negative() || !cents.fract().is_zero() => Err(InvalidCommand::InvalidCents),
+            Some(cents) => Ok(Command {
+                wealth: Some(cents / Decimal::ONE_HUNDRED),
+                ..self
+            }),
+            None => Ok(self),
+        }
+    }
+    /// Gets the executable form of the command, so that it can be dispatched without inspecting its type
+    /// 
+    /// # Arguments
+    /// 
+    /// allow_negative_adjustment   whether an adjustment debit may leave the available funds negative
+    /// max_open_disputes           the most disputes an account may have open at once; None enforces no limit
+    /// 
+    /// # Return Value
+    /// 
+    /// None                deposits, withdrawals, and adjustments which are missing their ammount cannot be executed
+    /// 
+    pub fn to_executable(&self, allow_negative_adjustment: bool, max_open_disputes: Option<u64>) -> Option<Box<dyn Executable>> {
+        match self.command_type {
+            CommandType::Deposit => self.wealth.map(|wealth| Box::new(Deposit { transaction_id: self.transaction_id, wealth, timestamp: self.timestamp }) as Box<dyn Executable>),
+            CommandType::Withdraw => self.wealth.map(|wealth| Box::new(Withdraw { transaction_id: self.transaction_id, wealth }) as Box<dyn Executable>),
+            CommandType::Dispute => Some(Box::new(Dispute { transaction_id: self.transaction_id, max_open_disputes })),
+            CommandType::Resolve => Some(Box::new(Resolve { transaction_id: self.transaction_id })),
+            CommandType::Chargeback => Some(Box::new(Chargeback { transaction_id: self.transaction_id })),
+            CommandType::ReverseChargeback => Some(Box::new(ReverseChargeback { transaction_id: self.transaction_id })),
+            CommandType::Open => Some(Box::new(Open)),
+            CommandType::AdjustmentCredit => self.wealth.map(|wealth| Box::new(AdjustCredit { wealth }) as Box<dyn Executable>),
+            CommandType::AdjustmentDebit => self.wealth.map(|wealth| Box::new(AdjustDebit { wealth, allow_negative: allow_negative_adjustment }) as Box<dyn Executable>),
+            CommandType::Assert => self.wealth.map(|expected| Box::new(Assert { expected }) as Box<dyn Executable>),
+        }
+    }
+}
+
+/// A command which can be executed against a client's account
+/// 
+/// New kinds of commands only need to implement this trait to be dispatched by the command_handler
+pub trait Executable {
+    /// Applies the command to the client's account
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure>;
+    /// The name of the process, used when logging failures
+    fn name(&self) -> &'static str;
+    /// Whether the command should create the client's account when it is unknown
+    fn opens_account(&self) -> bool { false }
+}
+
+pub struct Deposit {
+    transaction_id: TransactionID,
+    wealth: Decimal,
+    timestamp: Option<DateTime<Utc>>,
+}
+
+pub struct Withdraw {
+    transaction_id: TransactionID,
+    wealth: Decimal,
+}
+
+pub struct Dispute {
+    transaction_id: TransactionID,
+    max_open_disputes: Option<u64>,
+}
+
+pub struct Resolve {
+    transaction_id: TransactionID,
+}
+
+pub struct Chargeback {
+    transaction_id: TransactionID,
+}
+
+pub struct ReverseChargeback {
+    transaction_id: TransactionID,
+}
+
+pub struct Open;
+
+pub struct AdjustCredit {
+    wealth: Decimal,
+}
+
+pub struct AdjustDebit {
+    wealth: Decimal,
+    allow_negative: bool,
+}
+
+pub struct Assert {
+    expected: Decimal,
+}
+
+impl Executable for Deposit {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.deposit_at(self.transaction_id, self.wealth, self.timestamp) }
+    fn name(&self) -> &'static str { "deposit" }
+    fn opens_account(&self) -> bool { true }
+}
+
+impl Executable for Withdraw {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.withdraw(self.transaction_id, self.wealth) }
+    fn name(&self) -> &'static str { "withdraw" }
+    fn opens_account(&self) -> bool { true }
+}
+
+impl Executable for Dispute {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.dispute_with_limit(self.transaction_id, self.max_open_disputes) }
+    fn name(&self) -> &'static str { "dispute" }
+}
+
+impl Executable for Resolve {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.resolve(self.transaction_id) }
+    fn name(&self) -> &'static str { "resolve" }
+}
+
+impl Executable for Chargeback {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.chargeback(self.transaction_id) }
+    fn name(&self) -> &'static str { "chargeback" }
+}
+
+impl Executable for ReverseChargeback {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.reverse_chargeback(self.transaction_id) }
+    fn name(&self) -> &'static str { "reverse chargeback" }
+}
+
+// Executing against the account is enough to open it; the command_handler warns when the account already existed
+impl Executable for Open {
+    fn execute(&self, _client: &mut ClientData) -> Result<(), AccountUpdateFailure> { Ok(()) }
+    fn name(&self) -> &'static str { "open" }
+    fn opens_account(&self) -> bool { true }
+}
+
+impl Executable for AdjustCredit {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> {
+        client.adjust_credit(self.wealth);
+        Ok(())
+    }
+    fn name(&self) -> &'static str { "adjustment credit" }
+    fn opens_account(&self) -> bool { true }
+}
+
+impl Executable for AdjustDebit {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.adjust_debit(self.wealth, self.allow_negative) }
+    fn name(&self) -> &'static str { "adjustment debit" }
+}
+
+// Only compares the balance, so it is checked even on a frozen account
+impl Executable for Assert {
+    fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> {
+        let actual = client.get_wealth();
+        if actual == self.expected {
+            Ok(())
+        }
+        else {
+            Err(AccountUpdateFailure::BalanceMismatch(actual))
+        }
+    }
+    fn name(&self) -> &'static str { "assert" }
+}
+
+#[cfg(test)]
+mod command_tests {
+    use rust_decimal_macros::dec;
+    use tokio_stream::StreamExt;
+
+    use super::{Command, CommandType, InvalidCommand};
+
+    async fn deserialize(content: &str) -> Vec<Command> {
+        let mut rdr = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .create_deserializer(content.as_bytes());
+        rdr.deserialize::<Command>().map(|record| record.unwrap()).collect().await
+    }
+
+    #[test]
+    fn test_strip_currency() {
+        assert_eq!("5.00", super::strip_currency("$5.00"));
+        assert_eq!("5.00", super::strip_currency("5.00 USD"));
+        assert_eq!("5.00", super::strip_currency(" € 5.00EUR "));
+        assert_eq!("-1.50", super::strip_currency("-$1.50"));
+        assert_eq!("5.00", super::strip_currency("5.00"));
+        assert_eq!("", super::strip_currency(""));
+
+        // ambiguous or unknown decorations are left to fail parsing
+        assert_eq!("5,00", super::strip_currency("$5,00"));
+        assert_eq!("5.00 dollars", super::strip_currency("5.00 dollars"));
+        assert_eq!("US5.00", super::strip_currency("US5.00"));
+    }
+
+    #[tokio::test]
+    async fn test_aliases() {
+        let commands = deserialize(concat!(
+            "type,client,tx,amount\n",
+            "withdraw,1,1,5.0\n",
+            "withdrawal,1,2,5.0\n",
+            "debit,1,3,5.0\n",
+            "credit,1,4,5.0\n",
+            "charge_back,1,4,\n",
+            "reverse,1,4,\n",
+            "reverse_chargeback,1,4,\n",
+            "adjustment_credit,1,5,1.0\n",
+            "adjustment-debit,1,6,1.0\n",
+            "open,2,7,\n",
+            "assert,2,8,0\n",
+        )).await;
+
+        assert_eq!(commands[0].get_type(), CommandType::Withdraw);
+        assert_eq!(commands[0].get_client_id(), 1);
+        assert_eq!(commands[0].get_transaction_id(), 1);
+        assert_eq!(commands[0].get_wealth().unwrap(), dec!(5.0));
+        assert_eq!(commands[1].get_type(), CommandType::Withdraw);
+        assert_eq!(commands[2].get_type(), CommandType::Withdraw);
+        assert_eq!(commands[3].get_type(), CommandType::Deposit);
+        assert_eq!(commands[4].get_type(), CommandType::Chargeback);
+        assert_eq!(commands[5].get_type(), CommandType::ReverseChargeback);
+        assert_eq!(commands[6].get_type(), CommandType::ReverseChargeback);
+        assert_eq!(commands[7].get_type(), CommandType::AdjustmentCredit);
+        assert_eq!(commands[8].get_type(), CommandType::AdjustmentDebit);
+        assert_eq!(commands[9].get_type(), CommandType::Open);
+        assert_eq!(commands[10].get_type(), CommandType::Assert);
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive() {
+        let commands = deserialize(concat!(
+            "type,client,tx,amount\n",
+            "Deposit,1,1,5.0\n",
+            "WITHDRAWAL,1,2,1.0\n",
+            "DiSpUtE,1,1,\n",
+            "RESOLVE,1,1,\n",
+            "Chargeback,1,1,\n",
+            "Withdraw,1,3,1.0\n",
+        )).await;
+
+        assert_eq!(commands[0].get_type(), CommandType::Deposit);
+        assert_eq!(commands[1].get_type(), CommandType::Withdraw);
+        assert_eq!(commands[2].get_type(), CommandType::Dispute);
+        assert_eq!(commands[3].get_type(), CommandType::Resolve);
+        assert_eq!(commands[4].get_type(), CommandType::Chargeback);
+        assert_eq!(commands[5].get_type(), CommandType::Withdraw);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(CommandType::Dispute), "dispute".parse());
+        assert_eq!(Ok(CommandType::Withdraw), "Debit".parse());
+        assert_eq!(Ok(CommandType::ReverseChargeback), "reverse-chargeback".parse());
+        assert!("refund".parse::<CommandType>().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Ok(()), Command::new(CommandType::Deposit, 1, 1, Some(dec!(1))).validate());
+        assert_eq!(Ok(()), Command::new(CommandType::Withdraw, 1, 1, Some(dec!(1))).validate());
+        assert_eq!(Err(InvalidCommand::MissingAmount), Command::new(CommandType::Deposit, 1, 1, None).validate());
+        assert_eq!(Err(InvalidCommand::MissingAmount), Command::new(CommandType::Withdraw, 1, 1, None).validate());
+        assert_eq!(Ok(()), Command::new(CommandType::Dispute, 1, 1, None).validate());
+        assert_eq!(Ok(()), Command::new(CommandType::Resolve, 1, 1, None).validate());
+        assert_eq!(Ok(()), Command::new(CommandType::Chargeback, 1, 1, None).validate());
+        assert_eq!(Err(InvalidCommand::MissingAmount), Command::new(CommandType::AdjustmentCredit, 1, 1, None).validate());
+        assert_eq!(Err(InvalidCommand::MissingAmount), Command::new(CommandType::AdjustmentDebit, 1, 1, None).validate());
+
+        assert_eq!(Ok(()), Command::new(CommandType::Deposit, 1, 1, Some(dec!(5.1234))).validate());
+        assert_eq!(Ok(()), Command::new(CommandType::Deposit, 1, 1, Some(dec!(5.123400))).validate());
+        assert_eq!(Err(InvalidCommand::ExcessPrecision), Command::new(CommandType::Deposit, 1, 1, Some(dec!(5.12345))).validate());
+        assert_eq!(Err(InvalidCommand::ExcessPrecision), Command::new(CommandType::Withdraw, 1, 1, Some(dec!(0.00001))).validate());
+        assert_eq!(Ok(()), Command::new(CommandType::Dispute, 1, 1, Some(dec!(5.12345))).validate());
+
+        assert_eq!(Some(dec!(5.1234)), *Command::new(CommandType::Deposit, 1, 1, Some(dec!(5.12344))).rounded().get_wealth());
+        assert_eq!(Ok(()), Command::new(CommandType::Deposit, 1, 1, Some(dec!(5.12345))).rounded().validate());
+    }
+
+    #[test]
+    fn test_in_dollars() {
+        assert_eq!(Some(dec!(5.00)), *Command::new(CommandType::Deposit, 1, 1, Some(dec!(500))).in_dollars().unwrap().get_wealth());
+        assert_eq!(Some(dec!(0.01)), *Command::new(CommandType::Withdraw, 1, 1, Some(dec!(1))).in_dollars().unwrap().get_wealth());
+        assert_eq!(None, *Command::new(CommandType::Dispute, 1, 1, None).in_dollars().unwrap().get_wealth());
+        assert_eq!(Some(InvalidCommand::InvalidCents), Command::new(CommandType::Deposit, 1, 1, Some(dec!(500.5))).in_dollars().err());
+        assert_eq!(Some(InvalidCommand::InvalidCents), Command::new(CommandType::Deposit, 1, 1, Some(dec!(-500))).in_dollars().err());
+    }
+}
@@ -1,182 +1,942 @@
 //! # command_handler module
 //! This module separates logic for executing commands from the queue
-//! Commands are handled with flow control; however, this could be a good place to use a chain of responsibility if the file gets too large.
+//! Commands are dispatched through the command::Executable trait, so adding a kind of command does not require changes here.
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::{Arc, Mutex};
 
+use rust_decimal::prelude::Decimal;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
-use crate::client_data::{self, AccountUpdateFailure, TransactionID, ClientID};
-use crate::command;
+use crate::checkpoint;
+use crate::client_data::{self, AccountUpdateFailure, FeePolicy, TransactionID, ClientID};
+use crate::client_store::ClientStore;
+use crate::clock::{Clock, SystemClock};
+use crate::command::{self, Executable};
 use crate::logger;
+use crate::mode::Mode;
+
+/// Options controlling how handle_commands executes commands
+#[derive(Default)]
+pub struct HandlerOptions {
+    /// deposits and withdrawals above this ammount are skipped as likely data errors; None enforces no bound
+    pub max_amount: Option<Decimal>,
+    /// reports commands naming a client other than the tx's owner, as found in the tx_index, specifically
+    pub strict_tx: bool,
+    /// a deposit to a frozen account, as after a chargeback, is logged as an error and halts processing in any mode, rather than being warned of and skipped
+    pub strict_frozen: bool,
+    /// processing halts once this many chargebacks succeed, as so many likely indicate a corrupt or malicious file; None enforces no limit
+    pub max_chargebacks: Option<u64>,
+    /// keeps a FundsLedger of the money moved by commands, so the books can be checked with verify_conservation
+    pub audit: bool,
+    /// creates a zero-balance account for every client a command references, even when the command fails, so every client appears in the output
+    pub include_empty: bool,
+    /// under Mode::Strict, processing halts at the first command which is rejected or skipped, and harmless oddities are raised from info to a warning
+    pub mode: Mode,
+    /// logs the count of commands received, and the time elapsed, every this many commands; None logs no progress
+    pub progress_interval: Option<u64>,
+    /// saves a checkpoint of the client data to this path every checkpoint_interval commands; None saves no checkpoints
+    pub checkpoint_path: Option<String>,
+    pub checkpoint_interval: u64,
+    /// the count of commands the client data already reflects, when resuming from a checkpoint, so later checkpoints count them as well
+    pub resumed_commands: u64,
+    /// whether accounts opened while handling commands accept deposits once frozen
+    pub frozen_policy: client_data::FrozenPolicy,
+    /// keeps an ActivityReport of each client's deposits, disputes, and chargebacks, so unusual clients can be flagged with log_anomalies
+    pub anomaly_report: bool,
+    /// keeps AmountStats of the ammounts of deposits and withdrawals received, so outliers such as a single enormous deposit can be spotted with log_summary
+    pub amount_stats: bool,
+    /// commands for clients outside this inclusive range, such as a reserved system client 0, are skipped; None accepts every client
+    pub client_range: Option<std::ops::RangeInclusive<ClientID>>,
+    /// treats a deposit resent with the tx and ammount of an earlier deposit as a retry, which succeeds without depositing again;
+    /// a resent tx with a different ammount is reported as a conflict
+    pub dedupe_deposits: bool,
+    /// lets adjustment debits leave an account's available funds negative, rather than failing for insufficient funds
+    pub allow_negative_adjustment: bool,
+    /// how deposits, withdrawals, adjustments, and asserts without an ammount are handled
+    pub amount_policy: AmountPolicy,
+    /// disputes beyond this many open at once for a client are rejected, as an anti-abuse rule; None enforces no limit
+    pub max_open_disputes: Option<u64>,
+    /// commands of these types are skipped entirely, as for a reconciliation pass applying only deposits and withdrawals
+    pub disabled: HashSet<command::CommandType>,
+    /// collects a HandlerAnomaly for each command which is rejected or skipped, so callers can inspect them as data; they are logged either way
+    pub collect_anomalies: bool,
+    /// clients whose balances were seeded, such as with read_seed, without the deposit history behind them;
+    /// a dispute, resolve, or chargeback of an unknown tx for such a client is warned of as likely predating the seed
+    pub seeded_clients: HashSet<ClientID>,
+    /// handle_commands writes a line per successful command to a csv at this path, with the client's balances before and after it, as an audit trail;
+    /// failed commands are only in the warning log.  None writes no audit log; process_all ignores it
+    pub audit_log_path: Option<String>,
+    /// stops handling once cancelled, between batches, leaving the commands still in the channel unexecuted; None handles until the channel closes
+    pub cancel: Option<CancellationToken>,
+    /// the clock progress is timed by; None uses the SystemClock
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+// The most commands handle_commands executes per lock of the client data
+const BATCH_SIZE: usize = 256;
+
+/// The header of the audit log written to HandlerOptions::audit_log_path
+pub const AUDIT_LOG_HEADER: &str = "tx,client,type,amount,available_before,available_after,held_before,held_after";
+
+/// Clients with more than this many chargebacks are flagged by ActivityReport::log_anomalies
+pub const ANOMALY_CHARGEBACKS: u64 = 1;
+
+/// How a command is handled when its type carries an ammount, but it has none, as from an empty amount cell
+/// 
+/// Disputes, resolves, and chargebacks refer to an earlier ammount, so an empty amount cell is legitimate for them under every policy.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum AmountPolicy {
+    /// the command is warned of and skipped
+    #[default]
+    RequireForMonetary,
+    /// the missing ammount is treated as zero, and the command executed; this hides data errors, so it is warned of
+    DefaultZero,
+    /// the command is logged as an error, and processing halts in any mode
+    Reject,
+}
+
+/// Reasons a command could not be executed
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ExecutionFailure {
+    /// the client is unknown, and the command does not open accounts
+    UnknownClient,
+    /// the client's account rejected the command
+    Account(AccountUpdateFailure),
+}
+
+/// A command which was rejected or skipped, with why
+#[derive(Clone, PartialEq, Debug)]
+pub struct HandlerAnomaly {
+    pub command_type: command::CommandType,
+    pub client_id: ClientID,
+    pub transaction_id: TransactionID,
+    pub reason: AnomalyReason,
+}
+
+/// Why a command was rejected or skipped
+#[derive(Clone, PartialEq, Debug)]
+pub enum AnomalyReason {
+    /// the client is outside the client_range
+    ClientOutOfRange,
+    /// the ammount exceeds the max_amount
+    ExceedsMaxAmount(Decimal),
+    /// under dedupe_deposits, the deposit reuses the tx of an earlier deposit of this ammount, but for a different ammount
+    ConflictingDeposit(Decimal),
+    /// under strict_tx, the tx belongs to this other client
+    MisdirectedTx(ClientID),
+    /// the deposit, withdrawal, or adjustment did not carry an ammount
+    MissingAmount,
+    /// the client already had an account to open
+    AccountExists,
+    /// the command was executed, but failed
+    Failed(ExecutionFailure),
+}
+
+impl fmt::Display for AnomalyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnomalyReason::ClientOutOfRange => f.write_str("the client is outside the valid range"),
+            AnomalyReason::ExceedsMaxAmount(max_amount) => write!(f, "the amount exceeds the maximum of {}", max_amount),
+            AnomalyReason::ConflictingDeposit(earlier) => write!(f, "the tx conflicts with an earlier deposit of {}", earlier),
+            AnomalyReason::MisdirectedTx(owner) => write!(f, "tx belongs to client {}", owner),
+            AnomalyReason::MissingAmount => f.write_str("the transaction did not contain the amount"),
+            AnomalyReason::AccountExists => f.write_str("the account already exists"),
+            AnomalyReason::Failed(ExecutionFailure::UnknownClient) => f.write_str("the transaction did not correspond to a known user"),
+            AnomalyReason::Failed(ExecutionFailure::Account(err)) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Reasons handle_commands stopped before handling every command
+#[derive(Debug)]
+pub enum HandleError {
+    /// processing halted early, as after max_chargebacks or a failure under Mode::Strict; the stats tally the commands handled before it
+    Halted(Box<ProcessingStats>),
+    /// the client data lock was poisoned, as another thread panicked while holding it
+    Poisoned,
+    /// the audit log could not be created or written
+    AuditLog(std::io::Error),
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandleError::Halted(stats) => write!(f, "processing halted early; {}", stats),
+            HandleError::Poisoned => f.write_str("the client data lock was poisoned"),
+            HandleError::AuditLog(err) => write!(f, "writing the audit log failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for HandleError {}
+
+/// Tallies of how commands fared, summarizing the data quality of the input
+#[derive(Default, PartialEq, Debug)]
+pub struct ProcessingStats {
+    /// commands which were executed successfully
+    pub executed: u64,
+    /// commands which were not executed for any reason
+    pub failed: u64,
+    /// commands which referenced a client without an account
+    pub unknown_client: u64,
+    /// disputes, resolves, and chargebacks which referenced a tx the client does not have
+    pub tx_not_found: u64,
+    /// chargebacks which were executed successfully
+    pub chargebacks: u64,
+    /// deposits which were retries of an earlier deposit, under dedupe_deposits, and so were not executed again
+    pub retried: u64,
+    /// commands which were skipped because their type is disabled
+    pub disabled: u64,
+    /// whether processing stopped early because max_chargebacks was reached, or a command failed under Mode::Strict
+    pub halted: bool,
+    /// whether handling stopped early because the cancel token was cancelled
+    pub cancelled: bool,
+    /// commands received by the channel, but left unhandled because handling halted or was cancelled
+    pub unhandled: u64,
+    /// the money moved by commands, when an audit was requested
+    pub ledger: Option<FundsLedger>,
+    /// each client's deposits, disputes, and chargebacks, when an anomaly report was requested
+    pub activity: Option<ActivityReport>,
+    /// the count, sum, min, and max of deposit and withdrawal ammounts, when amount stats were requested
+    pub amounts: Option<AmountStats>,
+    /// each command which was rejected or skipped, in order, when collecting anomalies was requested
+    pub anomalies: Option<Vec<HandlerAnomaly>>,
+    /// which client owns each tx
+    pub tx_index: TxIndex,
+    /// commands received of each type, however they fared
+    pub by_type: HashMap<command::CommandType, u64>,
+}
+
+/// Maps each tx to the client which owns it, so a tx can be looked up without searching every client's history
+/// 
+/// Only deposits and withdrawals which executed successfully are indexed; a failed deposit does not claim its tx.
+/// A tx reused by another client, as some feeds do, is indexed to the latest client to use it; disputes are unaffected,
+/// as they look up the tx in the history of the client they name (see client_data).
+#[derive(Default, PartialEq, Debug)]
+pub struct TxIndex {
+    owners: HashMap<TransactionID, ClientID>,
+}
+
+impl TxIndex {
+    /// The client which owns the tx, if a deposit or withdrawal with the tx succeeded
+    pub fn owner(&self, transaction_id: TransactionID) -> Option<ClientID> {
+        self.owners.get(&transaction_id).copied()
+    }
+
+    /// The count of txs indexed
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+
+    // Records the owner of a command's tx, if the command is a deposit or withdrawal which executed successfully
+    fn record(&mut self, cmd: &command::Command) {
+        if matches!(cmd.get_type(), command::CommandType::Deposit | command::CommandType::Withdraw) {
+            self.owners.insert(cmd.get_transaction_id(), cmd.get_client_id());
+        }
+    }
+}
+
+/// Tallies each client's successful deposits, disputes, and chargebacks, for spotting clients who dispute unusually often
+#[derive(Default, PartialEq, Debug)]
+pub struct ActivityReport {
+    pub clients: HashMap<ClientID, ClientActivity>,
+}
+
+/// The successful deposits, disputes, and chargebacks of one client
+#[derive(Default, Copy, Clone, PartialEq, Debug)]
+pub struct ClientActivity {
+    pub deposits: u64,
+    pub disputes: u64,
+    pub chargebacks: u64,
+}
+
+/// Summarizes the ammounts of the deposits and withdrawals received, however they fared, for profiling the input
+/// 
+/// Each ammount is folded into running totals as it arrives, so no ammounts are kept.
+#[derive(Default, PartialEq, Debug)]
+pub struct AmountStats {
+    pub deposits: AmountSummary,
+    pub withdrawals: AmountSummary,
+}
+
+/// The count, sum, min, and max of a stream of ammounts
+#[derive(Default, Copy, Clone, PartialEq, Debug)]
+pub struct AmountSummary {
+    pub count: u64,
+    pub sum: Decimal,
+    /// None until an ammount is recorded
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+}
+
+/// Tallies the money moved into and out of accounts, independently of the accounts themselves
+/// 
+/// The sum of every client's total should equal opening + deposited - withdrawn - fees - charged_back + disputed_withdrawals + adjusted.
+/// A disputed withdrawal is a claim on funds which already left the account, so its hold adds to the client's total until it is resolved;
+/// charging it back moves the hold into available funds, leaving the total as it was.
+#[derive(Default, PartialEq, Debug)]
+pub struct FundsLedger {
+    /// the sum of the client totals before any command was handled, as when they were seeded or resumed from a checkpoint
+    pub opening: Decimal,
+    /// the sum of successful deposits
+    pub deposited: Decimal,
+    /// the sum of successful withdrawals
+    pub withdrawn: Decimal,
+    /// the sum of the fees charged on successful withdrawals, which are kept even if the withdrawal is disputed or charged back
+    pub fees: Decimal,
+    /// the sum of deposits which were charged back
+    pub charged_back: Decimal,
+    /// the sum of withdrawals which are disputed, or were charged back
+    pub disputed_withdrawals: Decimal,
+    /// the sum of adjustment credits, less adjustment debits
+    pub adjusted: Decimal,
+    // the kind and ammount of each deposit and withdrawal, so later commands know what they refer to
+    transactions: HashMap<(ClientID, TransactionID), (command::CommandType, Decimal)>,
+}
+
+impl FundsLedger {
+    // Records the money moved by a command which executed successfully, with the fee policy of its client
+    fn record(&mut self, cmd: &command::Command, fee_policy: FeePolicy) {
+        let key = (cmd.get_client_id(), cmd.get_transaction_id());
+        match (cmd.get_type(), cmd.get_wealth()) {
+            (command::CommandType::Deposit, Some(wealth)) => {
+                self.deposited += *wealth;
+                self.transactions.insert(key, (command::CommandType::Deposit, *wealth));
+            },
+            (command::CommandType::Withdraw, Some(wealth)) => {
+                self.withdrawn += *wealth;
+                self.fees += fee_policy.fee(*wealth);
+                self.transactions.insert(key, (command::CommandType::Withdraw, *wealth));
+            },
+            (command::CommandType::AdjustmentCredit, Some(wealth)) => self.adjusted += *wealth,
+            (command::CommandType::AdjustmentDebit, Some(wealth)) => self.adjusted -= *wealth,
+            (command_type, _) => match (command_type, self.transactions.get(&key)) {
+                (command::CommandType::Dispute, Some((command::CommandType::Withdraw, wealth))) => self.disputed_withdrawals += *wealth,
+                (command::CommandType::Resolve, Some((command::CommandType::Withdraw, wealth))) => self.disputed_withdrawals -= *wealth,
+                (command::CommandType::Chargeback, Some((command::CommandType::Deposit, wealth))) => self.charged_back += *wealth,
+                (command::CommandType::ReverseChargeback, Some((command::CommandType::Deposit, wealth))) => self.charged_back -= *wealth,
+                _ => (),
+            },
+        }
+    }
+
+    /// Checks that the client totals reconcile against the money moved by commands, warning if they do not
+    /// 
+    /// # Arguments
+    /// 
+    /// clients             data for all client accounts, after the commands were handled
+    /// 
+    /// # Return Value
+    /// 
+    /// true                the books balance
+    /// 
+    pub fn verify_conservation<S: ClientStore + ?Sized>(&self, clients: &S) -> bool {
+        let actual = sum_totals(clients);
+        let expected = self.opening + self.deposited - self.withdrawn - self.fees - self.charged_back + self.disputed_withdrawals + self.adjusted;
+        if actual != expected {
+            logger::warning( &format!("The books do not balance: client totals sum to {}, but opening balances ({}) and deposits ({}) less withdrawals ({}), fees ({}) and chargebacks ({}), plus disputed withdrawals ({}) and net adjustments ({}), come to {}.",
+                actual,
+                self.opening,
+                self.deposited,
+                self.withdrawn,
+                self.fees,
+                self.charged_back,
+                self.disputed_withdrawals,
+                self.adjusted,
+                expected) );
+        }
+        actual == expected
+    }
+}
+
+impl ActivityReport {
+    // Records a command which executed successfully
+    fn record(&mut self, cmd: &command::Command) {
+        let activity = self.clients.entry(cmd.get_client_id()).or_default();
+        match cmd.get_type() {
+            command::CommandType::Deposit => activity.deposits += 1,
+            command::CommandType::Dispute => activity.disputes += 1,
+            command::CommandType::Chargeback => activity.chargebacks += 1,
+            _ => (),
+        }
+    }
+
+    /// The clients with more than ANOMALY_CHARGEBACKS chargebacks, or who disputed more than half as many times as they deposited, ordered by client
+    pub fn flagged(&self) -> Vec<ClientID> {
+        let mut flagged: Vec<ClientID> = self.clients.iter()
+            .filter(|(_, activity)| activity.chargebacks > ANOMALY_CHARGEBACKS || activity.disputes * 2 > activity.deposits)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        flagged.sort();
+        flagged
+    }
+
+    /// Warns about each flagged client, with their counts
+    pub fn log_anomalies(&self) {
+        for client_id in self.flagged() {
+            let activity = self.clients[&client_id];
+            logger::warning( &format!("Client {} may be anomalous: {} deposits, {} disputes, {} chargebacks.", client_id, activity.deposits, activity.disputes, activity.chargebacks) );
+        }
+    }
+}
+
+impl AmountStats {
+    // Records the ammount of a command received, if it is a deposit or withdrawal with one
+    fn record(&mut self, cmd: &command::Command) {
+        match (cmd.get_type(), cmd.get_wealth()) {
+            (command::CommandType::Deposit, Some(wealth)) => self.deposits.record(*wealth),
+            (command::CommandType::Withdraw, Some(wealth)) => self.withdrawals.record(*wealth),
+            _ => (),
+        }
+    }
+
+    /// Logs the summary of deposits and of withdrawals, as info
+    pub fn log_summary(&self) {
+        logger::info( &format!("Deposits: {}.", self.deposits) );
+        logger::info( &format!("Withdrawals: {}.", self.withdrawals) );
+    }
+}
+
+impl AmountSummary {
+    fn record(&mut self, ammount: Decimal) {
+        self.count += 1;
+        self.sum += ammount;
+        self.min = Some(self.min.map_or(ammount, |min| min.min(ammount)));
+        self.max = Some(self.max.map_or(ammount, |max| max.max(ammount)));
+    }
+
+    /// The mean ammount, or None if none were recorded
+    pub fn mean(&self) -> Option<Decimal> {
+        (self.count > 0).then(|| self.sum / Decimal::from(self.count))
+    }
+}
+
+impl fmt::Display for AmountSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.min, self.max, self.mean()) {
+            (Some(min), Some(max), Some(mean)) => write!(f, "{} totalling {}, min {}, max {}, mean {}", self.count, self.sum, min, max, mean.round_dp(4)),
+            _ => write!(f, "none"),
+        }
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} commands executed, {} failed ({} for unknown clients, {} for unknown txs)",
+            self.executed,
+            self.failed,
+            self.unknown_client,
+            self.tx_not_found)
+    }
+}
 
 /// Handles command objects
 /// 
+/// Commands already waiting in the channel are received as a batch, up to BATCH_SIZE, and executed in order under a single lock of the client data.
+/// 
+/// Once the cancel token is cancelled, the batch in progress is finished, so the client data is left consistent, and handling stops.
+/// A checkpoint of the client data is saved then, if checkpoint_path is set, so the run can be resumed.
+/// 
+/// With an audit_log_path, the audit lines of each batch are written once its lock of the client data is released.
+/// The amount column is the command's own ammount, so it is empty for disputes, resolves, and chargebacks.
+/// 
 /// # Arguments
 /// 
 /// client_data         data for all client accounts
 /// rx                  a Reciever to gather commands
+/// options             options controlling how commands are executed
+/// 
+/// # Return Value
+/// 
+/// Err(HandleError::Halted(_))     processing halted early, as after max_chargebacks or a failure under Mode::Strict
+/// Err(HandleError::Poisoned)      the client data could not be locked
+/// Err(HandleError::AuditLog(_))   the audit log could not be created or written
+/// Ok(ProcessingStats)             tallies of how the commands fared, including whether handling was cancelled
 /// 
-pub async fn handle_commands ( 
-    client_data: Arc::<Mutex::<HashMap::<client_data::ClientID, Box<client_data::ClientData>>>>,
-    mut rx: mpsc::Receiver<command::Command>
-) -> () {
+pub async fn handle_commands<S: ClientStore> ( 
+    client_data: Arc::<Mutex::<S>>,
+    mut rx: mpsc::Receiver<command::Command>,
+    options: HandlerOptions
+) -> Result<ProcessingStats, HandleError> {
 
-    while let Some(cmd) = rx.recv().await {
+    let mut audit_log = match &options.audit_log_path {
+        Some(path) => Some(AuditLog::create(path).await?),
+        None => None,
+    };
+    let mut handler = Handler::new(options);
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
 
-        // Identify the type of command
-        match cmd.get_type() {
+    // client data loaded before handling, as from a seed or checkpoint, opens the books with its totals
+    if let Some(ledger) = handler.stats.ledger.as_mut() {
+        ledger.opening = sum_totals(&*client_data.lock().map_err(|_| HandleError::Poisoned)?);
+    }
 
-            // For deposits...
-            command::CommandType::Deposit => {
+    loop {
+        // a cancellation already pending wins over commands waiting in the channel
+        let received = tokio::select! {
+            biased;
+            _ = cancelled(&handler.options.cancel) => {
+                handler.stats.unhandled = rx.len() as u64;
+                let c_d = client_data.lock().map_err(|_| HandleError::Poisoned)?;
+                handler.cancel(&*c_d);
+                break;
+            },
+            received = rx.recv_many(&mut batch, BATCH_SIZE) => received,
+        };
+        if received == 0 {
+            break;
+        }
 
-                let mut c_d = client_data.lock().unwrap();
+        // the lock is released before the audit lines are written
+        let halted = {
+            let mut c_d = client_data.lock().map_err(|_| HandleError::Poisoned)?;
+            let mut commands = batch.drain(..);
+            let halted = !commands.all(|cmd| handler.handle(&mut *c_d, cmd));
+            if halted {
+                handler.stats.unhandled = (commands.len() + rx.len()) as u64;
+            }
+            halted
+        };
+        if let Some(audit_log) = audit_log.as_mut() {
+            audit_log.write(handler.audit_lines.drain(..)).await?;
+        }
+        if halted {
+            // the audit trail matters most when processing halts, so it is flushed rather than lost with the writer's buffer
+            if let Some(audit_log) = audit_log.as_mut() {
+                audit_log.flush().await?;
+            }
+            return Err(HandleError::Halted(Box::new(handler.stats)));
+        }
+    }
 
-                // find the client
-                if let Some(client) = c_d.get_mut(&cmd.get_client_id()) {
+    if let Some(audit_log) = audit_log.as_mut() {
+        audit_log.flush().await?;
+    }
+    Ok(handler.stats)
+}
 
-                    // If the client is known...
-                    deposit_for_client(client, &cmd);
-                }
-                else {
-                    
-                    // If the client is unknown, create it, update it, then add it to our list of clients...
-                    let mut client = Box::new(client_data::ClientData::new());
+// The audit trail written to HandlerOptions::audit_log_path
+struct AuditLog {
+    path: String,
+    writer: tokio::io::BufWriter<tokio::fs::File>,
+}
 
-                    deposit_for_client(&mut client, &cmd);
+impl AuditLog {
+    // Creates the log, replacing any file at the path, and writes its header
+    async fn create(path: &str) -> Result<AuditLog, HandleError> {
+        let file = tokio::fs::File::create(path).await.map_err(|err| {
+            logger::error( &format!("Creating the audit log {} failed: {}", path, err) );
+            HandleError::AuditLog(err)
+        })?;
+        let mut audit_log = AuditLog { path: path.to_owned(), writer: tokio::io::BufWriter::new(file) };
+        audit_log.write(std::iter::once(AUDIT_LOG_HEADER.to_owned())).await?;
+        Ok(audit_log)
+    }
 
-                    c_d.insert(cmd.get_client_id(), client);
-                }
+    async fn write(&mut self, lines: impl Iterator<Item = String>) -> Result<(), HandleError> {
+        for line in lines {
+            let written = tokio::io::AsyncWriteExt::write_all(&mut self.writer, format!("{}\n", line).as_bytes()).await;
+            written.map_err(|err| self.failed(err))?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), HandleError> {
+        let flushed = tokio::io::AsyncWriteExt::flush(&mut self.writer).await;
+        flushed.map_err(|err| self.failed(err))
+    }
+
+    fn failed(&self, err: std::io::Error) -> HandleError {
+        logger::error( &format!("Writing the audit log {} failed: {}", self.path, err) );
+        HandleError::AuditLog(err)
+    }
+}
+
+/// Handles a stream of commands against client data it owns, returning the client data
+/// 
+/// A companion to handle_commands for embedding and tests, where the commands have a single consumer and no locking is needed.
+/// 
+/// # Arguments
+/// 
+/// commands            the commands to execute, in order
+/// options             options controlling how commands are executed
+/// 
+/// # Return Value
+/// 
+/// HashMap             data for all client accounts
+/// 
+pub async fn process_all (
+    commands: impl tokio_stream::Stream<Item = command::Command>,
+    options: HandlerOptions
+) -> HashMap<client_data::ClientID, client_data::ClientData> {
+
+    tokio::pin!(commands);
+    let mut handler = Handler::new(options);
+    // there is no audit log to write to, so its lines are not kept
+    handler.auditing = false;
+    let mut clients: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+
+    while let Some(cmd) = tokio_stream::StreamExt::next(&mut commands).await {
+        if !handler.handle(&mut clients, cmd) {
+            break;
+        }
+    }
+
+    clients.into_iter().map(|(client_id, client)| (client_id, *client)).collect()
+}
+
+// The state of a run of commands, shared by handle_commands and process_all
+struct Handler {
+    options: HandlerOptions,
+    stats: ProcessingStats,
+    // the disabled types which have been skipped, so each is only logged the first time
+    skipped_types: HashSet<command::CommandType>,
+    clock: Arc<dyn Clock>,
+    started: std::time::Instant,
+    received: u64,
+    // whether audit lines are kept, and those not yet written to the audit log
+    auditing: bool,
+    audit_lines: Vec<String>,
+}
+
+impl Handler {
+    fn new(options: HandlerOptions) -> Handler {
+        let clock = options.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+        Handler {
+            stats: ProcessingStats {
+                ledger: options.audit.then(FundsLedger::default),
+                activity: options.anomaly_report.then(ActivityReport::default),
+                amounts: options.amount_stats.then(AmountStats::default),
+                anomalies: options.collect_anomalies.then(Vec::new),
+                ..ProcessingStats::default()
             },
-            // For withdrawals...
-            command::CommandType::Withdraw => {
+            auditing: options.audit_log_path.is_some(),
+            audit_lines: Vec::new(),
+            options,
+            skipped_types: HashSet::new(),
+            started: clock.now(),
+            clock,
+            received: 0,
+        }
+    }
+
+    // Records that handling was cancelled, saving a checkpoint of the commands handled so far, if requested
+    fn cancel<S: ClientStore + ?Sized>(&mut self, clients: &S) {
+        logger::info( &format!("Handling cancelled after {} commands, leaving {} in the channel.", self.received, self.stats.unhandled) );
+        self.stats.cancelled = true;
+        if let Some(checkpoint_path) = &self.options.checkpoint_path {
+            if let Err(err) = checkpoint::save(checkpoint_path, self.options.resumed_commands + self.received, clients) {
+                logger::error( &format!("Saving a checkpoint to {} failed: {}", checkpoint_path, err) );
+            }
+        }
+    }
 
-                let mut c_d = client_data.lock().unwrap();
+    // Handles one command, returning false when processing should halt
+    fn handle<S: ClientStore + ?Sized>(&mut self, clients: &mut S, cmd: command::Command) -> bool {
+        let options = &self.options;
+        let stats = &mut self.stats;
 
-                // find the client
-                if let Some(client) = c_d.get_mut(&cmd.get_client_id()) {
+        self.received += 1;
+        *stats.by_type.entry(cmd.get_type()).or_default() += 1;
+        if let Some(amounts) = stats.amounts.as_mut() {
+            amounts.record(&cmd);
+        }
+        if let Some(interval) = options.progress_interval {
+            if self.received.is_multiple_of(interval) {
+                logger::info( &format!("Processed {} commands in {:.2}s.", self.received, (self.clock.now() - self.started).as_secs_f64()) );
+            }
+        }
 
-                    // If the client is known...
-                    withdraw_for_client(client, &cmd);
+        // the checkpoint is saved before this command, as it reflects only the commands before it
+        if let Some(checkpoint_path) = &options.checkpoint_path {
+            if self.received > 1 && (self.received - 1).is_multiple_of(options.checkpoint_interval) {
+                if let Err(err) = checkpoint::save(checkpoint_path, options.resumed_commands + self.received - 1, clients) {
+                    logger::error( &format!("Saving a checkpoint to {} failed: {}", checkpoint_path, err) );
                 }
-                else {
+            }
+        }
 
-                    // If the client is unknown, create it, update it, then add it to our list of clients...
-                    let mut client = Box::new(client_data::ClientData::new());
+        // skip disabled types, as though the commands were not in the input
+        if options.disabled.contains(&cmd.get_type()) {
+            if self.skipped_types.insert(cmd.get_type()) {
+                logger::info( &format!("Skipping {} commands, as they are disabled; the first is TX:{} for user:{}.", type_name(cmd.get_type()), cmd.get_transaction_id(), cmd.get_client_id()) );
+            }
+            stats.disabled += 1;
+            return true;
+        }
 
-                    withdraw_for_client(&mut client, &cmd);
+        // skip clients which are reserved, or otherwise not valid
+        if let Some(client_range) = &options.client_range {
+            if !client_range.contains(&cmd.get_client_id()) {
+                let problem = format!("the client is outside the valid range of {} to {}", client_range.start(), client_range.end());
+                report(stats, options, type_name(cmd.get_type()), &problem, &cmd, AnomalyReason::ClientOutOfRange);
+                stats.failed += 1;
+                return !options.mode.is_strict() || halt(stats, &cmd);
+            }
+        }
 
-                   c_d.insert(cmd.get_client_id(), client);
+        // apply the amount policy to commands missing the ammount their type carries
+        let cmd = match (cmd.get_type().carries_ammount(), cmd.get_wealth(), options.amount_policy) {
+            (true, None, AmountPolicy::DefaultZero) => {
+                logger::warning( &format!("TX:{} to {} for user:{} does not have an amount, so it is treated as zero.", cmd.get_transaction_id(), type_name(cmd.get_type()), cmd.get_client_id()) );
+                cmd.with_wealth(Decimal::ZERO)
+            },
+            (true, None, amount_policy) => {
+                let reason = AnomalyReason::MissingAmount;
+                report(stats, options, type_name(cmd.get_type()), &reason.to_string(), &cmd, reason);
+                stats.failed += 1;
+                if amount_policy == AmountPolicy::Reject {
+                    logger::error( &format!("Processing halted at TX:{} for user:{} because it does not have an amount.", cmd.get_transaction_id(), cmd.get_client_id()) );
+                    stats.halted = true;
+                    return false;
+                }
+                if options.include_empty {
+                    open_empty(clients, cmd.get_client_id(), options.frozen_policy);
                 }
+                return !options.mode.is_strict() || halt(stats, &cmd);
             },
-            // For disputes...
-            command::CommandType::Dispute => {
-
-                // find the client
-                match client_data.lock().unwrap().get_mut(&cmd.get_client_id()) {
-
-                    // If the client is known...
-                    Some(client) => {
-
-                        // handle the dispute.
-                        match client.as_mut().dispute(cmd.get_transaction_id()) {
-
-                            // if there was an issue with the dispute, handle it
-                            Err(err) => {
-                                match err {
-                                    client_data::AccountUpdateFailure::Frozen => {
-                                        logger::warning( &msg_build("dispute", "the corresponding user account is frozen", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    client_data::AccountUpdateFailure::RedundantDispute => {
-                                        logger::warning( &msg_build("dispute", "the dispute was redundant", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    client_data::AccountUpdateFailure::TXNotFound => {
-                                        logger::warning( &msg_build("dispute", "the transaction did not correspond to a known deposit for that user", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    _ => (),
-                                };
-                            },
-                            Ok(()) => (),
-                        };
-                    },
+            _ => cmd,
+        };
+
+        // skip ammounts which are too large to be believable
+        if let (Some(max_amount), Some(wealth)) = (options.max_amount, cmd.get_wealth()) {
+            if *wealth > max_amount && matches!(cmd.get_type(), command::CommandType::Deposit | command::CommandType::Withdraw) {
+                let problem = format!("the amount {} exceeds the maximum of {}", wealth, max_amount);
+                report(stats, options, type_name(cmd.get_type()), &problem, &cmd, AnomalyReason::ExceedsMaxAmount(max_amount));
+                stats.failed += 1;
+                if options.include_empty {
+                    open_empty(clients, cmd.get_client_id(), options.frozen_policy);
+                }
+                return !options.mode.is_strict() || halt(stats, &cmd);
+            }
+        }
+
+        // a deposit resent by an idempotent feed is acknowledged, rather than deposited again
+        if let (true, command::CommandType::Deposit, Some(wealth)) = (options.dedupe_deposits, cmd.get_type(), cmd.get_wealth()) {
+            if let Some(earlier) = clients.get(cmd.get_client_id()).and_then(|client| client.deposit_ammount(cmd.get_transaction_id())) {
+                if earlier == *wealth {
+                    logger::info( &format!("TX:{} to deposit for user:{} repeats an earlier deposit, so it is treated as a retry.", cmd.get_transaction_id(), cmd.get_client_id()) );
+                    stats.retried += 1;
+                    return true;
+                }
+                let reason = AnomalyReason::ConflictingDeposit(earlier);
+                report(stats, options, "deposit", &reason.to_string(), &cmd, reason);
+                stats.failed += 1;
+                return !options.mode.is_strict() || halt(stats, &cmd);
+            }
+        }
+
+        // opening an account which already exists is likely a repeated onboarding record
+        if cmd.get_type() == command::CommandType::Open && clients.get(cmd.get_client_id()).is_some() {
+            let reason = AnomalyReason::AccountExists;
+            report(stats, options, "open", &reason.to_string(), &cmd, reason);
+            stats.failed += 1;
+            return !options.mode.is_strict() || halt(stats, &cmd);
+        }
+
+        // disputes, resolves, and chargebacks refer to the ammount of their tx, so an ammount of their own is ignored
+        if let (Some(wealth), false) = (cmd.get_wealth(), cmd.get_type().carries_ammount()) {
+            let msg = format!("TX:{} to {} for user:{} carries an amount of {}, which is ignored; the amount of the disputed transaction is used.", cmd.get_transaction_id(), type_name(cmd.get_type()), cmd.get_client_id(), wealth);
+            if options.mode.is_strict() {
+                logger::warning( &msg );
+            }
+            else {
+                logger::info( &msg );
+            }
+        }
 
-                    // If the client is unknown...
-                    None => {
-                        logger::warning(&msg_build("dispute", "the transaction did not correspond to a known user", &cmd.get_transaction_id(), &cmd.get_client_id()));
+        match cmd.to_executable(options.allow_negative_adjustment, options.max_open_disputes) {
+            Some(executable) => {
+                // an unknown client has no balances before the command opens its account
+                let before = self.auditing.then(|| clients.get(cmd.get_client_id()).map(|client| client.position()));
+                let result = execute(clients, cmd.get_client_id(), executable.as_ref(), options.frozen_policy);
+                stats.record(&result);
+                if options.include_empty && result.is_err() {
+                    open_empty(clients, cmd.get_client_id(), options.frozen_policy);
+                }
+
+                match &result {
+                    Ok(()) => {
+                        if let Some(ledger) = stats.ledger.as_mut() {
+                            // only a withdrawal is charged a fee, so only it needs its client's fee policy
+                            let fee_policy = match cmd.get_type() {
+                                command::CommandType::Withdraw => clients.get(cmd.get_client_id()).map(|client| client.get_fee_policy()).unwrap_or_default(),
+                                _ => FeePolicy::default(),
+                            };
+                            ledger.record(&cmd, fee_policy);
+                        }
+                        if let Some(activity) = stats.activity.as_mut() {
+                            activity.record(&cmd);
+                        }
+                        stats.tx_index.record(&cmd);
+                        // the command was applied, so it is audited even if it halts processing
+                        if let Some(before) = before {
+                            if let Some(client) = clients.get(cmd.get_client_id()) {
+                                self.audit_lines.push(audit_line(&cmd, before, client.position()));
+                            }
+                        }
+                        if cmd.get_type() == command::CommandType::Chargeback {
+                            stats.chargebacks += 1;
+                            if options.max_chargebacks.is_some_and(|max_chargebacks| stats.chargebacks >= max_chargebacks) {
+                                logger::error( &format!("Processing halted after {} chargebacks; the file may be corrupt or malicious.", stats.chargebacks) );
+                                stats.halted = true;
+                                return false;
+                            }
+                        }
                     },
-                };
-            },
-            // For Resolves...
-            command::CommandType::Resolve => {
-                
-                // find the client
-                match client_data.lock().unwrap().get_mut(&cmd.get_client_id()) {
-
-                    // if the client is known...
-                    Some(client) => {
-                        match client.as_mut().resolve( cmd.get_transaction_id() ) {
-                            Err(err) => {
-                                match err {
-                                    client_data::AccountUpdateFailure::Frozen => {
-                                        logger::warning( &msg_build("resolve", "the corresponding user account is frozen", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    client_data::AccountUpdateFailure::TXUndisputed => {
-                                        logger::warning( &msg_build("resolve", "the transaction is not under dispute", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    client_data::AccountUpdateFailure::TXNotFound => {
-                                        logger::warning( &msg_build("resolve", "the transaction did not correspond to a known deposit for that user", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    _ => (),
-                                }
-                            },
-                            Ok(()) => (),
+                    Err(err) => {
+                        let owner = if options.strict_tx && *err == ExecutionFailure::Account(AccountUpdateFailure::TXNotFound) {
+                            misdirected_owner(&stats.tx_index, &cmd)
+                        }
+                        else {
+                            None
                         };
-                    },
-                    // if the client is unknown...
-                    None => {
-                        logger::warning(&msg_build("resolve", "the transaction did not correspond to a known user", &cmd.get_transaction_id(), &cmd.get_client_id()));
-                    },
-                };
-
-            },
-            // For Chargebacks...
-            command::CommandType::Chargeback => {
-                
-                // find the client
-                match client_data.lock().unwrap().get_mut(&cmd.get_client_id()) {
-
-                    // if the client is known...
-                    Some(client) => {
-                        match client.as_mut().chargeback( cmd.get_transaction_id() ) {
-                            Err(err) => {
-                                match err {
-                                    client_data::AccountUpdateFailure::Frozen => {
-                                        logger::warning( &msg_build("chargeback", "the corresponding user account is frozen", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    client_data::AccountUpdateFailure::TXUndisputed => {
-                                        logger::warning( &msg_build("chargeback", "the transaction is not under dispute", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    client_data::AccountUpdateFailure::TXNotFound => {
-                                        logger::warning( &msg_build("chargeback", "the transaction did not correspond to a known deposit for that user", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                                    },
-                                    _ => (),
-                                }
-                            },
-                            Ok(()) => (),
+                        let reason = match owner {
+                            Some(owner) => AnomalyReason::MisdirectedTx(owner),
+                            None => AnomalyReason::Failed(*err),
                         };
-                    },
-                    // if the client is unknown...
-                    None => {
-                        logger::warning(&msg_build("chargeback", "the transaction did not correspond to a known user", &cmd.get_transaction_id(), &cmd.get_client_id()));
+                        let problem = match owner {
+                            Some(owner) => format!("tx belongs to client {}, not {}", owner, cmd.get_client_id()),
+                            None if *err == ExecutionFailure::Account(AccountUpdateFailure::TXNotFound) && options.seeded_clients.contains(&cmd.get_client_id()) =>
+                                format!("{}; it likely predates the seeded balances, which have no deposit history", reason),
+                            None => reason.to_string(),
+                        };
+                        let frozen_deposit = options.strict_frozen && is_frozen_deposit(&cmd, &reason);
+                        report(stats, options, executable.name(), &problem, &cmd, reason);
+                        // under strict_frozen, a deposit to a frozen account is a compliance breach, so it halts processing in any mode
+                        if frozen_deposit {
+                            logger::error( &format!("Processing halted at TX:{} for user:{} because it deposits to a frozen account.", cmd.get_transaction_id(), cmd.get_client_id()) );
+                            stats.halted = true;
+                            return false;
+                        }
                     },
                 };
-
+                if result.is_err() && options.mode.is_strict() {
+                    return halt(stats, &cmd);
+                }
             },
+            // commands without the ammount their type carries were handled by the amount policy above
+            None => unreachable!("TX:{} has no amount after the amount policy was applied", cmd.get_transaction_id()),
         };
 
+        true
+    }
+}
+
+impl ProcessingStats {
+    /// The count of commands handled, however they fared
+    pub fn handled(&self) -> u64 {
+        self.executed + self.failed + self.retried + self.disabled
+    }
+
+    fn record(&mut self, result: &Result<(), ExecutionFailure>) {
+        match result {
+            Ok(()) => self.executed += 1,
+            Err(err) => {
+                self.failed += 1;
+                match err {
+                    ExecutionFailure::UnknownClient => self.unknown_client += 1,
+                    ExecutionFailure::Account(AccountUpdateFailure::TXNotFound) => self.tx_not_found += 1,
+                    ExecutionFailure::Account(_) => (),
+                }
+            },
+        }
+    }
+}
+
+/// The count of commands sent on the channel which its receiver has not yet received
+/// 
+/// A diagnostic, such as to log how far the handler trails the parser; a clone of the parser's sender observes the channel without affecting it,
+/// though the handler does not finish until every sender, clones included, is dropped.
+pub fn channel_depth<T>(tx: &mpsc::Sender<T>) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+/// Waits for the handler to finish, cancelling it if interrupted first, as by a Ctrl-C
+/// 
+/// Used to cut a run short while keeping the work done so far.
+/// A cancelled handler finishes the batch in progress, so the client data it leaves is consistent, and can still be written.
+/// 
+/// # Arguments
+/// 
+/// handler             the task running handle_commands with the cancel token
+/// cancel              the handler's cancel token
+/// interrupt           completes on an interrupt, such as tokio::signal::ctrl_c()
+/// 
+/// # Return Value
+/// 
+/// (Err(JoinError), _)     the handler panicked
+/// (Ok(_), interrupted)    the handler's result, and whether the interrupt cancelled it
+/// 
+pub async fn drain_handler<T, F: std::future::Future> (
+    handler: &mut tokio::task::JoinHandle<T>,
+    cancel: &CancellationToken,
+    interrupt: F
+) -> (Result<T, tokio::task::JoinError>, bool) {
+    tokio::select! {
+        handled = &mut *handler => (handled, false),
+        _ = interrupt => {
+            logger::warning("Interrupted; handling stops after the current batch, leaving the commands still in the channel unhandled.");
+            cancel.cancel();
+            (handler.await, true)
+        },
+    }
+}
+
+// Completes once the token is cancelled; without a token, it never completes
+pub(crate) async fn cancelled(cancel: &Option<CancellationToken>) {
+    match cancel {
+        Some(cancel) => cancel.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Executes a command against a client's account
+/// 
+/// Unknown clients are created only for commands which open accounts, and only when the command succeeds; otherwise the command is not executed.
+/// 
+/// # Arguments
+/// 
+/// clients             data for all client accounts
+/// client_id           the client the command targets
+/// executable          the command to execute
+/// frozen_policy       the policy of an account the command opens
+/// 
+/// # Return Value
+/// 
+/// Err(ExecutionFailure::UnknownClient)    the client is unknown, and the command does not open accounts
+/// Err(ExecutionFailure::Account(_))       the client's account rejected the command
+/// Ok(())
+/// 
+pub fn execute<S: ClientStore + ?Sized> (
+    clients: &mut S,
+    client_id: ClientID,
+    executable: &dyn Executable,
+    frozen_policy: client_data::FrozenPolicy
+) -> Result<(), ExecutionFailure> {
+
+    // If the client is known, execute against its account...
+    if let Some(client) = clients.get(client_id) {
+        return executable.execute(client).map_err(ExecutionFailure::Account);
     }
 
+    // otherwise there is nothing to execute against, unless the command opens accounts
+    if !executable.opens_account() {
+        return Err(ExecutionFailure::UnknownClient);
+    }
+
+    // the account is only kept if the command opening it succeeds, so a failed first withdrawal leaves no empty account behind
+    let mut client = client_data::ClientData::with_policy(frozen_policy);
+    executable.execute(&mut client).map_err(ExecutionFailure::Account)?;
+    *clients.get_or_create(client_id, frozen_policy) = client;
+    Ok(())
 }
 
 
@@ -198,70 +958,1291 @@ fn msg_build (process_type: &str, problem: &str, tx: &TransactionID, client: &Cl
         problem )
 }
 
+// As msg_build, naming the ammount of commands which carry one, so the message can be matched to its source row
 #[inline(always)]
-fn withdraw_for_client (client: &mut client_data::ClientData, cmd: &command::Command) {
+fn msg_build_with_amount (process_type: &str, problem: &str, tx: &TransactionID, client: &ClientID, wealth: &Option<Decimal>) -> String {
+    match wealth {
+        Some(wealth) => format!( "TX:{} to {} of {} for user:{} did not succeed because {}.", 
+            tx, 
+            process_type,
+            wealth,
+            client,
+            problem ),
+        None => msg_build(process_type, problem, tx, client),
+    }
+}
 
-    // get the deposit ammount
-    if let Some(wealth) = cmd.get_wealth() {
+// The sum of every client's total
+fn sum_totals<S: ClientStore + ?Sized>(clients: &S) -> Decimal {
+    clients.sorted_clients().into_iter().map(|(_, client)| client.total()).sum()
+}
 
-        // withdraw the funds
-        if let Err(err) = client.withdraw(*wealth) {
+// Formats a line of the audit log; see AUDIT_LOG_HEADER
+// Balances are normalized, so equal balances read the same whatever scale they were computed at.
+fn audit_line (cmd: &command::Command, before: Option<client_data::NetPosition>, after: client_data::NetPosition) -> String {
+    let before = before.unwrap_or(client_data::NetPosition { available: Decimal::ZERO, held: Decimal::ZERO, total: Decimal::ZERO });
+    format!("{},{},{},{},{},{},{},{}",
+        cmd.get_transaction_id(),
+        cmd.get_client_id(),
+        type_name(cmd.get_type()),
+        cmd.get_wealth().map(|wealth| wealth.to_string()).unwrap_or_default(),
+        before.available.normalize(),
+        after.available.normalize(),
+        before.held.normalize(),
+        after.held.normalize())
+}
 
-            // if there was an error, log it appropriately
-            if client_data::AccountUpdateFailure::Frozen == err {
-                logger::warning( &msg_build("withdraw", "their account is frozen", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-            }
-            else {
-                logger::warning( &msg_build("withdraw", "their account has insufficient funds", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-            }
-        }
+// Creates a zero-balance account for the client, if it has none
+fn open_empty<S: ClientStore + ?Sized> (clients: &mut S, client_id: ClientID, frozen_policy: client_data::FrozenPolicy) {
+    clients.get_or_create(client_id, frozen_policy);
+}
+
+// Logs a command which was rejected or skipped, collecting it as a HandlerAnomaly when requested
+// A missing ammount is logged as an error under AmountPolicy::Reject, as it halts processing.
+// A failed assert, including one for an unknown client, means the file contradicts itself, so it is an error as well.
+// Deposits, withdrawals, adjustments, and asserts name their ammount; disputes, resolves, and chargebacks refer to an earlier one, so any ammount they carry is left out.
+fn report (stats: &mut ProcessingStats, options: &HandlerOptions, process_type: &str, problem: &str, cmd: &command::Command, reason: AnomalyReason) {
+    let msg = if cmd.get_type().carries_ammount() {
+        msg_build_with_amount(process_type, problem, &cmd.get_transaction_id(), &cmd.get_client_id(), cmd.get_wealth())
     }
-    // this condition should never be reached because deposit commands should always have a value
     else {
-        let msg = msg_build("withdraw", "the transaction did not contain the ammount", &cmd.get_transaction_id(), &cmd.get_client_id());
+        msg_build(process_type, problem, &cmd.get_transaction_id(), &cmd.get_client_id())
+    };
+    if (reason == AnomalyReason::MissingAmount && options.amount_policy == AmountPolicy::Reject) || cmd.get_type() == command::CommandType::Assert || (options.strict_frozen && is_frozen_deposit(cmd, &reason)) {
         logger::error( &msg );
     }
+    else {
+        logger::warning( &msg );
+    }
+    if let Some(anomalies) = stats.anomalies.as_mut() {
+        anomalies.push(HandlerAnomaly {
+            command_type: cmd.get_type(),
+            client_id: cmd.get_client_id(),
+            transaction_id: cmd.get_transaction_id(),
+            reason,
+        });
+    }
+}
+
+// Halts processing at a command which failed under Mode::Strict, returning false so the caller stops
+fn halt (stats: &mut ProcessingStats, cmd: &command::Command) -> bool {
+    logger::error( &format!("Processing halted at TX:{} for user:{} because it failed in strict mode.", cmd.get_transaction_id(), cmd.get_client_id()) );
+    stats.halted = true;
+    false
+}
+
+// Whether the command is a deposit rejected because its account is frozen, as after a chargeback
+fn is_frozen_deposit (cmd: &command::Command, reason: &AnomalyReason) -> bool {
+    cmd.get_type() == command::CommandType::Deposit && *reason == AnomalyReason::Failed(ExecutionFailure::Account(AccountUpdateFailure::Frozen))
+}
+
+// The client which actually owns a tx, when a command names a different client
+fn misdirected_owner (tx_index: &TxIndex, cmd: &command::Command) -> Option<ClientID> {
+    tx_index.owner(cmd.get_transaction_id())
+        .filter(|owner| *owner != cmd.get_client_id())
 }
 
-// This is what we do with a client's account when a deposit occurs.
 #[inline(always)]
-fn deposit_for_client (client: &mut client_data::ClientData, cmd: &command::Command) {
+fn type_name (command_type: command::CommandType) -> &'static str {
+    match command_type {
+        command::CommandType::Deposit => "deposit",
+        command::CommandType::Withdraw => "withdraw",
+        command::CommandType::Dispute => "dispute",
+        command::CommandType::Resolve => "resolve",
+        command::CommandType::Chargeback => "chargeback",
+        command::CommandType::ReverseChargeback => "reverse chargeback",
+        command::CommandType::Open => "open",
+        command::CommandType::AdjustmentCredit => "adjustment credit",
+        command::CommandType::AdjustmentDebit => "adjustment debit",
+        command::CommandType::Assert => "assert",
+    }
+}
 
-    // get the deposit ammount
-    if let Some(wealth) = cmd.get_wealth() {
 
-        // add the funds to the account
-        match client.deposit(cmd.get_transaction_id(), *wealth) {
+#[cfg(test)]
+mod command_handler_tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
 
-            // if there was an issue, log it
-            Err(err) => {
+    use rust_decimal::prelude::Decimal;
+    use rust_decimal_macros::dec;
 
-                //identify the issue
-                match err {
+    use crate::client_data::{AccountUpdateFailure, ClientData, ClientID, FeePolicy, FrozenPolicy};
+    use crate::client_store::ClientStore;
+    use crate::command::{Command, CommandType, Executable};
+    use crate::command_handler::{AmountPolicy, AmountSummary, AnomalyReason, ClientActivity, ExecutionFailure, HandlerAnomaly, FundsLedger, HandleError, HandlerOptions, ProcessingStats, TxIndex};
+    use crate::logger;
+    use crate::mode::Mode;
+    use crate::test_support::assert_balances_eq;
 
-                    AccountUpdateFailure::Frozen => {
-                        // log the error if the account was frozen
-                        logger::warning( &msg_build("deposit","their account is frozen", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                    },
+    // Handles the commands, returning the resulting client data
+    async fn handle(commands: Vec<Command>, options: HandlerOptions) -> HashMap<ClientID, Box<ClientData>> {
+        handle_with_stats(commands, options).await.0
+    }
 
-                    AccountUpdateFailure::DuplicateDepositTX => {
-                        // log the error if the deposit has a duplicate tx
-                        logger::warning( &msg_build("deposit","the deposit tx id is a duplicate", &cmd.get_transaction_id(), &cmd.get_client_id()) );
-                    },
+    // Handles the commands, returning the resulting client data and the handler's tallies
+    async fn handle_with_stats(commands: Vec<Command>, options: HandlerOptions) -> (HashMap<ClientID, Box<ClientData>>, ProcessingStats) {
+        let (tx, rx) = tokio::sync::mpsc::channel(commands.len().max(1));
+        for cmd in commands {
+            tx.send(cmd).await.unwrap();
+        }
+        drop(tx);
 
-                    _ => {
-                        panic!("unexpected issue with deposit");
-                    },
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        // a halted run still has its stats, which record that it halted
+        let stats = match super::handle_commands(data.clone(), rx, options).await {
+            Ok(stats) => stats,
+            Err(HandleError::Halted(stats)) => {
+                assert!(stats.halted);
+                *stats
+            },
+            Err(err) => panic!("handle_commands failed: {}", err),
+        };
+        (Arc::try_unwrap(data).ok().unwrap().into_inner().unwrap(), stats)
+    }
+
+    // a kind of command the handler knows nothing about
+    struct Bonus {
+        wealth: Decimal,
+    }
+
+    impl Executable for Bonus {
+        fn execute(&self, client: &mut ClientData) -> Result<(), AccountUpdateFailure> { client.deposit(0, self.wealth) }
+        fn name(&self) -> &'static str { "bonus" }
+        fn opens_account(&self) -> bool { true }
+    }
+
+    // a kind of command which cannot create accounts
+    struct Audit;
+
+    impl Executable for Audit {
+        fn execute(&self, _client: &mut ClientData) -> Result<(), AccountUpdateFailure> { Ok(()) }
+        fn name(&self) -> &'static str { "audit" }
+    }
+
+    #[test]
+    fn test_custom_executable() {
+        let mut clients: HashMap<ClientID, Box<ClientData>> = HashMap::new();
+
+        assert_eq!(Err(ExecutionFailure::UnknownClient), super::execute(&mut clients, 7, &Audit, FrozenPolicy::BlockAll));
+        assert!(!clients.contains_key(&7));
+
+        assert_eq!(Ok(()), super::execute(&mut clients, 7, &Bonus { wealth: dec!(12.5) }, FrozenPolicy::BlockAll));
+        assert_eq!(clients.get(&7).unwrap().get_wealth(), dec!(12.5));
+
+        assert_eq!(Ok(()), super::execute(&mut clients, 7, &Audit, FrozenPolicy::BlockAll));
+        assert_eq!(clients.get(&7).unwrap().get_wealth(), dec!(12.5));
+    }
+
+    #[tokio::test]
+    async fn test_max_amount() {
+        let options = HandlerOptions { max_amount: Some(dec!(1000)), ..HandlerOptions::default() };
+
+        let clients = handle(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(5000))),
+        ], options).await;
+        assert!(!clients.contains_key(&1));
+
+        let options = HandlerOptions { max_amount: Some(dec!(1000)), ..HandlerOptions::default() };
+        let clients = handle(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(5000))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(1000))),
+            Command::new(CommandType::Withdraw, 1, 3, Some(dec!(1000.01))),
+        ], options).await;
+        assert_eq!(clients.get(&1).unwrap().get_wealth(), dec!(1000));
+
+        // without a bound, anything goes
+        let clients = handle(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(5000))),
+        ], HandlerOptions::default()).await;
+        assert_eq!(clients.get(&1).unwrap().get_wealth(), dec!(5000));
+    }
+
+    #[tokio::test]
+    async fn test_strict_tx() {
+        let mut tx_index = TxIndex::default();
+        tx_index.record(&Command::new(CommandType::Deposit, 2, 43, Some(dec!(10))));
+        assert_eq!(Some(2), super::misdirected_owner(&tx_index, &Command::new(CommandType::Dispute, 5, 43, None)));
+        assert_eq!(None, super::misdirected_owner(&tx_index, &Command::new(CommandType::Dispute, 2, 43, None)));
+        assert_eq!(None, super::misdirected_owner(&tx_index, &Command::new(CommandType::Dispute, 5, 44, None)));
+
+        // a dispute naming the wrong client affects neither client
+        let options = HandlerOptions { strict_tx: true, ..HandlerOptions::default() };
+        let clients = handle(vec![
+            Command::new(CommandType::Deposit, 2, 43, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 5, 44, Some(dec!(3))),
+            Command::new(CommandType::Dispute, 5, 43, None),
+        ], options).await;
+        assert_eq!(clients.get(&2).unwrap().get_wealth(), dec!(10));
+        assert_eq!(clients.get(&2).unwrap().get_held_wealth(), dec!(0));
+        assert_eq!(clients.get(&5).unwrap().get_wealth(), dec!(3));
+        assert_eq!(clients.get(&5).unwrap().get_held_wealth(), dec!(0));
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 2, 1, None),
+            Command::new(CommandType::Resolve, 3, 1, None),
+            Command::new(CommandType::Chargeback, 4, 9, None),
+            Command::new(CommandType::Dispute, 1, 2, None),
+            Command::new(CommandType::Resolve, 1, 3, None),
+            Command::new(CommandType::Resolve, 1, 1, None),
+            Command::new(CommandType::Withdraw, 1, 4, Some(dec!(20))),
+        ], HandlerOptions::default()).await;
+
+        let mut tx_index = TxIndex::default();
+        tx_index.record(&Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))));
+        assert_eq!(ProcessingStats {
+            executed: 1,
+            failed: 7,
+            unknown_client: 3,
+            tx_not_found: 2,
+            tx_index,
+            by_type: HashMap::from([
+                (CommandType::Deposit, 1),
+                (CommandType::Withdraw, 1),
+                (CommandType::Dispute, 2),
+                (CommandType::Resolve, 3),
+                (CommandType::Chargeback, 1),
+            ]),
+            ..ProcessingStats::default()
+        }, stats);
+    }
+
+    #[tokio::test]
+    async fn test_max_chargebacks() {
+        let options = HandlerOptions { max_chargebacks: Some(2), ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(1))),
+            Command::new(CommandType::Deposit, 2, 2, Some(dec!(1))),
+            Command::new(CommandType::Deposit, 3, 3, Some(dec!(1))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Dispute, 2, 2, None),
+            Command::new(CommandType::Dispute, 3, 3, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+            Command::new(CommandType::Chargeback, 2, 2, None),
+            Command::new(CommandType::Chargeback, 3, 3, None),
+        ], options).await;
+
+        assert!(stats.halted);
+        assert_eq!(2, stats.chargebacks);
+        assert!(clients.get(&1).unwrap().is_locked());
+        assert!(clients.get(&2).unwrap().is_locked());
+        assert!(!clients.get(&3).unwrap().is_locked());
+
+        // unlimited by default
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(1))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+        ], HandlerOptions::default()).await;
+        assert!(!stats.halted);
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let options = HandlerOptions { audit: true, ..HandlerOptions::default() };
+        let (mut clients, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(5))),
+            Command::new(CommandType::Withdraw, 1, 3, Some(dec!(12))),
+            // a chargeback after the funds were withdrawn leaves a negative balance
+            Command::new(CommandType::Dispute, 1, 2, None),
+            Command::new(CommandType::Chargeback, 1, 2, None),
+            Command::new(CommandType::Deposit, 2, 4, Some(dec!(8))),
+            Command::new(CommandType::Withdraw, 2, 5, Some(dec!(3))),
+            Command::new(CommandType::Dispute, 2, 5, None),
+            Command::new(CommandType::Deposit, 3, 6, Some(dec!(4))),
+            Command::new(CommandType::Withdraw, 3, 7, Some(dec!(1))),
+            Command::new(CommandType::Dispute, 3, 7, None),
+            Command::new(CommandType::Chargeback, 3, 7, None),
+            Command::new(CommandType::Deposit, 4, 8, Some(dec!(2))),
+            Command::new(CommandType::Withdraw, 4, 9, Some(dec!(2))),
+            Command::new(CommandType::Dispute, 4, 9, None),
+            Command::new(CommandType::Resolve, 4, 9, None),
+            // failed commands move no money
+            Command::new(CommandType::Withdraw, 4, 10, Some(dec!(50))),
+            Command::new(CommandType::Chargeback, 5, 1, None),
+        ], options).await;
+
+        let ledger = stats.ledger.unwrap();
+        assert_eq!(dec!(29), ledger.deposited);
+        assert_eq!(dec!(18), ledger.withdrawn);
+        assert_eq!(dec!(5), ledger.charged_back);
+        assert_eq!(dec!(4), ledger.disputed_withdrawals);
+        assert!(ledger.verify_conservation(&clients));
+
+        // books which were tampered with do not balance
+        clients.insert(9, Box::new(ClientData::builder().wealth(dec!(1)).build().unwrap()));
+        assert!(!ledger.verify_conservation(&clients));
+
+        // balances loaded before handling, as from a seed, open the books, so they still balance
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let mut seeded: HashMap<ClientID, Box<ClientData>> = HashMap::new();
+        seeded.insert(1, Box::new(ClientData::builder().wealth(dec!(10)).held_wealth(dec!(2)).build().unwrap()));
+        let data = Arc::new(Mutex::new(seeded));
+        tx.send(Command::new(CommandType::Deposit, 1, 1, Some(dec!(6)))).await.unwrap();
+        drop(tx);
+        let stats = super::handle_commands(data.clone(), rx, HandlerOptions { audit: true, ..HandlerOptions::default() }).await.unwrap();
+        let ledger = stats.ledger.unwrap();
+        assert_eq!(dec!(12), ledger.opening);
+        assert!(ledger.verify_conservation(&*data.lock().unwrap()));
+
+        // the fee on a withdrawal leaves the books too, and is kept when the withdrawal is disputed
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let mut seeded: HashMap<ClientID, Box<ClientData>> = HashMap::new();
+        seeded.insert(1, Box::new(ClientData::builder().wealth(dec!(10)).fee_policy(FeePolicy::Fixed(dec!(0.5))).build().unwrap()));
+        let data = Arc::new(Mutex::new(seeded));
+        tx.send(Command::new(CommandType::Withdraw, 1, 1, Some(dec!(3)))).await.unwrap();
+        tx.send(Command::new(CommandType::Dispute, 1, 1, None)).await.unwrap();
+        drop(tx);
+        let stats = super::handle_commands(data.clone(), rx, HandlerOptions { audit: true, ..HandlerOptions::default() }).await.unwrap();
+        let ledger = stats.ledger.unwrap();
+        assert_eq!(dec!(0.5), ledger.fees);
+        assert!(ledger.verify_conservation(&*data.lock().unwrap()));
+
+        // no ledger is kept unless requested
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+        ], HandlerOptions::default()).await;
+        assert_eq!(None::<FundsLedger>, stats.ledger);
+    }
+
+    #[tokio::test]
+    async fn test_anomaly_report() {
+        let options = HandlerOptions { anomaly_report: true, ..HandlerOptions::default() };
+        let (_, stats) = handle_with_stats(vec![
+            // client 1 disputes most of their deposits, charging one back
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 3, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Dispute, 1, 2, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+            // client 2 disputes one of several deposits
+            Command::new(CommandType::Deposit, 2, 4, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 2, 5, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 2, 4, None),
+            Command::new(CommandType::Resolve, 2, 4, None),
+        ], options).await;
+
+        let activity = stats.activity.unwrap();
+        assert_eq!(ClientActivity { deposits: 3, disputes: 2, chargebacks: 1 }, activity.clients[&1]);
+        assert_eq!(vec![1], activity.flagged());
+
+        logger::capture::start();
+        activity.log_anomalies();
+        let logs = logger::capture::take();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
+        assert!(logs[0].ends_with("Client 1 may be anomalous: 3 deposits, 2 disputes, 1 chargebacks."));
+
+        let (_, stats) = handle_with_stats(vec![], HandlerOptions::default()).await;
+        assert_eq!(None, stats.activity);
+    }
+
+    #[tokio::test]
+    async fn test_amount_stats() {
+        let options = HandlerOptions { amount_stats: true, ..HandlerOptions::default() };
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(2.5))),
+            Command::new(CommandType::Deposit, 2, 3, Some(dec!(1000))),
+            // a failed deposit is still profiled, as it is part of the input
+            Command::new(CommandType::Deposit, 2, 3, Some(dec!(7.5))),
+            Command::new(CommandType::Withdraw, 1, 4, Some(dec!(5))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+        ], options).await;
+
+        let amounts = stats.amounts.unwrap();
+        assert_eq!(4, amounts.deposits.count);
+        assert_eq!(dec!(1020), amounts.deposits.sum);
+        assert_eq!(Some(dec!(2.5)), amounts.deposits.min);
+        assert_eq!(Some(dec!(1000)), amounts.deposits.max);
+        assert_eq!(Some(dec!(255)), amounts.deposits.mean());
+        assert_eq!(1, amounts.withdrawals.count);
+        assert_eq!(Some(dec!(5)), amounts.withdrawals.mean());
+
+        logger::capture::start();
+        amounts.log_summary();
+        let logs = logger::capture::take();
+        assert_eq!(vec![
+            "Info:  Deposits: 4 totalling 1020.0, min 2.5, max 1000, mean 255.0.".to_owned(),
+            "Info:  Withdrawals: 1 totalling 5, min 5, max 5, mean 5.".to_owned(),
+        ], logs);
+
+        assert_eq!(None, AmountSummary::default().mean());
+        assert_eq!("none", AmountSummary::default().to_string());
+
+        let (_, stats) = handle_with_stats(vec![], HandlerOptions::default()).await;
+        assert_eq!(None, stats.amounts);
+    }
+
+    // a store which counts the calls made to it
+    #[derive(Default)]
+    struct CountingStore {
+        clients: HashMap<ClientID, Box<ClientData>>,
+        gets: u64,
+        creates: u64,
+        iters: std::cell::Cell<u64>,
+    }
+
+    impl ClientStore for CountingStore {
+        fn get(&mut self, client_id: ClientID) -> Option<&mut ClientData> {
+            self.gets += 1;
+            ClientStore::get(&mut self.clients, client_id)
+        }
+        fn get_or_create(&mut self, client_id: ClientID, frozen_policy: FrozenPolicy) -> &mut ClientData {
+            self.creates += 1;
+            self.clients.get_or_create(client_id, frozen_policy)
+        }
+        fn iter(&self) -> Box<dyn Iterator<Item = (ClientID, &ClientData)> + '_> {
+            self.iters.set(self.iters.get() + 1);
+            ClientStore::iter(&self.clients)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_store() {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for cmd in [
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(4))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Dispute, 9, 1, None),
+        ] {
+            tx.send(cmd).await.unwrap();
+        }
+        drop(tx);
 
+        let store = Arc::new(Mutex::new(CountingStore::default()));
+        let options = HandlerOptions { audit: true, ..HandlerOptions::default() };
+        let stats = super::handle_commands(store.clone(), rx, options).await.unwrap();
+        assert_eq!(3, stats.executed);
+        assert_eq!(1, stats.unknown_client);
+
+        // every command looks its client up first, and only the first deposit has to open an account;
+        // the books look the withdrawal's client up again, for the fee policy
+        let store = Arc::try_unwrap(store).ok().unwrap().into_inner().unwrap();
+        assert_eq!(1, store.creates);
+        assert_eq!(5, store.gets);
+        assert_eq!(dec!(10), store.clients[&1].get_held_wealth());
+
+        // the store is iterated once to open the books, and once to check them
+        assert!(stats.ledger.unwrap().verify_conservation(&store));
+        assert_eq!(2, store.iters.get());
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        // every command is waiting in the channel, so they are handled as batches
+        let mut commands = Vec::new();
+        for tx in 0..600 {
+            let client = (tx % 3) as ClientID + 1;
+            commands.push(Command::new(CommandType::Deposit, client, tx, Some(dec!(1))));
+            if tx % 3 == 0 {
+                commands.push(Command::new(CommandType::Withdraw, client, tx + 10_000, Some(dec!(1))));
+            }
+        }
+        commands.push(Command::new(CommandType::Dispute, 2, 1, None));
+
+        let (clients, stats) = handle_with_stats(commands, HandlerOptions::default()).await;
+        assert_eq!(801, stats.executed);
+        // client 1's withdrawals each follow its deposit, so none fail
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(0), dec!(0), dec!(0));
+        assert_balances_eq(clients.get(&2).unwrap(), dec!(199), dec!(1), dec!(200));
+        assert_balances_eq(clients.get(&3).unwrap(), dec!(200), dec!(0), dec!(200));
+    }
+
+    #[tokio::test]
+    async fn test_client_range() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 0, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 100, 3, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 101, 4, Some(dec!(10))),
+        ];
+
+        let clients = handle(commands(), HandlerOptions::default()).await;
+        assert_eq!(4, clients.len());
+
+        // the bounds are inclusive
+        logger::capture::start();
+        let options = HandlerOptions { client_range: Some(1..=100), include_empty: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let logs = logger::capture::take();
+        assert!(!clients.contains_key(&0));
+        assert!(clients.contains_key(&1));
+        assert!(clients.contains_key(&100));
+        assert!(!clients.contains_key(&101));
+        assert_eq!(2, stats.failed);
+        assert_eq!(2, logs.len());
+        assert!(logs[0].contains("TX:1 to deposit of 10 for user:0 did not succeed because the client is outside the valid range of 1 to 100."));
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_deposits() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10.00))),
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(12))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(3))),
+        ];
+
+        // duplicates are rejected by default
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert_eq!(dec!(7), clients.get(&1).unwrap().get_total());
+        assert_eq!(2, stats.failed);
+        assert_eq!(0, stats.retried);
+
+        // an identical retry succeeds without depositing again, while a conflicting ammount still warns
+        logger::capture::start();
+        let options = HandlerOptions { dedupe_deposits: true, audit: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let logs = logger::capture::take();
+        assert_eq!(dec!(7), clients.get(&1).unwrap().get_total());
+        assert_eq!(2, stats.executed);
+        assert_eq!(1, stats.retried);
+        assert_eq!(1, stats.failed);
+        assert_eq!(2, logs.len());
+        assert!(logs[0].starts_with("Info:"));
+        assert!(logs[1].starts_with("Warning!"));
+        assert!(logs[1].contains("the tx conflicts with an earlier deposit of 10"));
+        assert!(stats.ledger.unwrap().verify_conservation(&clients));
+    }
+
+    #[tokio::test]
+    async fn test_adjustments() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::AdjustmentCredit, 1, 2, Some(dec!(2.5))),
+            Command::new(CommandType::AdjustmentDebit, 1, 3, Some(dec!(20))),
+            Command::new(CommandType::Dispute, 1, 2, None),
+            Command::new(CommandType::AdjustmentCredit, 2, 4, Some(dec!(1))),
+            Command::new(CommandType::AdjustmentDebit, 3, 5, Some(dec!(1))),
+        ];
+
+        // a debit beyond the available funds fails, and an adjustment cannot be disputed
+        logger::capture::start();
+        let options = HandlerOptions { audit: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let logs = logger::capture::take();
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(12.5), dec!(0), dec!(12.5));
+        assert_balances_eq(clients.get(&2).unwrap(), dec!(1), dec!(0), dec!(1));
+        assert!(!clients.contains_key(&3));
+        assert_eq!(3, stats.executed);
+        assert_eq!(3, stats.failed);
+        assert!(logs[0].contains("TX:3 to adjustment debit of 20 for user:1 did not succeed"));
+        let ledger = stats.ledger.unwrap();
+        assert_eq!(dec!(3.5), ledger.adjusted);
+        assert!(ledger.verify_conservation(&clients));
+
+        // the debit may leave the account negative when allowed
+        let options = HandlerOptions { audit: true, allow_negative_adjustment: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(-7.5), dec!(0), dec!(-7.5));
+        assert_eq!(4, stats.executed);
+        assert!(stats.ledger.unwrap().verify_conservation(&clients));
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(4))),
+            Command::new(CommandType::Dispute, 1, 2, None),
+        ];
+
+        logger::capture::start();
+        let options = HandlerOptions { disabled: HashSet::from([CommandType::Dispute]), ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let logs = logger::capture::take();
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(6), dec!(0), dec!(6));
+        assert_eq!(2, stats.executed);
+        assert_eq!(0, stats.failed);
+        assert_eq!(2, stats.disabled);
+        assert_eq!(4, stats.handled());
+
+        // only the first skip of each type is logged
+        assert_eq!(1, logs.len());
+        assert!(logs[0].contains("Skipping dispute commands, as they are disabled; the first is TX:1 for user:1."));
+
+        // when enabled, the dispute holds the deposit, so the withdrawal fails
+        let (clients, _) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(0), dec!(10), dec!(10));
+    }
+
+    #[tokio::test]
+    async fn test_tx_index() {
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 2, 2, Some(dec!(5))),
+            Command::new(CommandType::Withdraw, 2, 3, Some(dec!(1))),
+            Command::new(CommandType::Deposit, 3, 4, Some(dec!(1))),
+            Command::new(CommandType::Dispute, 3, 4, None),
+            Command::new(CommandType::Chargeback, 3, 4, None),
+            // these fail, so they do not claim their txs
+            Command::new(CommandType::Deposit, 2, 2, Some(dec!(7))),
+            Command::new(CommandType::Deposit, 3, 5, Some(dec!(7))),
+            Command::new(CommandType::Withdraw, 1, 6, Some(dec!(100))),
+        ], HandlerOptions::default()).await;
+
+        assert_eq!(Some(1), stats.tx_index.owner(1));
+        assert_eq!(Some(2), stats.tx_index.owner(2));
+        assert_eq!(Some(2), stats.tx_index.owner(3));
+        assert_eq!(Some(3), stats.tx_index.owner(4));
+        assert_eq!(None, stats.tx_index.owner(5));
+        assert_eq!(None, stats.tx_index.owner(6));
+        assert_eq!(4, stats.tx_index.len());
+    }
+
+    #[tokio::test]
+    async fn test_collect_anomalies() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(20))),
+            Command::new(CommandType::Dispute, 2, 1, None),
+            Command::new(CommandType::Resolve, 1, 1, None),
+            Command::new(CommandType::Deposit, 1, 3, Some(dec!(5000))),
+            Command::new(CommandType::Deposit, 0, 4, Some(dec!(1))),
+            Command::new(CommandType::Dispute, 3, 1, None),
+            Command::new(CommandType::Withdraw, 1, 5, None),
+            Command::new(CommandType::Deposit, 1, 6, Some(dec!(1))),
+        ];
+        let anomaly = |command_type, client_id, transaction_id, reason| HandlerAnomaly { command_type, client_id, transaction_id, reason };
+
+        let options = HandlerOptions {
+            collect_anomalies: true,
+            max_amount: Some(dec!(1000)),
+            client_range: Some(1..=100),
+            strict_tx: true,
+            ..HandlerOptions::default()
+        };
+        logger::capture::start();
+        let (_, stats) = handle_with_stats(commands(), options).await;
+        let logs = logger::capture::take();
+        assert_eq!(Some(vec![
+            anomaly(CommandType::Withdraw, 1, 2, AnomalyReason::Failed(ExecutionFailure::Account(AccountUpdateFailure::InsufficientFunds))),
+            anomaly(CommandType::Dispute, 2, 1, AnomalyReason::Failed(ExecutionFailure::UnknownClient)),
+            anomaly(CommandType::Resolve, 1, 1, AnomalyReason::Failed(ExecutionFailure::Account(AccountUpdateFailure::TXUndisputed))),
+            anomaly(CommandType::Deposit, 1, 3, AnomalyReason::ExceedsMaxAmount(dec!(1000))),
+            anomaly(CommandType::Deposit, 0, 4, AnomalyReason::ClientOutOfRange),
+            anomaly(CommandType::Dispute, 3, 1, AnomalyReason::Failed(ExecutionFailure::UnknownClient)),
+            anomaly(CommandType::Withdraw, 1, 5, AnomalyReason::MissingAmount),
+        ]), stats.anomalies);
+        assert_eq!(7, stats.failed);
+
+        // they are logged as well
+        assert_eq!(7, logs.len());
+        assert!(logs[0].contains("TX:2 to withdraw of 20 for user:1 did not succeed because the account has insufficient funds."));
+
+        // nothing is collected unless requested
+        let (_, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert_eq!(None, stats.anomalies);
+    }
+
+    #[tokio::test]
+    async fn test_failure_messages() {
+        let commands = vec![
+            Command::new(CommandType::Deposit, 2, 43, Some(dec!(17.0))),
+            Command::new(CommandType::Deposit, 2, 43, Some(dec!(17.0))),
+            Command::new(CommandType::Dispute, 2, 44, Some(dec!(3.0))),
+        ];
+
+        logger::capture::start();
+        handle(commands, HandlerOptions::default()).await;
+        let logs = logger::capture::take();
+
+        // a deposit names its ammount, while a dispute refers to the ammount of its tx, so any of its own is left out
+        assert!(logs.iter().any(|log| log.contains("TX:43 to deposit of 17.0 for user:2 did not succeed because the deposit tx id is a duplicate.")));
+        assert!(logs.iter().any(|log| log.contains("TX:44 to dispute for user:2 did not succeed because")));
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log_path = dir.path().join("audit.csv").to_str().unwrap().to_owned();
+        let commands = vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10.5))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(20))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+        ];
+
+        let options = HandlerOptions { audit_log_path: Some(audit_log_path.clone()), ..HandlerOptions::default() };
+        let (_, stats) = handle_with_stats(commands, options).await;
+        assert_eq!(1, stats.failed);
+
+        // the successful deposit and dispute are logged with the balances before and after them; the failed withdrawal is not
+        let audit_log = std::fs::read_to_string(&audit_log_path).unwrap();
+        assert_eq!(vec![
+            super::AUDIT_LOG_HEADER,
+            "1,1,deposit,10.5,0,10.5,0,0",
+            "1,1,dispute,,10.5,0,0,10.5",
+        ], audit_log.lines().collect::<Vec<&str>>());
+
+        // a halted run still writes the lines of the commands before it
+        let options = HandlerOptions { audit_log_path: Some(audit_log_path.clone()), mode: Mode::Strict, ..HandlerOptions::default() };
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10.5))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(20))),
+        ], options).await;
+        assert!(stats.halted);
+        let audit_log = std::fs::read_to_string(&audit_log_path).unwrap();
+        assert_eq!(vec![super::AUDIT_LOG_HEADER, "1,1,deposit,10.5,0,10.5,0,0"], audit_log.lines().collect::<Vec<&str>>());
+
+        // the chargeback which reaches max_chargebacks was applied, so it is audited before processing halts
+        let options = HandlerOptions { audit_log_path: Some(audit_log_path.clone()), max_chargebacks: Some(1), ..HandlerOptions::default() };
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10.5))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+        ], options).await;
+        assert!(stats.halted);
+        let audit_log = std::fs::read_to_string(&audit_log_path).unwrap();
+        assert_eq!(Some("1,1,chargeback,,0,0,10.5,0"), audit_log.lines().last());
+
+        // a path which cannot be created fails before any command is handled
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let options = HandlerOptions { audit_log_path: Some(dir.path().join("no/such/audit.csv").to_str().unwrap().to_owned()), ..HandlerOptions::default() };
+        let data: Arc<Mutex<HashMap<ClientID, Box<ClientData>>>> = Arc::new(Mutex::new(HashMap::new()));
+        assert!(matches!(super::handle_commands(data, rx, options).await, Err(HandleError::AuditLog(_))));
+    }
+
+    #[tokio::test]
+    async fn test_seeded_clients() {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let mut seeded: HashMap<ClientID, Box<ClientData>> = HashMap::new();
+        seeded.insert(1, Box::new(ClientData::builder().wealth(dec!(10)).held_wealth(dec!(2)).build().unwrap()));
+        let data = Arc::new(Mutex::new(seeded));
+        let options = HandlerOptions { seeded_clients: [1].into_iter().collect(), ..HandlerOptions::default() };
+
+        tx.send(Command::new(CommandType::Deposit, 1, 5, Some(dec!(1)))).await.unwrap();
+        tx.send(Command::new(CommandType::Resolve, 1, 4, None)).await.unwrap();
+        tx.send(Command::new(CommandType::Deposit, 2, 6, Some(dec!(1)))).await.unwrap();
+        tx.send(Command::new(CommandType::Dispute, 2, 4, None)).await.unwrap();
+        drop(tx);
+
+        logger::capture::start();
+        let stats = super::handle_commands(data.clone(), rx, options).await.unwrap();
+        let logs = logger::capture::take();
+
+        // the seeded balances compound with the commands handled after them
+        assert_balances_eq(&data.lock().unwrap()[&1], dec!(11), dec!(2), dec!(13));
+        assert_eq!(2, stats.failed);
+
+        // an unknown tx for a seeded client likely predates the seed, unlike one for any other client
+        assert_eq!(2, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
+        assert!(logs[0].contains("predates the seeded balances"));
+        assert!(!logs[1].contains("predates the seeded balances"));
+    }
+
+    #[tokio::test]
+    async fn test_open() {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let data: Arc<Mutex<HashMap<ClientID, Box<ClientData>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let handler = tokio::spawn(super::handle_commands(data.clone(), rx, HandlerOptions::default()));
+
+        // the account exists, empty, before any deposit
+        tx.send(Command::new(CommandType::Open, 1, 1, None)).await.unwrap();
+        while data.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        {
+            let data = data.lock().unwrap();
+            assert_balances_eq(data.get(&1).unwrap(), dec!(0), dec!(0), dec!(0));
+            assert_eq!(0, data.get(&1).unwrap().get_transaction_count());
+        }
+
+        logger::capture::start();
+        tx.send(Command::new(CommandType::Deposit, 1, 2, Some(dec!(10)))).await.unwrap();
+        tx.send(Command::new(CommandType::Open, 1, 3, None)).await.unwrap();
+        drop(tx);
+        let stats = handler.await.unwrap().unwrap();
+        let logs = logger::capture::take();
+
+        // opening it again warns, and leaves it as it was
+        assert_balances_eq(data.lock().unwrap().get(&1).unwrap(), dec!(10), dec!(0), dec!(10));
+        assert_eq!(2, stats.executed);
+        assert_eq!(1, stats.failed);
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!") && logs[0].contains("TX:3 to open for user:1 did not succeed because the account already exists."));
+    }
+
+    #[tokio::test]
+    async fn test_include_empty() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 2, 1, None),
+        ];
+
+        let clients = handle(commands(), HandlerOptions::default()).await;
+        assert!(!clients.contains_key(&2));
+
+        let options = HandlerOptions { include_empty: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let empty = clients.get(&2).unwrap();
+        assert_eq!(dec!(0), empty.get_total());
+        assert_eq!(dec!(0), empty.get_held_wealth());
+        assert!(!empty.is_locked());
+        assert_eq!(dec!(10), clients.get(&1).unwrap().get_wealth());
+
+        // the dispute still failed because the client was unknown
+        assert_eq!(1, stats.unknown_client);
+    }
+
+    #[tokio::test]
+    async fn test_failed_withdraw_opens_no_account() {
+        let commands = || vec![
+            Command::new(CommandType::Withdraw, 1, 1, Some(dec!(5))),
+            Command::new(CommandType::Deposit, 2, 2, Some(dec!(10))),
+            Command::new(CommandType::Withdraw, 2, 3, Some(dec!(4))),
+        ];
+
+        // a withdrawal from an unknown client has no funds to draw on, so it fails without leaving an empty account
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert!(!clients.contains_key(&1));
+        assert_eq!(1, stats.failed);
+        assert_balances_eq(clients.get(&2).unwrap(), dec!(6), dec!(0), dec!(6));
+
+        // include_empty still lists the client, with nothing in the account
+        let options = HandlerOptions { include_empty: true, ..HandlerOptions::default() };
+        let clients = handle(commands(), options).await;
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(0), dec!(0), dec!(0));
+    }
+
+    #[tokio::test]
+    async fn test_frozen_policy() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(4))),
+            Command::new(CommandType::Withdraw, 1, 3, Some(dec!(1))),
+        ];
+
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert_eq!(dec!(0), clients.get(&1).unwrap().get_total());
+        assert_eq!(2, stats.failed);
+
+        let options = HandlerOptions { frozen_policy: FrozenPolicy::AllowDeposits, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let client = clients.get(&1).unwrap();
+        assert_eq!(FrozenPolicy::AllowDeposits, client.get_frozen_policy());
+        assert_eq!(dec!(4), client.get_total());
+        assert!(client.is_locked());
+        assert_eq!(1, stats.failed);
+    }
+
+    #[tokio::test]
+    async fn test_tx_reused_across_clients() {
+        let options = HandlerOptions { strict_tx: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 7, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 2, 7, Some(dec!(3))),
+            Command::new(CommandType::Dispute, 1, 7, None),
+            Command::new(CommandType::Dispute, 2, 7, None),
+            Command::new(CommandType::Resolve, 1, 7, None),
+            Command::new(CommandType::Chargeback, 2, 7, None),
+        ], options).await;
+
+        // each client's commands reach only their own tx 7
+        assert_eq!(6, stats.executed);
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(10), dec!(0), dec!(10));
+        assert!(!clients.get(&1).unwrap().is_locked());
+        assert_balances_eq(clients.get(&2).unwrap(), dec!(0), dec!(0), dec!(0));
+        assert!(clients.get(&2).unwrap().is_locked());
+
+        // a tx is still unique within a client
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 7, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 7, Some(dec!(3))),
+        ], HandlerOptions::default()).await;
+        assert_eq!(1, stats.failed);
+    }
+
+    #[tokio::test]
+    async fn test_amount_policy() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, None),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Deposit, 1, 3, Some(dec!(5))),
+        ];
+
+        // by default, the deposit missing its ammount is warned of and skipped, while the dispute without one is executed
+        logger::capture::start();
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        let logs = logger::capture::take();
+        assert_balances_eq(&clients[&1], dec!(5), dec!(10), dec!(15));
+        assert_eq!((3, 1, false), (stats.executed, stats.failed, stats.halted));
+        assert_eq!(vec!["Warning!  TX:2 to deposit for user:1 did not succeed because the transaction did not contain the amount.".to_owned()], logs);
+
+        // under DefaultZero, it is warned of and deposits nothing, but claims its tx
+        logger::capture::start();
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions { amount_policy: AmountPolicy::DefaultZero, ..HandlerOptions::default() }).await;
+        let logs = logger::capture::take();
+        assert_balances_eq(&clients[&1], dec!(5), dec!(10), dec!(15));
+        assert_eq!((4, 0), (stats.executed, stats.failed));
+        assert_eq!(Some(1), stats.tx_index.owner(2));
+        assert_eq!(vec!["Warning!  TX:2 to deposit for user:1 does not have an amount, so it is treated as zero.".to_owned()], logs);
+
+        // under Reject, it is an error which halts processing, even in lenient mode
+        logger::capture::start();
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions { amount_policy: AmountPolicy::Reject, ..HandlerOptions::default() }).await;
+        let logs = logger::capture::take();
+        assert_balances_eq(&clients[&1], dec!(10), dec!(0), dec!(10));
+        assert_eq!((1, 1, true), (stats.executed, stats.failed, stats.halted));
+        assert_eq!(vec![
+            "ERROR!  TX:2 to deposit for user:1 did not succeed because the transaction did not contain the amount.".to_owned(),
+            "ERROR!  Processing halted at TX:2 for user:1 because it does not have an amount.".to_owned(),
+        ], logs);
+    }
+
+    #[tokio::test]
+    async fn test_strict_frozen() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(4))),
+            Command::new(CommandType::Deposit, 2, 3, Some(dec!(1))),
+        ];
+
+        // by default, the deposit to the frozen account is warned of and skipped
+        logger::capture::start();
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        let logged = logger::capture::take();
+        assert!(!stats.halted);
+        assert!(clients.contains_key(&2));
+        assert!(logged.iter().any(|line| line.starts_with("Warning! ") && line.contains("the account is frozen")), "{:?}", logged);
+
+        // under strict_frozen, it is an error which halts processing, even in lenient mode
+        logger::capture::start();
+        let options = HandlerOptions { strict_frozen: true, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let logged = logger::capture::take();
+        assert!(stats.halted);
+        assert_eq!(1, stats.failed);
+        assert!(!clients.contains_key(&2));
+        assert!(logged.iter().any(|line| line.starts_with("ERROR! ") && line.contains("the account is frozen")), "{:?}", logged);
+        assert!(!logged.iter().any(|line| line.starts_with("Warning! ")), "{:?}", logged);
+
+        // a frozen account which accepts deposits never rejects one
+        let options = HandlerOptions { strict_frozen: true, frozen_policy: FrozenPolicy::AllowDeposits, ..HandlerOptions::default() };
+        let (_, stats) = handle_with_stats(commands(), options).await;
+        assert!(!stats.halted);
+    }
+
+    #[tokio::test]
+    async fn test_max_open_disputes() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(5))),
+            Command::new(CommandType::Deposit, 1, 3, Some(dec!(2))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Dispute, 1, 2, None),
+            Command::new(CommandType::Dispute, 1, 3, None),
+        ];
+
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert_eq!(dec!(17), clients.get(&1).unwrap().get_held_wealth());
+        assert_eq!(0, stats.failed);
+
+        crate::logger::capture::start();
+        let options = HandlerOptions { max_open_disputes: Some(2), ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        let logged = crate::logger::capture::take();
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(2), dec!(15), dec!(17));
+        assert_eq!(1, stats.failed);
+        assert!(logged.iter().any(|line| line.contains("the account has reached its limit of open disputes")), "{:?}", logged);
+    }
+
+    #[tokio::test]
+    async fn test_assert() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(5))),
+            Command::new(CommandType::Dispute, 1, 2, None),
+            // held funds are not available, so only the 10 is
+            Command::new(CommandType::Assert, 1, 3, Some(dec!(10.00))),
+            Command::new(CommandType::Assert, 1, 4, Some(dec!(15))),
+            Command::new(CommandType::Assert, 2, 5, Some(dec!(0))),
+            Command::new(CommandType::Withdraw, 1, 6, Some(dec!(1))),
+        ];
+
+        logger::capture::start();
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        let logs = logger::capture::take();
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(9), dec!(5), dec!(14));
+        assert!(!clients.contains_key(&2));
+        assert_eq!(5, stats.executed);
+        assert_eq!(2, stats.failed);
+        assert_eq!(2, logs.len());
+        assert!(logs[0].starts_with("ERROR!") && logs[0].contains("TX:4 to assert of 15 for user:1 did not succeed because the available balance is 10."));
+        assert!(logs[1].starts_with("ERROR!") && logs[1].contains("TX:5 to assert of 0 for user:2 did not succeed because"));
+
+        // under Mode::Strict, a mismatch aborts before the rest of the file
+        let options = HandlerOptions { mode: Mode::Strict, ..HandlerOptions::default() };
+        let (clients, stats) = handle_with_stats(commands(), options).await;
+        assert!(stats.halted);
+        assert_eq!(4, stats.executed);
+        assert_balances_eq(clients.get(&1).unwrap(), dec!(10), dec!(5), dec!(15));
+    }
+
+    #[tokio::test]
+    async fn test_deposit_after_reverse_chargeback() {
+        let options = HandlerOptions { audit: true, ..HandlerOptions::default() };
+        let data = Arc::new(Mutex::new(HashMap::<ClientID, Box<ClientData>>::new()));
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for cmd in [
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 1, 1, None),
+            Command::new(CommandType::Chargeback, 1, 1, None),
+            // rejected, as the account is frozen by the chargeback
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(4))),
+            Command::new(CommandType::ReverseChargeback, 1, 1, None),
+            // the reversal unfroze the account, so a later deposit, even reusing the rejected tx, applies
+            Command::new(CommandType::Deposit, 1, 2, Some(dec!(4))),
+            // the reversed deposit is still in the history, so its tx can be neither deposited again nor charged back twice
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Resolve, 1, 1, None),
+        ] {
+            tx.send(cmd).await.unwrap();
+        }
+        drop(tx);
+        let stats = super::handle_commands(data.clone(), rx, options).await.unwrap();
+
+        let clients = data.lock().unwrap();
+        let client = clients.get(&1).unwrap();
+        assert!(!client.is_locked());
+        assert_balances_eq(client, dec!(14), dec!(0), dec!(14));
+        assert_eq!(6, stats.executed);
+        assert_eq!(2, stats.failed);
+        assert!(stats.ledger.unwrap().verify_conservation(&*clients));
+    }
+
+    #[tokio::test]
+    async fn test_ignored_amount() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 2, 43, Some(dec!(10))),
+            Command::new(CommandType::Dispute, 2, 43, Some(dec!(17.0))),
+        ];
+
+        logger::capture::start();
+        let clients = handle(commands(), HandlerOptions::default()).await;
+        let logs = logger::capture::take();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Info:"));
+        assert!(logs[0].contains("TX:43 to dispute for user:2 carries an amount of 17.0, which is ignored"));
+
+        // the disputed deposit's ammount is held, not the dispute's
+        assert_eq!(dec!(10), clients.get(&2).unwrap().get_held_wealth());
+        assert_eq!(dec!(0), clients.get(&2).unwrap().get_wealth());
+
+        logger::capture::start();
+        handle(commands(), HandlerOptions { mode: Mode::Strict, ..HandlerOptions::default() }).await;
+        let logs = logger::capture::take();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
+    }
+
+    #[tokio::test]
+    async fn test_mode() {
+        let commands = || vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(50))),
+            Command::new(CommandType::Dispute, 9, 1, None),
+            Command::new(CommandType::Deposit, 1, 3, Some(dec!(5))),
+        ];
+
+        // insufficient funds and an unknown client are logged and skipped under lenient
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions::default()).await;
+        assert_eq!(dec!(15), clients.get(&1).unwrap().get_wealth());
+        assert_eq!(2, stats.failed);
+        assert!(!stats.halted);
+
+        // the first failure halts processing under strict
+        logger::capture::start();
+        let (clients, stats) = handle_with_stats(commands(), HandlerOptions { mode: Mode::Strict, ..HandlerOptions::default() }).await;
+        let logs = logger::capture::take();
+        assert_eq!(dec!(10), clients.get(&1).unwrap().get_wealth());
+        assert_eq!(1, stats.failed);
+        assert!(stats.halted);
+        assert!(logs.last().unwrap().contains("Processing halted at TX:2 for user:1"));
+    }
+
+    #[tokio::test]
+    async fn test_drain_handler() {
+        let write = |data| async move {
+            let mut output: Vec<u8> = Vec::new();
+            crate::transaction_csv::write_csv(data, &mut output, &crate::transaction_csv::WriteOptions::default()).await.unwrap();
+            let mut lines: Vec<String> = std::str::from_utf8(&output).unwrap().lines().map(str::to_owned).collect();
+            lines.sort();
+            lines
+        };
+        let commands = || [
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 2, 2, Some(dec!(3))),
+            Command::new(CommandType::Withdraw, 1, 3, Some(dec!(4))),
+        ];
+        let expected = vec!["1,6,0.0000,6,false", "2,3,0.0000,3,false", "client,available,held,total,locked"];
+
+        // without an interrupt, the handler drains the channel once the sender is dropped
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for cmd in commands() {
+            tx.send(cmd).await.unwrap();
+        }
+        drop(tx);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let mut handler = tokio::spawn(super::handle_commands(data.clone(), rx, HandlerOptions { cancel: Some(cancel.clone()), ..HandlerOptions::default() }));
+        let (handled, interrupted) = super::drain_handler(&mut handler, &cancel, std::future::pending::<()>()).await;
+        assert!(!interrupted);
+        assert_eq!(3, handled.unwrap().unwrap().executed);
+        assert_eq!(expected, write(data).await);
+
+        // an interrupt cancels a handler whose channel never closes, keeping the commands it executed, which are still written
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for cmd in commands() {
+            tx.send(cmd).await.unwrap();
+        }
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let data = Arc::new(Mutex::new(HashMap::<ClientID, Box<ClientData>>::new()));
+        let mut handler = tokio::spawn(super::handle_commands(data.clone(), rx, HandlerOptions { cancel: Some(cancel.clone()), ..HandlerOptions::default() }));
+        // interrupt once the commands sent were executed
+        let executed = {
+            let data = data.clone();
+            async move {
+                while data.lock().unwrap().get(&1).is_none_or(|client| client.get_wealth() != dec!(6)) {
+                    tokio::task::yield_now().await;
                 }
-            },
-            Ok(()) => {},
+            }
+        };
+        logger::capture::start();
+        let (handled, interrupted) = super::drain_handler(&mut handler, &cancel, executed).await;
+        let logs = logger::capture::take();
+        assert!(interrupted);
+        assert!(logs[0].starts_with("Warning!  Interrupted; handling stops after the current batch"));
+        let stats = handled.unwrap().unwrap();
+        assert!(stats.cancelled);
+        assert_eq!(3, stats.executed);
+        assert_eq!(expected, write(data).await);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let checkpoint_file = tempfile::NamedTempFile::new().unwrap();
+
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let options = HandlerOptions {
+            cancel: Some(cancel.clone()),
+            checkpoint_path: Some(checkpoint_file.path().to_str().unwrap().to_owned()),
+            checkpoint_interval: 1000,
+            ..HandlerOptions::default()
+        };
+        let handler = tokio::spawn(super::handle_commands(data.clone(), rx, options));
+
+        tx.send(Command::new(CommandType::Deposit, 1, 1, Some(dec!(10)))).await.unwrap();
+        tx.send(Command::new(CommandType::Withdraw, 1, 2, Some(dec!(4)))).await.unwrap();
+        while data.lock().unwrap().get(&1).map(|client| client.get_wealth()) != Some(dec!(6)) {
+            tokio::task::yield_now().await;
         }
+
+        // the sender stays open, so only the cancellation can stop the handler
+        cancel.cancel();
+        let stats = tokio::time::timeout(std::time::Duration::from_secs(5), handler).await.unwrap().unwrap().unwrap();
+        assert!(stats.cancelled);
+        assert_eq!(2, stats.executed);
+
+        // commands sent after the cancellation are not executed
+        assert!(tx.send(Command::new(CommandType::Deposit, 1, 3, Some(dec!(1)))).await.is_err());
+        assert_eq!(dec!(6), data.lock().unwrap().get_mut(&1).unwrap().get_wealth());
+
+        // the state at the cancellation is flushed to the checkpoint
+        let checkpoint = crate::checkpoint::load(checkpoint_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(2, checkpoint.commands);
+        assert_eq!(dec!(6), checkpoint.clients[&1].get_wealth());
     }
-    // this condition should never be reached because deposit commands should always have a value
-    else {
-        let msg = msg_build("deposit", "the transaction did not contain the ammount", &cmd.get_transaction_id(), &cmd.get_client_id());
-        logger::error( &msg );
+
+    #[tokio::test]
+    async fn test_unhandled() {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for transaction_id in 1..=10 {
+            tx.send(Command::new(CommandType::Deposit, 1, transaction_id, Some(dec!(1)))).await.unwrap();
+        }
+        assert_eq!(10, super::channel_depth(&tx));
+
+        // cancelled before the handler starts, so every command sent is left in the channel
+        let cancel = tokio_util::sync::CancellationToken::new();
+        cancel.cancel();
+        let options = HandlerOptions { cancel: Some(cancel), ..HandlerOptions::default() };
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let stats = super::handle_commands(data, rx, options).await.unwrap();
+        assert!(stats.cancelled);
+        assert_eq!(0, stats.executed);
+        assert_eq!(10, stats.unhandled);
+
+        // a halt leaves the rest of its batch unhandled
+        let (_, stats) = handle_with_stats(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(1))),
+            Command::new(CommandType::Withdraw, 1, 2, Some(dec!(5))),
+            Command::new(CommandType::Deposit, 1, 3, Some(dec!(1))),
+            Command::new(CommandType::Deposit, 1, 4, Some(dec!(1))),
+        ], HandlerOptions { mode: Mode::Strict, ..HandlerOptions::default() }).await;
+        assert!(stats.halted);
+        assert_eq!(2, stats.unhandled);
+
+        // none are left when every command is handled
+        let (_, stats) = handle_with_stats(vec![Command::new(CommandType::Deposit, 1, 1, Some(dec!(1)))], HandlerOptions::default()).await;
+        assert_eq!(0, stats.unhandled);
     }
-}
 
+    #[tokio::test]
+    async fn test_progress() {
+        let commands: Vec<Command> = (1..=25).map(|tx| Command::new(CommandType::Deposit, 1, tx, Some(dec!(1)))).collect();
+
+        logger::capture::start();
+        handle(commands, HandlerOptions { progress_interval: Some(10), ..HandlerOptions::default() }).await;
+        let logs = logger::capture::take();
+        assert_eq!(2, logs.len());
+        assert!(logs[0].starts_with("Info:  Processed 10 commands in "));
+        assert!(logs[1].starts_with("Info:  Processed 20 commands in "));
+
+        // under a clock which advances 1.5s with each read, progress is logged at the same times on every run
+        let commands: Vec<Command> = (1..=25).map(|tx| Command::new(CommandType::Deposit, 1, tx, Some(dec!(1)))).collect();
+        let clock = Arc::new(crate::clock::FixedClock::new(std::time::Duration::from_millis(1500)));
+        logger::capture::start();
+        handle(commands, HandlerOptions { progress_interval: Some(10), clock: Some(clock), ..HandlerOptions::default() }).await;
+        assert_eq!(vec!["Info:  Processed 10 commands in 1.50s.", "Info:  Processed 20 commands in 3.00s."], logger::capture::take());
+
+        // no progress is logged by default
+        let commands: Vec<Command> = (1..=25).map(|tx| Command::new(CommandType::Deposit, 1, tx, Some(dec!(1)))).collect();
+        logger::capture::start();
+        handle(commands, HandlerOptions::default()).await;
+        assert!(logger::capture::take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_all() {
+        let clients = super::process_all(tokio_stream::iter(vec![
+            Command::new(CommandType::Deposit, 1, 1, Some(dec!(10))),
+            Command::new(CommandType::Deposit, 2, 2, Some(dec!(4))),
+            Command::new(CommandType::Withdraw, 1, 3, Some(dec!(2.5))),
+            Command::new(CommandType::Dispute, 2, 2, None),
+            Command::new(CommandType::Chargeback, 2, 2, None),
+        ]), HandlerOptions::default()).await;
+
+        assert_eq!(2, clients.len());
+        assert_eq!(dec!(7.5), clients[&1].get_wealth());
+        assert!(!clients[&1].is_locked());
+        assert_eq!(dec!(0), clients[&2].get_total());
+        assert!(clients[&2].is_locked());
+    }
+}
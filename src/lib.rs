@@ -0,0 +1,40 @@
+//! # transaction parser library
+//! 
+//! The transaction engine behind the transaction_parser binary.
+//! 
+//! Commands are parsed from a transaction csv, or a directory of them, into a channel by `parse_csv` or `parse_dir`, executed against client data by `handle_commands`, and the resulting accounts are output by `write_csv`.
+//! Consumers may drive the engine with their own channel, and their own data store through the `ClientStore` trait, rather than a file, or hand a stream of commands to `process_all`, which owns and returns the client data.
+//! 
+//! # tests
+//! 
+//! transaction_csv_tests
+//! arguments_tests
+//! checkpoint_tests
+//! client_data_tests
+//! command_handler_tests
+//! command_tests
+//! logger_tests
+//! manifest_tests
+//! 
+//! Helpers shared by the tests, such as `assert_balances_eq`, are in the test_support module.
+//! 
+
+pub mod arguments;
+pub mod checkpoint;
+pub mod client_data;
+pub mod client_store;
+pub mod clock;
+pub mod command;
+pub mod command_handler;
+pub mod logger;
+pub mod manifest;
+pub mod mode;
+pub mod transaction_csv;
+
+#[cfg(test)]
+mod test_support;
+
+pub use client_store::ClientStore;
+pub use command_handler::{handle_commands, process_all};
+pub use mode::Mode;
+pub use transaction_csv::{parse_csv, parse_dir, parse_tcp, write_csv, write_statements};
@@ -1,19 +1,127 @@
 use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 // Since this is &str, a::b::log and a::c::log would not cause duplication of the string.
 //  That isn't necessarily true of other data types.
 //  str cannot be static or const directly for now because it is unsized which is why it is an exception.
-const WARNING_PREFIX: &'static str = "Warning! ";
-const ERROR_PREFIX: &'static str = "ERROR! ";
+const WARNING_PREFIX: &str = "Warning! ";
+const ERROR_PREFIX: &str = "ERROR! ";
+const INFO_PREFIX: &str = "Info: ";
+
+// Counts of what was logged, across every thread, for summaries such as a manifest
+static WARNINGS: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+// The count of warnings written before further warnings are suppressed; u64::MAX is unlimited
+static MAX_WARNINGS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+pub fn info(msg: &str) {
+    log(INFO_PREFIX, msg, "info");
+}
 
 pub fn warning(msg: &str) {
-    if let Err(err) = std::io::stderr().write_all(format!( "\n{} {}\n", WARNING_PREFIX, msg).as_bytes()) {
-        panic!("An error occured while trying to print a warning: {}", err);
-    };
+    let count = WARNINGS.fetch_add(1, Ordering::Relaxed) + 1;
+    limited_warning(count, MAX_WARNINGS.load(Ordering::Relaxed), msg);
 }
 
 pub fn error(msg: &str) {
-    if let Err(err) = std::io::stderr().write_all(format!( "\n{} {}\n", ERROR_PREFIX, msg).as_bytes()) {
-        panic!("An error occured while trying to print an error: {}", err);
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    log(ERROR_PREFIX, msg, "an error");
+}
+
+/// The count of warnings logged so far by the process, including those suppressed
+pub fn warnings() -> u64 {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Limits the count of warnings written, so a file with millions of identical anomalies does not flood stderr
+/// 
+/// Once the limit is reached, a single line notes that further warnings are suppressed; they are still counted.
+/// None writes every warning, which is the default.
+pub fn set_max_warnings(max: Option<u64>) {
+    MAX_WARNINGS.store(max.unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// The count of warnings logged so far which were suppressed by the limit of set_max_warnings
+pub fn suppressed_warnings() -> u64 {
+    warnings().saturating_sub(MAX_WARNINGS.load(Ordering::Relaxed))
+}
+
+/// The count of errors logged so far by the process
+pub fn errors() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+// Writes the count'th warning if it is within the max, or notes that further warnings are suppressed if it is the first beyond it
+fn limited_warning(count: u64, max: u64, msg: &str) {
+    if count <= max {
+        log(WARNING_PREFIX, msg, "a warning");
+    }
+    else if Some(count) == max.checked_add(1) {
+        log(WARNING_PREFIX, &format!("{} warnings have been logged; further warnings are suppressed.", max), "a warning");
+    }
+}
+
+fn log(prefix: &str, msg: &str, kind: &str) {
+    let line = format!( "{} {}", prefix, msg);
+    #[cfg(test)]
+    capture::record(&line);
+    if let Err(err) = std::io::stderr().write_all(format!( "\n{}\n", line).as_bytes()) {
+        panic!("An error occured while trying to print {}: {}", kind, err);
     };
 }
+
+/// Lets tests inspect what was logged on their thread
+/// 
+/// Tests on the default tokio runtime run spawned tasks on their own thread, so their logs are captured as well.
+#[cfg(test)]
+pub(crate) mod capture {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    /// Starts capturing log lines on this thread, discarding any captured before
+    pub(crate) fn start() {
+        CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    }
+
+    /// Stops capturing, returning the lines captured since start
+    pub(crate) fn take() -> Vec<String> {
+        CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+    }
+
+    pub(super) fn record(line: &str) {
+        CAPTURED.with(|captured| {
+            if let Some(lines) = captured.borrow_mut().as_mut() {
+                lines.push(line.to_owned());
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod logger_tests {
+    #[test]
+    fn test_limited_warning() {
+        let max = 3;
+        super::capture::start();
+        for count in 1..=max + 5 {
+            super::limited_warning(count, max, &format!("warning {}", count));
+        }
+        let logs = super::capture::take();
+
+        // the warnings up to the max are written, then a single line notes the rest are suppressed
+        assert_eq!(max as usize + 1, logs.len());
+        assert!(logs[2].ends_with("warning 3"));
+        assert!(logs[3].starts_with("Warning!") && logs[3].ends_with("3 warnings have been logged; further warnings are suppressed."));
+
+        // without a limit, every warning is written
+        super::capture::start();
+        for count in 1..=max + 5 {
+            super::limited_warning(count, u64::MAX, "warning");
+        }
+        assert_eq!(max as usize + 5, super::capture::take().len());
+    }
+}
@@ -4,65 +4,351 @@
 //! 
 //! Output is generated to stdout; logging is performed to stderr
 //! 
-//! # tests
+//! With --listen, the transactions are read from a single TCP connection instead of a file, and the output is written once the client closes it.
+//! With --dir, the transactions are read from every csv file in a directory, in order of file name, into the same client data.
 //! 
-//! transaction_csv_tests
-//! client_data_tests
+//! On Ctrl-C, parsing stops, the commands already parsed are executed, and the output is written before exiting with an error.
+//! A Ctrl-C while those commands are executed stops handling after the current batch, and one while the output is written exits at once.
+//! Such output reflects only the commands processed before the interrupt.
+//! 
+//! The transaction engine itself lives in the library crate; this binary only wires it to the command line.
+//! 
+//! # Exit codes
+//! 
+//! 0       success
+//! 1       a usage error, such as a missing file argument, or another failure, such as writing the output or an interrupt
+//! 2       the transaction csv could not be parsed, such as a missing file, or an anomalous row under --mode strict
+//! 3       handling commands halted, such as after --max-chargebacks, or on a failed command under --mode strict
+//! 
+//! Output is still written after a parse or handler error, reflecting the commands processed before it.
+//! With --manifest, the manifest is written last, so its exit status is the code the run exits with.
 //! 
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::sync::{Arc, Mutex};
 
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use transaction_parser::{arguments, checkpoint, client_data, command, logger, manifest};
+use transaction_parser::command_handler::{channel_depth, drain_handler, HandleError, HandlerOptions, ProcessingStats};
+use transaction_parser::transaction_csv::{ParseOptions, TeeWriter, WriteOptions};
 
-mod client_data;
-mod command;
-mod command_handler;
-mod logger;
-mod transaction_csv;
+// The count of commands between progress logs, when --progress is given
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+// The count of commands between checkpoints, when --checkpoint is given
+const CHECKPOINT_INTERVAL: u64 = 1_000_000;
+
+// Exit codes; see the module documentation
+const EXIT_FAILURE: i32 = 1;
+const EXIT_PARSE_ERROR: i32 = 2;
+const EXIT_HANDLER_ERROR: i32 = 3;
 
 // In this program, thread count shouldn't cause issues on most computers; however, to be scalable we spawn async threads.
 
-#[tokio::main]
-async fn main() {
+fn main() {
 
-    let (tx, rx) = mpsc::channel::<command::Command>(16);
+    // Get the file argument and flags from args
+    let input_args: Vec<String> = env::args().skip(1).collect();
+    let arguments = match arguments::parse(&input_args) {
+        Ok(arguments) => arguments,
+        Err(msg) => {
+            logger::error( &msg );
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
 
-    // Get the file argument from args
-    let input_args: Vec<String> = env::args().collect();
-    let file_path: &String = match input_args.get(1) {
-        Some(arg) => {
-            arg
-        },
-        None => {
-            logger::error( "Transaction Parser expects a file path for the transactions csv file.  Example: `./transaction_parser \"C:\\input.csv\"`" );
-            std::process::exit(1);
+    // The runtime is built by hand, rather than by #[tokio::main], so --threads can size it for containers with CPU limits.
+    // Commands are handled by a single task, so the worker count only matters once the handler is parallelized.
+    let worker_threads = arguments.threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cores| cores.get()));
+    let runtime = match tokio::runtime::Builder::new_multi_thread().worker_threads(worker_threads).enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            logger::error( &format!("Starting the runtime with {} threads failed: {}", worker_threads, err) );
+            std::process::exit(EXIT_FAILURE);
         }
     };
+    runtime.block_on(run(arguments));
+}
+
+// Parses the transactions, handles the commands, and writes the output, then exits with the run's code
+async fn run(arguments: arguments::Arguments) {
+
+    let (tx, rx) = mpsc::channel::<command::Command>(16);
+    // observes how many commands the handler trails the parser by; it must be dropped before awaiting the handler, which otherwise never sees the channel close
+    let depth_probe = tx.clone();
+
+    logger::set_max_warnings(arguments.max_warnings);
 
     // Create a client data object container
-    // If many many clients are present, this may need to be re-engineered to handle clients in a DB
-    let data = Arc::new(Mutex::new(HashMap::<client_data::ClientID, Box<client_data::ClientData>>::new()));
+    // If many many clients are present, a ClientStore backed by a DB could stand in for this HashMap
+    // Resuming starts from the checkpoint's client data, skipping the commands it already reflects
+    // Seeding starts from the balances of a previous run's output, processing every command
+    let (clients, resumed_commands) = match (&arguments.resume, &arguments.seed_from) {
+        (Some(resume_path), _) => match checkpoint::load(resume_path) {
+            Ok(checkpoint) => (checkpoint.clients, checkpoint.commands),
+            Err(err) => {
+                logger::error(format!("Resuming from {} failed: {}", resume_path, err).as_str());
+                std::process::exit(EXIT_FAILURE);
+            }
+        },
+        // read_seed logs its own errors
+        (None, Some(seed_path)) => match transaction_parser::transaction_csv::read_seed(seed_path, arguments.frozen_policy).await {
+            Ok(clients) => (clients, 0),
+            Err(_) => std::process::exit(EXIT_FAILURE),
+        },
+        (None, None) => (HashMap::<client_data::ClientID, Box<client_data::ClientData>>::new(), 0),
+    };
+    let seeded_clients = if arguments.seed_from.is_some() { clients.keys().copied().collect() } else { HashSet::new() };
+    let data = Arc::new(Mutex::new(clients));
+
+    // the first Ctrl-C cancels parsing only, so the handler still executes the commands already parsed; another cancels the handler
+    let cancel = CancellationToken::new();
+    let handler_cancel = CancellationToken::new();
+
+    let parse_options = ParseOptions {
+        delimiter: arguments.delimiter,
+        reorder_window: arguments.reorder_window,
+        reorder_by_time: arguments.reorder_by_time,
+        column_map: arguments.column_map.clone(),
+        mode: arguments.mode,
+        skip_commands: resumed_commands,
+        units: arguments.units,
+        cancel: Some(cancel.clone()),
+        open_retries: arguments.open_retries,
+        max_record_bytes: arguments.max_record_bytes,
+        strip_currency: arguments.strip_currency,
+        schema: arguments.schema,
+        trim: arguments.trim,
+        shards: if arguments.parallel_parse { std::thread::available_parallelism().map_or(1, |cores| cores.get()) } else { 1 },
+    };
 
     // split concurrent asynchronous processes
-    let parse = tokio::spawn(transaction_csv::parse_csv(
-        file_path.clone(), 
-        tx
-    ) );
-    let handle = tokio::spawn(command_handler::handle_commands(data.clone(), rx));
+    // with --listen, the transactions are read from a single TCP connection rather than a file
+    let mut parse = match &arguments.listen {
+        Some(addr) => match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                logger::info(format!("Listening for transactions on {}.", addr).as_str());
+                tokio::spawn(transaction_parser::parse_tcp(listener, tx, parse_options))
+            },
+            Err(err) => {
+                logger::error(format!("Listening on {} failed: {}", addr, err).as_str());
+                std::process::exit(EXIT_FAILURE);
+            }
+        },
+        None => match &arguments.dir {
+            Some(dir) => tokio::spawn(transaction_parser::parse_dir(dir.clone(), tx, parse_options)),
+            None => tokio::spawn(transaction_parser::parse_csv(arguments.file_path.clone(), tx, parse_options)),
+        },
+    };
+    let mut handle = tokio::spawn(transaction_parser::handle_commands(
+        data.clone(),
+        rx,
+        HandlerOptions {
+            max_amount: arguments.max_amount,
+            strict_tx: arguments.strict_tx,
+            strict_frozen: arguments.strict_frozen,
+            max_chargebacks: arguments.max_chargebacks,
+            audit: arguments.audit,
+            include_empty: arguments.include_empty,
+            mode: arguments.mode,
+            progress_interval: arguments.progress.then_some(PROGRESS_INTERVAL),
+            checkpoint_path: arguments.checkpoint.clone(),
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            resumed_commands,
+            frozen_policy: arguments.frozen_policy,
+            amount_policy: arguments.amount_policy,
+            anomaly_report: arguments.anomaly_report,
+            amount_stats: arguments.amount_stats,
+            client_range: arguments.client_range.clone(),
+            dedupe_deposits: arguments.dedupe_deposits,
+            allow_negative_adjustment: arguments.allow_negative_adjustment,
+            max_open_disputes: arguments.max_open_disputes,
+            disabled: arguments.disabled.clone(),
+            // the handler logs each anomaly to stderr, so the binary has no need to collect them
+            collect_anomalies: false,
+            seeded_clients,
+            audit_log_path: arguments.audit_log.clone(),
+            cancel: Some(handler_cancel.clone()),
+            clock: None,
+        }
+    ));
 
     // Join threads
     
-    if let Err(err) = parse.await {
-        logger::error(format!("Parser thread err: {:?}", err).as_str());
+    // a parser which failed, such as on an anomaly in strict mode, leaves the commands already sent to be handled
+    let (handled, interrupted, parse_failed) = tokio::select! {
+        parsed = &mut parse => {
+            let parse_failed = match parsed {
+                Ok(Ok(())) => false,
+                // parse_csv logs its own errors
+                Ok(Err(_)) => true,
+                Err(err) => {
+                    logger::error(format!("Parser thread err: {:?}", err).as_str());
+                    true
+                },
+            };
+            let waiting = channel_depth(&depth_probe);
+            drop(depth_probe);
+            let draining = std::time::Instant::now();
+            let (handled, interrupted) = drain_handler(&mut handle, &handler_cancel, tokio::signal::ctrl_c()).await;
+            logger::info(format!("Parsing finished with {} commands waiting in the channel; the handler took {:.2}s to drain them.", waiting, draining.elapsed().as_secs_f64()).as_str());
+            (handled, interrupted, parse_failed)
+        },
+        _ = tokio::signal::ctrl_c() => {
+            logger::warning("Interrupted; the output will reflect only the commands processed so far. Interrupt again to stop handling the commands already parsed.");
+            cancel.cancel();
+            // the parser's result is only that it was cancelled
+            let _ = parse.await;
+            drop(depth_probe);
+            (drain_handler(&mut handle, &handler_cancel, tokio::signal::ctrl_c()).await.0, true, false)
+        },
+    };
+
+    // the client data is settled, so a Ctrl-C while writing the output stops the run at once
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            logger::error("Interrupted while writing the output, which may be incomplete.");
+            std::process::exit(EXIT_FAILURE);
+        }
+    });
+
+    // a halted run still writes its partial output, but exits with an error
+    let (stats, handle_failed) = match handled {
+        Ok(Ok(stats)) => (Some(stats), false),
+        // the handler logs why it halted
+        Ok(Err(HandleError::Halted(stats))) => (Some(*stats), true),
+        Ok(Err(err)) => {
+            logger::error(format!("Handling commands failed: {}", err).as_str());
+            (None, true)
+        },
+        Err(err) => {
+            logger::error(format!("Handler thread err: {:?}", err).as_str());
+            (None, true)
+        },
+    };
+    if let Some(stats) = &stats {
+        logger::info(format!("Processing summary: {}", stats).as_str());
+        if stats.unhandled > 0 {
+            logger::info(format!("{} commands were left unhandled in the channel.", stats.unhandled).as_str());
+        }
     }
-    if let Err(err) = handle.await {
-        logger::error(format!("Handler thread err: {:?}", err).as_str());
+
+    // save a final checkpoint, if requested, so an interrupted or halted run can be resumed
+    if let (Some(checkpoint_path), Some(stats)) = (&arguments.checkpoint, &stats) {
+        if let Err(err) = checkpoint::save(checkpoint_path, resumed_commands + stats.handled(), &*data.lock().unwrap()) {
+            logger::error(format!("Saving a checkpoint to {} failed: {}", checkpoint_path, err).as_str());
+        }
+    }
+
+    // check the books, if requested
+    if let Some(ledger) = stats.as_ref().and_then(|stats| stats.ledger.as_ref()) {
+        ledger.verify_conservation(&*data.lock().unwrap());
+    }
+
+    // flag unusual clients, if requested
+    if let Some(activity) = stats.as_ref().and_then(|stats| stats.activity.as_ref()) {
+        activity.log_anomalies();
+    }
+
+    // profile the ammounts, if requested
+    if let Some(amounts) = stats.as_ref().and_then(|stats| stats.amounts.as_ref()) {
+        amounts.log_summary();
     }
 
     // write output
     
-    transaction_csv::write_csv(data.clone()).await;
+    let write_options = WriteOptions {
+        with_counts: arguments.with_counts,
+        delimiter: if arguments.match_delimiter { arguments.delimiter } else { b',' },
+        quiet_rounding: arguments.quiet_rounding,
+        stream_output: arguments.stream_output,
+        with_totals: arguments.with_totals,
+        clamp_negative: arguments.clamp_negative,
+        locked_only: arguments.locked_only,
+        format: arguments.format.clone(),
+        columns: arguments.columns.clone(),
+        bool_format: arguments.bool_format,
+    };
+    let written = match &arguments.tee {
+        Some(tee_path) => write_tee(data.clone(), tee_path, &write_options).await,
+        None => match transaction_parser::write_csv(data.clone(), &mut tokio::io::stdout(), &write_options).await {
+            Ok(()) => true,
+            Err(err) => {
+                logger::error(format!("Writing output failed: {}", err).as_str());
+                false
+            },
+        },
+    };
+    if !written {
+        finish(&arguments, stats.as_ref(), EXIT_FAILURE);
+    }
 
+    // write statements, if requested
+    
+    if let Some(statements_path) = &arguments.statements {
+        let result = match tokio::fs::File::create(statements_path).await {
+            Ok(mut file) => transaction_parser::write_statements(data.clone(), &mut file).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            logger::error(format!("Writing statements to {} failed: {}", statements_path, err).as_str());
+            finish(&arguments, stats.as_ref(), EXIT_FAILURE);
+        }
+    }
+
+    let exit_status = if parse_failed {
+        EXIT_PARSE_ERROR
+    }
+    else if handle_failed {
+        EXIT_HANDLER_ERROR
+    }
+    else if interrupted {
+        EXIT_FAILURE
+    }
+    else {
+        0
+    };
+    finish(&arguments, stats.as_ref(), exit_status);
+
+}
+
+// Writes the output to stdout and to a file at the path, returning whether both were written
+// Each failure is logged with the sink it concerns; a file which cannot be created, or fails part way, does not stop the output to stdout.
+async fn write_tee(data: Arc<Mutex<HashMap<client_data::ClientID, Box<client_data::ClientData>>>>, tee_path: &str, write_options: &WriteOptions) -> bool {
+    let mut sinks: Vec<(String, Box<dyn tokio::io::AsyncWrite + Unpin + Send>)> = vec![("stdout".to_owned(), Box::new(tokio::io::stdout()))];
+    let created = match tokio::fs::File::create(tee_path).await {
+        Ok(file) => {
+            sinks.push((tee_path.to_owned(), Box::new(file)));
+            true
+        },
+        Err(err) => {
+            logger::error(format!("Creating {} failed: {}", tee_path, err).as_str());
+            false
+        },
+    };
+    let mut output = TeeWriter::new(sinks);
+    // the tee logs each sink which fails; it only fails itself once every sink has
+    let written = transaction_parser::write_csv(data, &mut output, write_options).await.is_ok();
+    created && written && output.failed().is_empty()
+}
+
+// Notes any warnings suppressed by --max-warnings, and writes the manifest, if requested, then exits with the code
+// A manifest which cannot be written fails the run, as a pipeline relying on it would otherwise find none.
+fn finish(arguments: &arguments::Arguments, stats: Option<&ProcessingStats>, exit_status: i32) -> ! {
+    let suppressed = logger::suppressed_warnings();
+    if suppressed > 0 {
+        logger::info(format!("{} further warnings were suppressed, of {} in total.", suppressed, logger::warnings()).as_str());
+    }
+    if let Some(manifest_path) = &arguments.manifest {
+        let input = arguments.listen.as_deref().or(arguments.dir.as_deref()).unwrap_or(&arguments.file_path);
+        let manifest = manifest::Manifest::new(input, stats, logger::warnings(), logger::errors(), exit_status);
+        if let Err(err) = manifest::save(manifest_path, &manifest) {
+            logger::error(format!("Writing the manifest to {} failed: {}", manifest_path, err).as_str());
+            std::process::exit(EXIT_FAILURE);
+        }
+    }
+    std::process::exit(exit_status);
 }
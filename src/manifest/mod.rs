@@ -0,0 +1,153 @@
+//! # manifest module
+//! This module separates logic for describing a run in a manifest file, so a pipeline can check its outcome without reading the logs.
+//!
+//! A manifest is JSON holding the input, how the commands fared, the commands of each type, the count of warnings and errors logged, and the exit status.
+//!
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command_handler::ProcessingStats;
+
+/// A summary of a run, as written alongside its output
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct Manifest {
+    /// the transactions csv, the directory of them, or the address listened on for a TCP connection
+    pub input: String,
+    /// commands handled, however they fared
+    pub commands: u64,
+    pub executed: u64,
+    pub failed: u64,
+    pub unknown_client: u64,
+    pub tx_not_found: u64,
+    pub chargebacks: u64,
+    pub retried: u64,
+    pub disabled: u64,
+    /// commands received of each type, by the type's canonical name
+    pub by_type: BTreeMap<String, u64>,
+    /// warnings logged by the run
+    pub warnings: u64,
+    /// errors logged by the run
+    pub errors: u64,
+    pub halted: bool,
+    pub cancelled: bool,
+    /// the code the run exits with
+    pub exit_status: i32,
+}
+
+impl Manifest {
+    /// Builds a manifest of a run
+    ///
+    /// Without stats, as when handling commands failed outright, every count is zero.
+    ///
+    /// # Arguments
+    ///
+    /// input               the transactions csv, the directory of them, or the address listened on
+    /// stats               how the commands fared, if handling them finished
+    /// warnings            the count of warnings logged
+    /// errors              the count of errors logged
+    /// exit_status         the code the run exits with
+    ///
+    pub fn new(input: &str, stats: Option<&ProcessingStats>, warnings: u64, errors: u64, exit_status: i32) -> Manifest {
+        let manifest = Manifest { input: input.to_owned(), warnings, errors, exit_status, ..Manifest::default() };
+        match stats {
+            Some(stats) => Manifest {
+                commands: stats.handled(),
+                executed: stats.executed,
+                failed: stats.failed,
+                unknown_client: stats.unknown_client,
+                tx_not_found: stats.tx_not_found,
+                chargebacks: stats.chargebacks,
+                retried: stats.retried,
+                disabled: stats.disabled,
+                by_type: stats.by_type.iter().map(|(command_type, count)| (command_type.name().to_owned(), *count)).collect(),
+                halted: stats.halted,
+                cancelled: stats.cancelled,
+                ..manifest
+            },
+            None => manifest,
+        }
+    }
+}
+
+/// Saves a manifest, replacing any file already there
+///
+/// # Arguments
+///
+/// path                the path of the manifest file
+/// manifest            the summary of the run
+///
+/// # Return Value
+///
+/// Err(std::io::Error)     serializing or writing the manifest failed
+/// Ok(())
+///
+pub fn save(path: &str, manifest: &Manifest) -> Result<(), io::Error> {
+    let content = serde_json::to_vec_pretty(manifest)?;
+    std::fs::write(path, content)
+}
+
+#[cfg(test)]
+mod manifest_tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tempfile::tempdir;
+
+    use crate::client_data::{ClientData, ClientID};
+    use crate::command_handler::HandlerOptions;
+    use crate::transaction_csv::ParseOptions;
+
+    use super::Manifest;
+
+    const TRANSACTIONS: &str = concat!(
+        "type, client, tx, amount\n",
+        "deposit, 1, 1, 10.0\n",
+        "deposit, 2, 2, 5.5\n",
+        "withdrawal, 1, 3, 2.5\n",
+        "withdrawal, 2, 4, 8\n",
+        "dispute, 1, 1,\n",
+        "chargeback, 1, 1,\n",
+        "dispute, 3, 9,\n",
+    );
+
+    #[tokio::test]
+    async fn test_manifest() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("transactions.csv");
+        std::fs::write(&input, TRANSACTIONS).unwrap();
+        let input = input.to_str().unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let data = Arc::new(Mutex::new(HashMap::<ClientID, Box<ClientData>>::new()));
+        let parse = tokio::spawn(crate::transaction_csv::parse_csv(input.to_owned(), tx, ParseOptions::default()));
+        let handle = tokio::spawn(crate::command_handler::handle_commands(data, rx, HandlerOptions::default()));
+        parse.await.unwrap().unwrap();
+        let stats = handle.await.unwrap().unwrap();
+
+        let path = dir.path().join("manifest.json");
+        let path = path.to_str().unwrap();
+        super::save(path, &Manifest::new(input, Some(&stats), 2, 0, 0)).unwrap();
+
+        // the manifest is read back as plain JSON, as a downstream job would
+        let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(input, manifest["input"]);
+        assert_eq!(7, manifest["commands"]);
+        assert_eq!(5, manifest["executed"]);
+        assert_eq!(2, manifest["failed"]);
+        assert_eq!(1, manifest["unknown_client"]);
+        assert_eq!(1, manifest["chargebacks"]);
+        assert_eq!(serde_json::json!({ "chargeback": 1, "deposit": 2, "dispute": 2, "withdrawal": 2 }), manifest["by_type"]);
+        assert_eq!(2, manifest["warnings"]);
+        assert_eq!(0, manifest["exit_status"]);
+        assert_eq!(false, manifest["halted"]);
+
+        // without stats, the manifest still records the input and exit status
+        let failed = Manifest::new(input, None, 0, 1, 3);
+        assert_eq!(0, failed.commands);
+        assert!(failed.by_type.is_empty());
+        assert_eq!(3, failed.exit_status);
+    }
+}
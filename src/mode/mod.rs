@@ -0,0 +1,29 @@
+//! # mode module
+//! This module separates the mode governing how the whole pipeline responds to anomalies in its input.
+//! 
+//! An anomaly is anything which would otherwise cause a row, or command, to be skipped or altered:
+//! a row which cannot be deserialized, a row with more fields than the header, an ammount with too many digits after the decimal,
+//! an ammount above --max-amount, a client outside --client-range, or a command which its account rejects, such as one for an unknown client or with insufficient funds.
+//! 
+//! | anomaly                                   | lenient                  | strict                             |
+//! |-------------------------------------------|--------------------------|------------------------------------|
+//! | parse anomaly                             | logged, skipped or fixed | logged as an error, parsing stops  |
+//! | command rejected, or skipped              | logged, skipped          | logged as an error, handling halts |
+//! | harmless, such as an ammount on a dispute | info                     | warning                            |
+//! 
+//! Harmless oddities do not change what is executed, so they are only reported more loudly under strict.
+//! 
+
+/// How the pipeline responds to anomalies in its input
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Mode {
+    /// anomalies are logged, and the rows or commands they affect are skipped or fixed, so processing carries on
+    #[default]
+    Lenient,
+    /// the first anomaly aborts the run
+    Strict,
+}
+
+impl Mode {
+    pub fn is_strict(&self) -> bool { *self == Mode::Strict }
+}
@@ -0,0 +1,28 @@
+//! # test_support module
+//! This module separates helpers shared by the unit tests.
+//! 
+//! Equal ammounts may carry different scaling factors, such as `33` and `33.0000`, which format differently with `to_string()`.
+//! These helpers compare balances as Decimal values, so assertions hold regardless of the scale an ammount was built with.
+
+use rust_decimal::prelude::Decimal;
+
+use crate::client_data::ClientData;
+
+/// Asserts a client's available, held, and total balances, by value
+/// 
+/// # Arguments
+/// 
+/// client              the client whose balances are checked
+/// available           the expected available funds
+/// held                the expected held funds
+/// total               the expected total funds
+/// 
+#[track_caller]
+pub fn assert_balances_eq(client: &ClientData, available: Decimal, held: Decimal, total: Decimal) {
+    let position = client.position();
+    assert!(
+        position.available == available && position.held == held && position.total == total,
+        "balances (available, held, total) were ({}, {}, {}), but expected ({}, {}, {})",
+        position.available, position.held, position.total, available, held, total
+    );
+}
@@ -10,217 +10,1634 @@
 //! 'assume the transactions occur chronologically in the file'
 //! 
 
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::Decimal;
 use tokio::fs::File;
-use tokio::io::{AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
 use crate::{logger, client_data, command};
+use crate::client_store::ClientStore;
+use crate::mode::Mode;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// The wait before the first retry of opening the file; each later retry waits twice as long as the one before
+const OPEN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Options controlling how parse_csv reads the transaction csv
+pub struct ParseOptions {
+    /// the byte separating fields, a comma by default
+    pub delimiter: u8,
+    /// buffers up to this many commands, dispatching them in tx order to smooth minor out-of-order arrivals; 0 dispatches in file order
+    pub reorder_window: usize,
+    /// orders the commands within the reorder_window by their time column, then by tx; commands without a time go first
+    pub reorder_by_time: bool,
+    /// renames source headers to the canonical `type`, `client`, `tx`, and `amount`; headers which are not keys are kept as they are
+    pub column_map: HashMap<String, String>,
+    /// splits the file into this many shards which are deserialized concurrently, then merged back into file order; 1 parses sequentially
+    pub shards: usize,
+    /// under Mode::Strict, parsing stops at the first anomalous row; under Mode::Lenient, such rows are skipped,
+    /// ammounts with more than command::MAX_SCALE digits after the decimal are rounded, and fields beyond the header are ignored
+    pub mode: Mode,
+    /// the count of commands which are parsed but not sent, such as those already reflected in a checkpoint
+    pub skip_commands: u64,
+    /// the units of the amount column
+    pub units: Units,
+    /// stops parsing once cancelled, between rows, leaving the rest of the file unsent; None parses until the end of the file
+    pub cancel: Option<CancellationToken>,
+    /// which of the headers and fields have their surrounding whitespace trimmed; Trim::All by default
+    pub trim: csv_async::Trim,
+    /// retries opening the file this many times, with exponential backoff, as networked filesystems fail transiently; a missing file is not retried
+    pub open_retries: u32,
+    /// removes a leading currency symbol, and a trailing currency code, from the amount column, so `$5.00` and `5.00 USD` parse as 5.00;
+    /// other decorations still fail to parse
+    pub strip_currency: bool,
+    /// rejects records longer than this many bytes, so a malformed file, such as one with an unterminated quote, cannot exhaust memory;
+    /// with more than one shard the whole file is read into memory first, so the records are still rejected, but memory is not bounded
+    pub max_record_bytes: usize,
+    /// checks the headers, after the column_map renames them, before any row is parsed; Schema::Unchecked by default
+    pub schema: Schema,
+}
+
+/// The default of ParseOptions::max_record_bytes, which is far longer than any well formed transaction record
+pub const MAX_RECORD_BYTES: usize = 64 * 1024;
+
+/// Reasons parse_csv stopped before parsing the whole file
+/// 
+/// Each is logged, with the file it concerns, before it is returned.
+#[derive(Debug)]
+pub enum ParseError {
+    /// the file could not be opened or read
+    Io(std::io::Error),
+    /// the csv could not be read, such as its headers
+    Csv(csv_async::Error),
+    /// a task deserializing a shard of the file failed
+    Shard(tokio::task::JoinError),
+    /// a row was anomalous under Mode::Strict; the message describes it
+    Anomaly(String),
+    /// the headers did not match the schema; the columns which were missing, and those which were unexpected
+    Schema { missing: Vec<String>, unexpected: Vec<String> },
+    /// under Mode::Strict, the amount on a line was not a number; the line, and the amount as it was written
+    InvalidAmount { line: u64, value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "{}", err),
+            ParseError::Csv(err) => write!(f, "{}", err),
+            ParseError::Shard(err) => write!(f, "{}", err),
+            ParseError::Anomaly(msg) => f.write_str(msg),
+            ParseError::InvalidAmount { line, value } => write!(f, "the amount {} on line {} is not a number", value, line),
+            ParseError::Schema { missing, unexpected } => {
+                write!(f, "the headers do not match the columns {}", COLUMNS.join(", "))?;
+                if !missing.is_empty() {
+                    write!(f, "; missing {}", missing.join(", "))?;
+                }
+                if !unexpected.is_empty() {
+                    write!(f, "; unexpected {}", unexpected.join(", "))?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The columns every transactions csv has, in any order
+pub const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+// Columns a transactions csv may have besides COLUMNS, which Schema::Exact allows
+const OPTIONAL_COLUMNS: [&str; 1] = ["time"];
+
+/// How the headers of the csv are checked before any row is parsed
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Schema {
+    /// the headers are not checked; a row missing a column is anomalous on its own
+    #[default]
+    Unchecked,
+    /// the headers are exactly COLUMNS, in any order, along with an optional time column
+    Exact,
+    /// the headers include COLUMNS, in any order; any other columns are ignored
+    AllowExtras,
+}
+
+/// The units in which the amount column is expressed
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Units {
+    /// decimal dollars, such as 5.25
+    #[default]
+    Dollars,
+    /// integer minor units, such as 525, which are converted to dollars; negative or fractional cents are rejected
+    Cents,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            delimiter: b',',
+            reorder_window: 0,
+            reorder_by_time: false,
+            column_map: HashMap::new(),
+            shards: 1,
+            mode: Mode::Lenient,
+            skip_commands: 0,
+            units: Units::Dollars,
+            cancel: None,
+            open_retries: 0,
+            trim: csv_async::Trim::All,
+            max_record_bytes: MAX_RECORD_BYTES,
+            strip_currency: false,
+            schema: Schema::Unchecked,
+        }
+    }
+}
 
 /// Parses a csv file asynchronously into the command queue
 /// The csv file should be a transaction csv, containing a series of transactions to affect client data... or 'commands'
 /// 
 /// By default, the csv reader will assume a header ("type, client, tx, amount") exists
 /// It therefore skips the first line in csv input.
+/// An optional time column holds RFC3339 timestamps; an invalid timestamp is dropped with a warning, keeping the rest of the row.
+/// 
+/// A leading UTF-8 byte order mark is discarded, and the final record need not end with a newline.
+/// A file which is empty, or has only headers, is logged as having no transactions; it is not an error, even under a schema.
+/// 
+/// Unless options.schema is Schema::Unchecked, headers which do not match it are logged as an error, listing the missing and unexpected columns, and no row is parsed.
+/// 
+/// With a reorder_window, commands are dispatched in tx order within the window; a deposit or withdrawal goes before the disputes, resolves, and chargebacks sharing its tx.
+/// With reorder_by_time as well, they are dispatched in order of their time column first, falling back to tx order for equal or missing times.
+/// This is a best-effort aid for feeds which interleave slightly; it cannot fix commands which arrive further out of order than the window.
+/// 
+/// With more than one shard, the whole file is read into memory and split at line boundaries; the shards are deserialized concurrently and merged back into file order before dispatch.
+/// 
+/// Under Mode::Strict, an anomalous row, such as one which cannot be deserialized, is logged as an error and parsing stops; the commands already sent are unaffected.
+/// Under Mode::Lenient, it is logged and skipped, or fixed where it can be.
+/// An amount which is not a number is logged with its value and line; under Mode::Strict, parsing stops with ParseError::InvalidAmount.
+/// A record longer than options.max_record_bytes is logged as an error, and is skipped under Mode::Lenient; with one shard, at most that many bytes of it are ever buffered.
+/// 
+/// Once the cancel token is cancelled, parsing stops before the next row, and the commands already sent are unaffected.
 /// 
 /// # Arguments
 /// 
 /// file_path           the path to the input csv file
 /// tx                  transmitter to produce commands
+/// options             options controlling how the csv is read
+/// 
+/// # Return Value
+/// 
+/// Err(ParseError)     the file could not be read, its headers did not match the schema, or a row was anomalous under Mode::Strict
+/// Ok(())              the file was parsed, or parsing was cancelled, or the receiver stopped early
 /// 
 pub async fn parse_csv(
     file_path: String,
-    tx: mpsc::Sender<command::Command>
-) {
+    tx: mpsc::Sender<command::Command>,
+    options: ParseOptions
+) -> Result<(), ParseError> {
+    parse_file(&file_path, tx, &options).await
+}
+
+/// Parses every csv file in a directory into the command queue, one after another, as parse_csv does for each
+/// 
+/// Files are parsed in order of their file name, which approximates chronological order for names such as dates; files without a `.csv` extension,
+/// and subdirectories, are ignored.  The commands of every file go to the same queue, so they update the same client data.
+/// 
+/// A file which cannot be parsed stops parsing, as it would for parse_csv, leaving the later files unsent.
+/// options.skip_commands is applied to each file, so it should be 0.
+/// 
+/// # Arguments
+/// 
+/// dir_path            the path to the directory of transaction csv files
+/// tx                  transmitter to produce commands
+/// options             options controlling how each csv is read
+/// 
+/// # Return Value
+/// 
+/// Err(ParseError)     the directory could not be read, or a file could not be parsed, as for parse_csv
+/// Ok(())              every file was parsed, or parsing was cancelled, or the receiver stopped early
+/// 
+pub async fn parse_dir(
+    dir_path: String,
+    tx: mpsc::Sender<command::Command>,
+    options: ParseOptions
+) -> Result<(), ParseError> {
+
+    let file_paths = csv_files(&dir_path).await.map_err(|err| {
+        logger::error(&format!("Reading the directory {} failed: {}", dir_path, err));
+        ParseError::Io(err)
+    })?;
+    if file_paths.is_empty() {
+        logger::warning(&format!("The directory {} has no csv files.", dir_path));
+    }
+
+    for file_path in file_paths {
+        if tx.is_closed() || options.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled()) {
+            break;
+        }
+        let file_path = file_path.to_string_lossy();
+        logger::info(&format!("Reading transactions from {}.", file_path));
+        parse_file(&file_path, tx.clone(), &options).await?;
+    }
+    Ok(())
+}
+
+// The paths of the files with a csv extension in the directory, sorted by file name
+async fn csv_files(dir_path: &str) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+    let mut entries = tokio::fs::read_dir(dir_path).await?;
+    let mut file_paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+        if is_csv && tokio::fs::metadata(&path).await?.is_file() {
+            file_paths.push(path);
+        }
+    }
+    file_paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(file_paths)
+}
+
+// Parses a csv file into the command queue, sharding it when options.shards is more than 1
+async fn parse_file(
+    file_path: &str,
+    tx: mpsc::Sender<command::Command>,
+    options: &ParseOptions
+) -> Result<(), ParseError> {
+
+    if options.shards > 1 {
+        let records = parse_shards(file_path, options).await?;
+        return dispatch(tokio_stream::iter(records), file_path, tx, options).await;
+    }
 
     // open the file
-    let mut rdr = csv_async::AsyncReaderBuilder::new()
-        .trim(csv_async::Trim::All)
-        .flexible(true)
-        .create_deserializer(match File::open(&file_path).await {
-            Err(err) => {
-                let msg = format!("Opening {} failed: {}", &file_path, err);
-                logger::error(&msg);
-                panic!("{}", msg);
-            }
-            Ok(resolution) => resolution,
-        });
+    let file = open(file_path, options.open_retries).await?;
 
-    // get a stream for the file
-    let mut records = rdr.deserialize::<command::Command>();
+    parse_source(file, file_path, tx, options).await
+}
+
+/// Parses transaction csv read from a TCP connection into the command queue, as parse_csv does for a file
+/// 
+/// Only a single connection is accepted; the listener is dropped once it arrives, so later clients are refused.
+/// Parsing finishes when the client closes the connection.
+/// 
+/// Sharding is not supported, as the csv arrives as a stream; options.shards is ignored.
+/// Once the cancel token is cancelled, waiting for the connection, or for its next row, stops.
+/// 
+/// # Arguments
+/// 
+/// listener            a listener bound to the address clients connect to
+/// tx                  transmitter to produce commands
+/// options             options controlling how the csv is read
+/// 
+/// # Return Value
+/// 
+/// Err(ParseError)     accepting the connection, or reading from it, failed, or a row was anomalous under Mode::Strict
+/// Ok(())              the connection was closed, or parsing was cancelled, or the receiver stopped early
+/// 
+pub async fn parse_tcp(
+    listener: tokio::net::TcpListener,
+    tx: mpsc::Sender<command::Command>,
+    options: ParseOptions
+) -> Result<(), ParseError> {
+
+    let (socket, peer) = tokio::select! {
+        accepted = listener.accept() => accepted.map_err(|err| {
+            logger::error(&format!("Accepting a connection failed: {}", err));
+            ParseError::Io(err)
+        })?,
+        _ = crate::command_handler::cancelled(&options.cancel) => {
+            logger::info("Listening was cancelled before a connection arrived.");
+            return Ok(());
+        },
+    };
+    drop(listener);
+
+    let name = format!("the connection from {}", peer);
+    logger::info(&format!("Reading transactions from {}.", name));
+    parse_source(socket, &name, tx, &options).await
+}
+
+// Parses the csv from any source, such as a file or a socket, into the command queue; name describes the source in logs
+async fn parse_source<R: tokio::io::AsyncRead + Unpin + Send>(
+    source: R,
+    name: &str,
+    tx: mpsc::Sender<command::Command>,
+    options: &ParseOptions
+) -> Result<(), ParseError> {
+
+    let mut source = BufReader::new(source);
+
+    // Files exported by some tools, such as Excel, begin with a byte order mark which would otherwise become part of the first header
+    match source.fill_buf().await {
+        Ok(buf) if buf.starts_with(UTF8_BOM) => source.consume(UTF8_BOM.len()),
+        Ok(_) => (),
+        Err(err) => {
+            logger::error(&format!("Reading {} failed: {}", name, err));
+            return Err(ParseError::Io(err));
+        }
+    };
+
+    let source = RecordLimit::new(source, name, options.max_record_bytes, options.mode, 0);
+    let (mut rdr, headers) = create_reader(source, name, options).await?;
+
+    // get a stream for the source
+    let records = rdr.records()
+        .map(|record| Record::read(record, &headers, 0, options.strip_currency));
+
+    dispatch(records, name, tx, options).await
+}
+
+// A deserialized record, with its line in the file and whether it carried more fields than there are headers
+// When the record could not be deserialized because of its amount, invalid_amount holds the amount as it was written.
+struct Record {
+    command: Result<command::Command, csv_async::Error>,
+    line: u64,
+    extra_fields: bool,
+    invalid_amount: Option<String>,
+}
+
+impl Record {
+    // Deserializes a csv record; line_offset is added to the line the reader reports, for readers which start part way through the file
+    // With strip_currency, the amount column has its currency decorations removed first.
+    fn read(record: Result<csv_async::StringRecord, csv_async::Error>, headers: &csv_async::StringRecord, line_offset: u64, strip_currency: bool) -> Record {
+        let amount = headers.iter().position(|header| header == "amount");
+        let record = match (record, amount) {
+            (Ok(record), Some(amount)) if strip_currency => {
+                let mut stripped: csv_async::StringRecord = record.iter().enumerate()
+                    .map(|(i, field)| if i == amount { command::strip_currency(field) } else { field.to_owned() })
+                    .collect();
+                stripped.set_position(record.position().cloned());
+                Ok(stripped)
+            },
+            (record, _) => record,
+        };
+        match record {
+            Ok(record) => {
+                let command: Result<command::Command, csv_async::Error> = record.deserialize(Some(headers));
+                // Decimal's own errors do not name their field, so the amount is parsed again the same way to see whether it was at fault
+                let invalid_amount = match (&command, amount.and_then(|amount| record.get(amount))) {
+                    (Err(_), Some(value)) if !value.is_empty() && Decimal::from_str(value).or_else(|_| Decimal::from_scientific(value)).is_err() => Some(value.to_owned()),
+                    _ => None,
+                };
+                Record {
+                    command,
+                    line: line_offset + record.position().map_or(0, |position| position.line()),
+                    extra_fields: record.len() > headers.len(),
+                    invalid_amount,
+                }
+            },
+            Err(err) => Record {
+                line: line_offset + err.position().map_or(0, |position| position.line()),
+                command: Err(err),
+                extra_fields: false,
+                invalid_amount: None,
+            },
+        }
+    }
+}
+
+// Validates records and sends them to the handler, in the order the reorder buffer releases them
+async fn dispatch<S: tokio_stream::Stream<Item = Record> + Unpin>(
+    mut records: S,
+    file_path: &str,
+    tx: mpsc::Sender<command::Command>,
+    options: &ParseOptions
+) -> Result<(), ParseError> {
+
+    let mut buffer = ReorderBuffer::new(options.reorder_window, options.reorder_by_time);
+
+    // commands already reflected in resumed client data
+    let mut to_skip = options.skip_commands;
+    // rows read, however they fared, so a source without any can be reported
+    let mut rows: u64 = 0;
 
     // iterate over the file, deserializing 'records' (commands) as we go
-    while let Some(record) = records.next().await {
+    // a source such as a socket may wait indefinitely for its next record, so cancellation is awaited alongside it
+    loop {
+        let next = tokio::select! {
+            biased;
+            _ = crate::command_handler::cancelled(&options.cancel) => {
+                logger::info(&format!("Parsing {} cancelled.", file_path));
+                return Ok(());
+            },
+            next = records.next() => next,
+        };
+        let Some(Record { command: record, line, extra_fields, invalid_amount }) = next else {
+            break;
+        };
+        rows += 1;
 
         // handle any errors deserializing a 'record'
         let record: crate::command::Command = match record {
 
-            Err(err) => {
-                let msg = format!("Getting a command from {} failed: {}",file_path, err);
+            // an amount which is not a number is likely a typo in one row, rather than a problem with the csv, so it is reported with its value
+            Err(_) if invalid_amount.is_some() => {
+                let value = invalid_amount.unwrap_or_default();
+                let msg = format!("The amount {} on line {} of {} is not a number", value, line, file_path);
+                match options.mode {
+                    Mode::Strict => {
+                        logger::error(&format!("{}.", msg));
+                        return Err(ParseError::InvalidAmount { line, value });
+                    },
+                    Mode::Lenient => {
+                        logger::warning(&format!("{}; skipping the row.", msg));
+                        continue;
+                    },
+                }
+            }
 
-                logger::error(&msg);
-                panic!("{}", msg);
+            Err(err) => {
+                anomaly(options.mode, &format!("Getting a command from {} failed: {}", file_path, err))?;
+                continue;
             }
 
             Ok(resolution) => resolution,
 
         };
 
-        // send command
-        if let Err(err) = tx.send(record).await {
-            let msg = format!("Failed to send command to rx: {:?}", err);
-            logger::error(&msg);
-            panic!("{}", msg);
+        // flexible reading lets rows omit the ammount, but fields beyond the headers are likely junk
+        if extra_fields {
+            match options.mode {
+                Mode::Strict => anomaly(options.mode, &format!("Line {} of {} has more fields than the header.", line, file_path))?,
+                Mode::Lenient => logger::info(&format!("Ignoring the fields beyond the header on line {} of {}.", line, file_path)),
+            }
+        }
+
+        // ammounts in cents are converted to dollars before they are validated
+        let record = match options.units {
+            Units::Dollars => record,
+            Units::Cents => match record.in_dollars() {
+                Ok(record) => record,
+                Err(err) => {
+                    anomaly(options.mode, &format!("Skipping line {} of {} because the command is invalid: {:?}", line, file_path, err))?;
+                    continue;
+                },
+            },
+        };
+
+        // skip commands which cannot be executed; a missing ammount is left to the handler's AmountPolicy, as it is legitimate for disputes
+        let record = match record.validate() {
+            Ok(()) | Err(command::InvalidCommand::MissingAmount) => record,
+            Err(command::InvalidCommand::ExcessPrecision) if !options.mode.is_strict() => {
+                logger::info(&format!("Rounding the amount on line {} of {} to {} digits after the decimal.", line, file_path, command::MAX_SCALE));
+                record.rounded()
+            },
+            Err(err) => {
+                anomaly(options.mode, &format!("Skipping line {} of {} because the command is invalid: {:?}", line, file_path, err))?;
+                continue;
+            },
         };
 
+        // send command, once the reorder buffer releases it
+        // A closed channel means the handler stopped early; its failure is the root cause, so stop cleanly rather than masking it with a panic here.
+        if let Some(ready) = buffer.push(record) {
+            if to_skip > 0 {
+                to_skip -= 1;
+                continue;
+            }
+            if let Err(err) = tx.send(ready).await {
+                if is_cancelled(options) {
+                    logger::info(&format!("Parsing {} cancelled at line {}.", file_path, line));
+                    return Ok(());
+                }
+                // the rest of the input is left unread, as parsing it only to count it could take as long as the run, or never end on a connection
+                log_unsent(file_path, 1 + buffer.len(), &err.0);
+                return Ok(());
+            };
+        }
+
     };
+
+    if rows == 0 {
+        logger::info(&format!("No transactions were found in {}.", file_path));
+    }
+
+    // send whatever the reorder buffer still holds
+    let mut remaining = buffer.into_sorted().into_iter();
+    while let Some(ready) = remaining.next() {
+        if is_cancelled(options) {
+            logger::info(&format!("Parsing {} cancelled with {} reordered commands unsent.", file_path, 1 + remaining.len()));
+            return Ok(());
+        }
+        if to_skip > 0 {
+            to_skip -= 1;
+            continue;
+        }
+        if let Err(err) = tx.send(ready).await {
+            log_unsent(file_path, 1 + remaining.len(), &err.0);
+            return Ok(());
+        }
+    }
+    Ok(())
 }
 
-/// Writes a csv file
-/// The csv file contains information about user accounts
-/// 
-/// # Example Output
-/// 
-/// client, available, held, total, locked
-/// 4, 36.0, 0.0, 36.0, true
-/// 2, 33.0, 0.0, 30.0, false
-/// 1, 30.0, 2.0, 32.0, false
-/// 3, 36.0, 2.0, 32.0, true
-/// 5, -6.0, 0.0, -6.0, true
-/// 
-/// # Arguments
-/// 
-/// command_queue       the queue to store commands in
-/// 
-pub async fn write_csv(
-    client_data: Arc::<Mutex::<HashMap<client_data::ClientID, Box<client_data::ClientData>>>>
-) {
-    let mut stdout = tokio::io::stdout();
+// Opens the file, retrying failures other than a missing file up to `retries` times, with exponential backoff
+async fn open(file_path: &str, retries: u32) -> Result<File, ParseError> {
+    let mut delay = OPEN_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match File::open(file_path).await {
+            Ok(file) => return Ok(file),
+            Err(err) if attempt < retries && err.kind() != std::io::ErrorKind::NotFound => {
+                attempt += 1;
+                logger::warning(&format!("Opening {} failed: {}; retrying in {}ms ({} of {}).", file_path, err, delay.as_millis(), attempt, retries));
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            },
+            Err(err) => {
+                logger::error(&format!("Opening {} failed: {}", file_path, err));
+                return Err(ParseError::Io(err));
+            },
+        }
+    }
+}
 
-    // write the headers to the file
-    let headers = "client,available,held,total,locked\n";
-    match stdout.write_all(headers.as_bytes()).await {
-        Ok(()) => (),
+// Creates a reader for the csv, along with its headers; the headers of providers which use their own column names are renamed
+async fn create_reader<R: tokio::io::AsyncRead + Unpin + Send>(source: R, file_path: &str, options: &ParseOptions) -> Result<(csv_async::AsyncReader<R>, csv_async::StringRecord), ParseError> {
+    let mut rdr = csv_async::AsyncReaderBuilder::new()
+        .trim(options.trim)
+        .flexible(true)
+        .delimiter(options.delimiter)
+        .create_reader(source);
+
+    let headers = match rdr.headers().await {
+        Ok(headers) => map_headers(headers, &options.column_map),
         Err(err) => {
-            let msg = format!("An error occured while trying to write headers to the file: {}", err);
-            logger::error(&msg);
-            panic!("{}", msg);
+            logger::error(&format!("Reading the headers of {} failed: {}", file_path, err));
+            return Err(ParseError::Csv(err));
         }
     };
+    // an empty file has no headers to check; it is reported as having no transactions, as a file with only headers is
+    if headers.is_empty() {
+        return Ok((rdr, headers));
+    }
+    if let Err(err) = check_schema(&headers, options.schema) {
+        logger::error(&format!("The headers of {} are not as expected: {}", file_path, err));
+        return Err(err);
+    }
+    Ok((rdr, headers))
+}
 
-    let c_d = {
-        match client_data.lock() {
-            Ok(c_d) => c_d,
-            Err(err) => panic!("transaction_csv parser cannot lock the client_data for writing: {:?}", err),
-        }
+// Checks the headers against the schema, listing the columns which are missing and those which are unexpected
+fn check_schema(headers: &csv_async::StringRecord, schema: Schema) -> Result<(), ParseError> {
+    if schema == Schema::Unchecked {
+        return Ok(());
+    }
+    let missing: Vec<String> = COLUMNS.iter()
+        .filter(|column| !headers.iter().any(|header| header == **column))
+        .map(|column| column.to_string())
+        .collect();
+    let unexpected: Vec<String> = match schema {
+        Schema::Exact => headers.iter()
+            .filter(|header| !COLUMNS.contains(header) && !OPTIONAL_COLUMNS.contains(header))
+            .map(str::to_owned)
+            .collect(),
+        Schema::Unchecked | Schema::AllowExtras => Vec::new(),
     };
+    if missing.is_empty() && unexpected.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(ParseError::Schema { missing, unexpected })
+    }
+}
 
-    // output user data
-    for (client_id, client) in c_d.iter() {
-
-        let mut record = [
-            client_id.to_string(),
-            client.get_wealth().round_dp(4).to_string(), 
-            client.get_held_wealth().round_dp(4).to_string(), 
-            client.get_total().round_dp(4).to_string(), 
-            client.is_locked().to_string(),
-        ].join(",");
-
-        record+="\n";
- 
-        match stdout.write_all(record.as_bytes()).await {
-            Ok(()) => (),
-            Err(err) => {
-                let msg = format!("An error occured while trying to write records to the file: {}", err);
-                logger::error(&msg);
-                panic!("{}", msg);
-            }
+// Reads the whole file, splits it at line boundaries into shards which are deserialized concurrently, then merges the records back into file order
+//
+// Splitting at newlines assumes no quoted field contains a newline, which holds for transaction csvs.
+async fn parse_shards(file_path: &str, options: &ParseOptions) -> Result<Vec<Record>, ParseError> {
+
+    let mut content = Vec::new();
+    open(file_path, options.open_retries).await?.read_to_end(&mut content).await.map_err(|err| {
+        logger::error(&format!("Reading {} failed: {}", file_path, err));
+        ParseError::Io(err)
+    })?;
+    let content = content.strip_prefix(UTF8_BOM).unwrap_or(&content);
+
+    // every shard is given the header, so it deserializes the same way as the whole file would
+    let header_end = content.iter().position(|byte| *byte == b'\n').map_or(content.len(), |end| end + 1);
+    let (header, body) = content.split_at(header_end);
+
+    // the headers are checked against the schema once, rather than by every shard
+    create_reader(header, file_path, options).await?;
+
+    let mut shards = Vec::new();
+    let mut lines_before: u64 = 1;
+    let mut start = 0;
+    for shard in 0..options.shards {
+        // each shard ends at the first newline after its share of the body
+        let end = if shard + 1 == options.shards {
+            body.len()
+        }
+        else {
+            let target = (body.len() * (shard + 1) / options.shards).max(start);
+            body[target..].iter().position(|byte| *byte == b'\n').map_or(body.len(), |newline| target + newline + 1)
+        };
+
+        let mut chunk = header.to_vec();
+        chunk.extend_from_slice(&body[start..end]);
+
+        let shard_options = ParseOptions {
+            delimiter: options.delimiter,
+            column_map: options.column_map.clone(),
+            trim: options.trim,
+            strip_currency: options.strip_currency,
+            ..ParseOptions::default()
         };
+        let shard_path = file_path.to_owned();
+        let (max_record_bytes, mode) = (options.max_record_bytes, options.mode);
+        // the shard's header stands in for the line before the shard
+        let offset = lines_before - 1;
+        shards.push(tokio::spawn(async move {
+            let source = RecordLimit::new(chunk.as_slice(), &shard_path, max_record_bytes, mode, offset);
+            let (mut rdr, headers) = create_reader(source, &shard_path, &shard_options).await?;
+            Ok::<Vec<Record>, ParseError>(rdr.records()
+                .map(|record| Record::read(record, &headers, offset, shard_options.strip_currency))
+                .collect::<Vec<Record>>()
+                .await)
+        }));
 
+        lines_before += body[start..end].iter().filter(|byte| **byte == b'\n').count() as u64;
+        start = end;
     }
 
+    let mut records = Vec::new();
+    for shard in shards {
+        match shard.await {
+            Ok(shard_records) => records.extend(shard_records?),
+            Err(err) => {
+                logger::error(&format!("Parsing a shard of {} failed: {}", file_path, err));
+                return Err(ParseError::Shard(err));
+            }
+        }
+    }
+    Ok(records)
 }
 
+fn map_headers(headers: &csv_async::StringRecord, column_map: &HashMap<String, String>) -> csv_async::StringRecord {
+    headers.iter()
+        .map(|header| column_map.get(header).map(String::as_str).unwrap_or(header))
+        .collect()
+}
 
+// Reports a row which cannot be dispatched as it is; under Mode::Strict, it is an error which stops parsing
+fn anomaly(mode: Mode, msg: &str) -> Result<(), ParseError> {
+    match mode {
+        Mode::Strict => {
+            logger::error(msg);
+            Err(ParseError::Anomaly(msg.to_owned()))
+        },
+        Mode::Lenient => {
+            logger::warning(msg);
+            Ok(())
+        },
+    }
+}
 
-#[cfg(test)]
-mod transaction_csv_tests {
-    use std::collections::{HashMap};
-    use std::fs::File;
-    use std::io::Write;
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+// Passes the csv through a record at a time, rejecting records longer than `max` bytes before the csv reader buffers them
+//
+// A record ends at a newline outside of quotes.  Under Mode::Lenient, an oversized record is logged and replaced by an empty line, which the csv reader skips without losing count of lines;
+// under Mode::Strict, it is returned as an InvalidData error, which stops parsing.
+struct RecordLimit<R> {
+    inner: R,
+    name: String,
+    max: usize,
+    mode: Mode,
+    // the line the current record starts on
+    line: u64,
+    // lines read since the current record started
+    lines_in_record: u64,
+    in_quotes: bool,
+    // the record being read, or, once complete, being released
+    record: Vec<u8>,
+    released: usize,
+    complete: bool,
+    // whether the rest of an oversized record is being dropped
+    discarding: bool,
+}
 
-    use rust_decimal_macros::dec;
-    use tempfile::tempdir;
-    use tokio::time::timeout;
+impl<R: tokio::io::AsyncBufRead + Unpin> RecordLimit<R> {
+    // line_offset is added to the lines counted, for sources which start part way through the file
+    fn new(inner: R, name: &str, max: usize, mode: Mode, line_offset: u64) -> RecordLimit<R> {
+        RecordLimit {
+            inner,
+            name: name.to_owned(),
+            max,
+            mode,
+            line: line_offset + 1,
+            lines_in_record: 0,
+            in_quotes: false,
+            record: Vec::new(),
+            released: 0,
+            complete: false,
+            discarding: false,
+        }
+    }
 
-    use crate::client_data::{self, ClientData};
+    // Rejects the record being read, which has grown longer than the max
+    fn reject(&mut self) -> std::io::Result<()> {
+        self.record.clear();
+        self.discarding = true;
+        match self.mode {
+            Mode::Strict => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the record on line {} of {} is longer than the max of {} bytes", self.line, self.name, self.max)
+            )),
+            Mode::Lenient => {
+                logger::error(&format!("Skipping the record on line {} of {}, which is longer than the max of {} bytes.", self.line, self.name, self.max));
+                Ok(())
+            },
+        }
+    }
 
-    macro_rules! assert_ok {
-        ($in:expr) => {
-            assert!( Ok(()) == $in );
-        };
+    // Ends the current record, queueing it for release; a dropped record becomes a newline per line, so the csv reader still counts them
+    fn finish(&mut self) {
+        if self.discarding {
+            self.record = vec![b'\n'; self.lines_in_record as usize];
+            self.discarding = false;
+        }
+        self.line += self.lines_in_record;
+        self.lines_in_record = 0;
+        self.complete = true;
     }
+}
 
-    macro_rules! write_str {
-        ($dst:expr, $fmt:expr) => {{
-            if let Ok(result) = $dst.write_fmt(format_args!("{}", $fmt)) {
-                result
+impl<R: tokio::io::AsyncBufRead + Unpin> tokio::io::AsyncRead for RecordLimit<R> {
+    fn poll_read(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // release the completed record
+            if this.complete {
+                if this.released < this.record.len() {
+                    let len = buf.remaining().min(this.record.len() - this.released);
+                    buf.put_slice(&this.record[this.released..this.released + len]);
+                    this.released += len;
+                    return std::task::Poll::Ready(Ok(()));
+                }
+                this.record.clear();
+                this.released = 0;
+                this.complete = false;
             }
-            else {
-                panic!()
+
+            let available = match std::pin::Pin::new(&mut this.inner).poll_fill_buf(cx) {
+                std::task::Poll::Ready(Ok(available)) => available,
+                other => return other.map_ok(|_| ()),
+            };
+
+            // the final record need not end with a newline
+            if available.is_empty() {
+                if this.record.is_empty() {
+                    return std::task::Poll::Ready(Ok(()));
+                }
+                this.finish();
+                continue;
             }
-        }};
-    }
 
-    #[tokio::test]
-    async fn test_read() {
+            let mut end = None;
+            for (i, byte) in available.iter().enumerate() {
+                match byte {
+                    b'"' => this.in_quotes = !this.in_quotes,
+                    b'\n' => {
+                        this.lines_in_record += 1;
+                        if !this.in_quotes {
+                            end = Some(i + 1);
+                            break;
+                        }
+                    },
+                    _ => (),
+                }
+            }
+            let taken = end.unwrap_or(available.len());
+            if !this.discarding {
+                this.record.extend_from_slice(&available[..taken]);
+            }
+            std::pin::Pin::new(&mut this.inner).consume(taken);
 
-        // Create a directory inside of `std::env::temp_dir()`.
-        if let Ok(dir) = tempdir() {
+            // the newline ending a record is not counted against it
+            let len = this.record.len() - usize::from(end.is_some());
+            if !this.discarding && len > this.max {
+                if let Err(err) = this.reject() {
+                    return std::task::Poll::Ready(Err(err));
+                }
+            }
+            if end.is_some() {
+                this.finish();
+            }
+        }
+    }
+}
 
-            let file_path = dir.path().join("temp_transactions.csv");
-            
-            if let Ok(mut file) = File::create(&file_path) {
+fn is_cancelled(options: &ParseOptions) -> bool {
+    options.cancel.as_ref().is_some_and(CancellationToken::is_cancelled)
+}
 
-                let content = concat!(
-                    "type,  client,     tx, amount\n",
-                    "deposit,    2,     44, 22.125\n",
-                    "deposit,    2,     43, 11.0625\n",
-                    "withdrawal, 1,     40, 15\n", // client won't be found; insufficient funds should be raised
-                    "dispute,    2,     43, 17.0\n", // deposit 43 under dispute, ammount meaningless
-                    "deposit,    1,     45, 20002.0001\n",
-                    "deposit,    3,     44, 9999999.9999\n",
-                    "resolve,    2,     43\n", // 43 not under dispute anymore
-                    "dispute,    2,     43, 17.0\n", // deposit 43 under dispute
-                    "dispute,    2,     43, 17.0\n", // attempt to duplicate dispute
-                    "chargeback, 2  ,  43 , 23.33\n", // ammount should be stored in command but ignored by handler
-                    "dispute,    2,     43, 17.0\n", // account locked; dispute no longer present
-                    "dispute,    1,     11, 17.0\n", // dispute cannot find tx
-                    "  deposit , 1,   50  ,  13  \n",
-                    "deposit,    1,     51, \n", // will 0 be used for the ammount or will it raise an issue?
-                );
+fn log_unsent(file_path: &str, unsent: usize, first: &command::Command) {
+    logger::error(&format!("The command handler stopped before parsing of {} finished; {} parsed records were not sent, starting with {:?}", file_path, unsent, first));
+}
 
-                write_str!(file, content);
+// Sorts held commands by time (None unless ordering by time), then tx, then whether the command follows up on an earlier one, then arrival
+type ReorderKey = (Option<DateTime<Utc>>, client_data::TransactionID, bool, u64);
 
-                let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+// Holds up to `window` commands, releasing the one which sorts first once the window overflows
+struct ReorderBuffer {
+    window: usize,
+    by_time: bool,
+    sequence: u64,
+    commands: BTreeMap<ReorderKey, command::Command>,
+}
 
-                let parser = tokio::spawn( crate::transaction_csv::parse_csv(
-                    file_path.to_str().unwrap().to_owned(),
-                    tx,
-                ) );                
-                
-                let tester = tokio::spawn( async move {
+impl ReorderBuffer {
+    fn new(window: usize, by_time: bool) -> ReorderBuffer {
+        ReorderBuffer {
+            window,
+            by_time,
+            sequence: 0,
+            commands: BTreeMap::new(),
+        }
+    }
 
-                    let mut counter = 0;
+    fn len(&self) -> usize { self.commands.len() }
 
-                    while let Ok(Some(cmd)) = timeout(Duration::from_millis(1500), rx.recv()).await {
+    // Adds a command, returning the command which should be dispatched next, if the window is full
+    fn push(&mut self, cmd: command::Command) -> Option<command::Command> {
+        if self.window == 0 {
+            return Some(cmd);
+        }
 
-                        match counter {
+        let follow_up = !cmd.get_type().carries_ammount();
+        let time = cmd.get_timestamp().filter(|_| self.by_time);
+        self.commands.insert((time, cmd.get_transaction_id(), follow_up, self.sequence), cmd);
+        self.sequence += 1;
+
+        if self.commands.len() > self.window {
+            self.commands.pop_first().map(|(_, cmd)| cmd)
+        }
+        else {
+            None
+        }
+    }
+
+    // The held commands, in the order they should be dispatched
+    fn into_sorted(self) -> Vec<command::Command> {
+        self.commands.into_values().collect()
+    }
+}
+
+/// Options controlling the content of the csv written by write_csv
+pub struct WriteOptions {
+    /// adds a tx_count column with the number of transactions applied to each account
+    pub with_counts: bool,
+    /// the byte separating fields, a comma by default
+    pub delimiter: u8,
+    /// skips warnings about balances which lose precision when rounded for output
+    pub quiet_rounding: bool,
+    /// copies the balances, locked flag, and tx count of each client under the lock, then formats the records without it, so other work waits only for the copy rather than for formatting;
+    /// the output is the same either way, from a single state of the client data
+    pub stream_output: bool,
+    /// appends a summary row, with the client TOTALS_CLIENT, of the available, held, and total balances summed across all clients
+    pub with_totals: bool,
+    /// reports negative available and total balances as zero, warning of their true value; the client data itself is unchanged
+    pub clamp_negative: bool,
+    /// writes only the locked (frozen) accounts, such as for a compliance review; the header, and any summary row, are still written
+    pub locked_only: bool,
+    /// writes csv, or fixed-width lines for systems which cannot read csv; OutputFormat::Csv by default
+    pub format: OutputFormat,
+    /// writes only these columns, in this order, overriding with_counts; None writes every column, with tx_count only under with_counts
+    pub columns: Option<Vec<Column>>,
+    /// how the locked column is written, as true and false, or as 1 and 0 for consumers which expect integers
+    pub bool_format: BoolFormat,
+}
+
+/// How booleans, such as the locked column, are written in the balance output
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum BoolFormat {
+    /// true and false
+    #[default]
+    Text,
+    /// 1 and 0
+    Int,
+}
+
+impl BoolFormat {
+    /// Formats the boolean in this format
+    pub fn format(self, value: bool) -> String {
+        match self {
+            BoolFormat::Text => value.to_string(),
+            BoolFormat::Int => u8::from(value).to_string(),
+        }
+    }
+}
+
+/// A column of the balance output
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    TxCount,
+}
+
+impl Column {
+    /// Every column, in the order they are written by default
+    pub const ALL: [Column; 6] = [Column::Client, Column::Available, Column::Held, Column::Total, Column::Locked, Column::TxCount];
+
+    /// The name of the column, as in the header of the output
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::TxCount => "tx_count",
+        }
+    }
+
+    /// The column with the name, if there is one
+    pub fn from_name(name: &str) -> Option<Column> {
+        Column::ALL.into_iter().find(|column| column.name() == name)
+    }
+}
+
+/// The layout of the balance output
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum OutputFormat {
+    /// comma separated values, or separated by WriteOptions::delimiter
+    #[default]
+    Csv,
+    /// the same fields, each left aligned and padded with spaces to the width of its column, without delimiters;
+    /// a value longer than its column is truncated to fit, with a warning, and a column without a width is not padded
+    Fixed(Vec<usize>),
+}
+
+/// The default widths of the client, available, held, total, locked, and tx_count columns of OutputFormat::Fixed, in the order of Column::ALL
+/// 
+/// Each is a space wider than its header, or its longest likely value; balances below 10^14 fit along with a sign and 4 digits after the decimal.
+pub const FIXED_WIDTHS: [usize; 6] = [7, 21, 21, 21, 7, 10];
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            with_counts: false,
+            delimiter: b',',
+            quiet_rounding: false,
+            stream_output: false,
+            with_totals: false,
+            clamp_negative: false,
+            locked_only: false,
+            format: OutputFormat::Csv,
+            columns: None,
+            bool_format: BoolFormat::Text,
+        }
+    }
+}
+
+// The number of digits after the decimal which are output
+const OUTPUT_SCALE: u32 = 4;
+
+/// The client field of the summary row written under WriteOptions::with_totals
+/// 
+/// It is not a number, so the row can never be mistaken for, or read back as, a real client.
+pub const TOTALS_CLIENT: &str = "TOTAL";
+
+// The balances summed across clients for the summary row; sums are of the unrounded balances, so rounding is only applied once
+#[derive(Default)]
+struct Totals {
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    tx_count: u64,
+}
+
+impl Totals {
+    // Adds a client's balances, as they are reported; the sums saturate rather than overflowing, as ClientData::get_total does
+    fn add(&mut self, position: &client_data::NetPosition, fields: &OutputFields) {
+        self.available = self.available.saturating_add(position.available);
+        self.held = self.held.saturating_add(position.held);
+        self.total = self.total.saturating_add(position.total);
+        self.tx_count += fields.tx_count;
+    }
+
+    // The summary row, of the columns written; its locked field is left empty, as locking does not sum
+    fn format(&self, columns: &[Column]) -> Vec<String> {
+        columns.iter().map(|column| match column {
+            Column::Client => TOTALS_CLIENT.to_owned(),
+            Column::Available => format_balance(self.available),
+            Column::Held => format_balance(self.held),
+            Column::Total => format_balance(self.total),
+            Column::Locked => String::new(),
+            Column::TxCount => self.tx_count.to_string(),
+        }).collect()
+    }
+}
+
+// The fields of a client which write_csv outputs, copied so they can be formatted without the client data's lock
+struct OutputFields {
+    position: client_data::NetPosition,
+    locked: bool,
+    tx_count: u64,
+}
+
+impl OutputFields {
+    fn of(client: &client_data::ClientData) -> OutputFields {
+        OutputFields {
+            position: client.position(),
+            locked: client.is_locked(),
+            tx_count: client.get_transaction_count(),
+        }
+    }
+}
+
+// A row of the csv written by write_csv; the client is read as text so the summary row can be recognized
+#[derive(serde::Deserialize)]
+struct SeedRecord {
+    client: String,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: Option<bool>,
+}
+
+/// Reads client balances from a csv written by write_csv, such as a previous day's output, to seed a run which continues from them
+/// 
+/// The summary row of with_totals, and any tx_count column, are ignored.  Seeded accounts have no deposit history,
+/// so disputes, resolves, and chargebacks of the transactions behind their balances cannot succeed.
+/// A total which differs from the sum of available and held funds, as can happen with rounded or clamped output, is warned of; the sum is used.
+/// 
+/// # Arguments
+/// 
+/// file_path           the path to the csv, which is comma delimited
+/// frozen_policy       the policy of the seeded accounts
+/// 
+/// # Return Value
+/// 
+/// Err(ParseError)     the file could not be read, or a row is not a valid account, such as one with negative held funds
+/// Ok(HashMap)         the seeded accounts, by client
+/// 
+pub async fn read_seed(file_path: &str, frozen_policy: client_data::FrozenPolicy) -> Result<HashMap<client_data::ClientID, Box<client_data::ClientData>>, ParseError> {
+
+    let file = open(file_path, 0).await?;
+    let mut rdr = csv_async::AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .flexible(true)
+        .create_deserializer(file);
+
+    let mut clients = HashMap::new();
+    let mut records = rdr.deserialize::<SeedRecord>();
+    while let Some(record) = records.next().await {
+        let record = record.map_err(|err| {
+            logger::error(&format!("Reading the seed {} failed: {}", file_path, err));
+            ParseError::Csv(err)
+        })?;
+        if record.client == TOTALS_CLIENT {
+            continue;
+        }
+
+        let invalid = |problem: String| {
+            let msg = format!("The seed {} has an invalid row for client {}: {}", file_path, record.client, problem);
+            logger::error(&msg);
+            ParseError::Anomaly(msg)
+        };
+        let client_id: client_data::ClientID = record.client.parse().map_err(|err| invalid(format!("{}", err)))?;
+        if record.total != record.available + record.held {
+            logger::warning(&format!("The seed {} gives client {} a total of {}, rather than the {} of its available and held funds, which is used.", file_path, client_id, record.total, record.available + record.held));
+        }
+        let client = client_data::ClientData::builder()
+            .wealth(record.available)
+            .held_wealth(record.held)
+            .frozen(record.locked.unwrap_or(false))
+            .frozen_policy(frozen_policy)
+            .build()
+            .map_err(|err| invalid(format!("{:?}", err)))?;
+        if clients.insert(client_id, Box::new(client)).is_some() {
+            return Err(invalid("the client appears more than once".to_owned()));
+        }
+    }
+    Ok(clients)
+}
+
+/// Writes a csv file
+/// The csv file contains information about user accounts, ordered by client
+/// 
+/// # Example Output
+/// 
+/// client, available, held, total, locked
+/// 1, 30.0, 2.0, 32.0, false
+/// 2, 33.0, 0.0, 30.0, false
+/// 3, 36.0, 2.0, 32.0, true
+/// 4, 36.0, 0.0, 36.0, true
+/// 5, -6.0, 0.0, -6.0, true
+/// 
+/// With with_totals, a final row sums the clients, such as
+/// 
+/// TOTAL, 129.0, 4.0, 124.0, 
+/// 
+/// With locked_only, only the locked clients are written, and summed; if none are locked, only the header is written.
+/// 
+/// With BoolFormat::Int, the locked column is written 1 or 0 rather than true or false.
+/// 
+/// With columns, only the columns selected are written, in the order selected, in the header, the records, and any summary row.
+/// 
+/// With OutputFormat::Fixed, the same header and records are written as lines of padded columns, such as
+/// 
+/// client available            held                 total                locked
+/// 1      30                   2                    32                   false
+/// 
+/// # Arguments
+/// 
+/// client_data         data for all client accounts
+/// writer              the destination of the csv, such as stdout
+/// options             options controlling the content of the csv
+/// 
+/// # Return Value
+/// 
+/// Err(std::io::Error)     writing to, or flushing, the writer failed
+/// Ok(())
+/// 
+pub async fn write_csv<S: ClientStore, W: AsyncWrite + Unpin>(
+    client_data: Arc::<Mutex::<S>>,
+    writer: &mut W,
+    options: &WriteOptions
+) -> Result<(), std::io::Error> {
+
+    let mut wtr = RecordWriter::new(writer, options);
+    let columns = output_columns(options);
+
+    // write the headers to the file
+    let headers: Vec<&str> = columns.iter().map(|column| column.name()).collect();
+    wtr.write_record(&headers).await?;
+
+    let mut totals = Totals::default();
+
+    if options.stream_output {
+
+        // copy the fields written under a single lock, so every record and the totals come from the same state, then format and write them without it
+        let snapshot: Vec<(client_data::ClientID, OutputFields)> = lock(&client_data).sorted_clients().into_iter()
+            .filter(|(_, client)| is_written(client, options))
+            .map(|(client_id, client)| (client_id, OutputFields::of(client)))
+            .collect();
+
+        for (client_id, fields) in snapshot {
+            let record = format_record(&client_id, &fields, options, &columns, &mut totals);
+            wtr.write_record(&record).await?;
+        }
+    }
+    else {
+
+        // format the records while holding the lock, so that it is released before awaiting any writes
+        let records: Vec<Vec<String>> = lock(&client_data).sorted_clients().into_iter()
+            .filter(|(_, client)| is_written(client, options))
+            .map(|(client_id, client)| format_record(&client_id, &OutputFields::of(client), options, &columns, &mut totals))
+            .collect();
+
+        // output user data
+        for record in records {
+            wtr.write_record(&record).await?;
+        }
+    }
+
+    if options.with_totals {
+        wtr.write_record(&totals.format(&columns)).await?;
+    }
+
+    wtr.flush().await
+}
+
+
+
+/// Writes a statement csv listing each client's retained deposits, for dispute investigations
+/// 
+/// Rows are ordered by client, then by tx, so the output is deterministic.  The time is empty for deposits without a timestamp.
+/// 
+/// # Example Output
+/// 
+/// client,tx,amount,disputed,time
+/// 1,3,2.5,false,2024-05-01T09:30:00Z
+/// 1,7,10,true,
+/// 2,4,1,false,
+/// 
+/// # Arguments
+/// 
+/// client_data         data for all client accounts
+/// writer              the destination of the csv
+/// 
+/// # Return Value
+/// 
+/// Err(std::io::Error)     writing to, or flushing, the writer failed
+/// Ok(())
+/// 
+pub async fn write_statements<S: ClientStore, W: AsyncWrite + Unpin>(
+    client_data: Arc::<Mutex::<S>>,
+    writer: &mut W
+) -> Result<(), std::io::Error> {
+
+    writer.write_all("client,tx,amount,disputed,time\n".as_bytes()).await?;
+
+    // format the records while holding the lock, so that it is released before awaiting any writes
+    let records: Vec<String> = {
+        let c_d = lock(&client_data);
+
+        c_d.sorted_clients().into_iter().flat_map(|(client_id, client)| {
+            client.deposit_records().into_iter().map(move |(transaction_id, ammount, disputed, timestamp)| {
+                let time = timestamp.map(|timestamp| timestamp.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)).unwrap_or_default();
+                format!("{},{},{},{},{}\n", client_id, transaction_id, ammount, disputed, time)
+            })
+        }).collect()
+    };
+
+    for record in records {
+        writer.write_all(record.as_bytes()).await?;
+    }
+
+    writer.flush().await
+}
+
+
+
+// Writes the records of the balance output in its format
+enum RecordWriter<'a, W: AsyncWrite + Unpin> {
+    // the csv writer quotes and escapes fields consistently with the reader
+    Csv(Box<csv_async::AsyncWriter<&'a mut W>>),
+    Fixed(&'a mut W, &'a [usize]),
+}
+
+impl<'a, W: AsyncWrite + Unpin> RecordWriter<'a, W> {
+    fn new(writer: &'a mut W, options: &'a WriteOptions) -> RecordWriter<'a, W> {
+        match &options.format {
+            OutputFormat::Csv => RecordWriter::Csv(Box::new(csv_async::AsyncWriterBuilder::new()
+                .delimiter(options.delimiter)
+                .create_writer(writer))),
+            OutputFormat::Fixed(widths) => RecordWriter::Fixed(writer, widths),
+        }
+    }
+
+    async fn write_record<T: AsRef<str>>(&mut self, record: &[T]) -> Result<(), std::io::Error> {
+        match self {
+            RecordWriter::Csv(wtr) => Ok(wtr.write_record(record.iter().map(AsRef::as_ref)).await?),
+            RecordWriter::Fixed(writer, widths) => writer.write_all(fixed_line(record, widths).as_bytes()).await,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            RecordWriter::Csv(wtr) => wtr.flush().await,
+            RecordWriter::Fixed(writer, _) => writer.flush().await,
+        }
+    }
+}
+
+// Formats a record as a line of fixed-width columns, truncating values which are too long for their column
+fn fixed_line<T: AsRef<str>>(record: &[T], widths: &[usize]) -> String {
+    let mut line = String::new();
+    for (column, field) in record.iter().enumerate() {
+        let field = field.as_ref();
+        match widths.get(column) {
+            Some(width) if field.chars().count() > *width => {
+                let truncated: String = field.chars().take(*width).collect();
+                logger::warning(&format!("The value {} is longer than the {} characters of its column, so it is written as {}.", field, width, truncated));
+                line += &truncated;
+            },
+            Some(width) => line += &format!("{:<width$}", field, width = *width),
+            None => line += field,
+        }
+    }
+    line.push('\n');
+    line
+}
+
+/// Writes the same bytes to several sinks, such as stdout and a file, so write_csv can output to all of them at once
+/// 
+/// A sink which fails is logged, with its name, and dropped from later writes, while the other sinks still complete;
+/// the writer itself only fails once every sink has.  Check failed() once writing finishes to learn which sinks, if any, failed.
+/// 
+/// # Example
+/// 
+/// '''
+/// let mut tee = TeeWriter::new(vec![
+///     ("stdout".to_owned(), Box::new(tokio::io::stdout())),
+///     ("balances.csv".to_owned(), Box::new(file)),
+/// ]);
+/// write_csv(client_data, &mut tee, &options).await?;
+/// '''
+/// 
+pub struct TeeWriter<'a> {
+    sinks: Vec<TeeSink<'a>>,
+    // the bytes of the last write accepted, until every sink has taken them
+    pending: Vec<u8>,
+}
+
+// A sink of a TeeWriter, with how much of the pending bytes it has taken, and why it failed, if it has
+struct TeeSink<'a> {
+    name: String,
+    writer: Box<dyn AsyncWrite + Unpin + Send + 'a>,
+    written: usize,
+    failure: Option<std::io::Error>,
+}
+
+impl<'a> TeeSink<'a> {
+    fn fail(&mut self, err: std::io::Error) {
+        logger::error(&format!("Writing the output to {} failed: {}", self.name, err));
+        self.failure = Some(err);
+    }
+}
+
+impl<'a> TeeWriter<'a> {
+    /// A writer to each of the sinks, which are named in logs
+    pub fn new(sinks: Vec<(String, Box<dyn AsyncWrite + Unpin + Send + 'a>)>) -> TeeWriter<'a> {
+        TeeWriter {
+            sinks: sinks.into_iter().map(|(name, writer)| TeeSink { name, writer, written: 0, failure: None }).collect(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// The names of the sinks which failed, in the order they were given
+    pub fn failed(&self) -> Vec<&str> {
+        self.sinks.iter().filter(|sink| sink.failure.is_some()).map(|sink| sink.name.as_str()).collect()
+    }
+
+    // Fails once every sink has failed, so the caller stops writing
+    fn result<T>(&self, value: T) -> std::task::Poll<std::io::Result<T>> {
+        if !self.sinks.is_empty() && self.sinks.iter().all(|sink| sink.failure.is_some()) {
+            std::task::Poll::Ready(Err(std::io::Error::other(format!("writing the output to {} failed", self.failed().join(", ")))))
+        }
+        else {
+            std::task::Poll::Ready(Ok(value))
+        }
+    }
+
+    // Writes the pending bytes to each sink which has not failed, from where it left off, until every one has taken them all
+    fn poll_drain(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        let mut pending = false;
+        for sink in self.sinks.iter_mut().filter(|sink| sink.failure.is_none()) {
+            while sink.written < self.pending.len() {
+                match std::pin::Pin::new(&mut sink.writer).poll_write(cx, &self.pending[sink.written..]) {
+                    std::task::Poll::Ready(Ok(0)) => {
+                        sink.fail(std::io::ErrorKind::WriteZero.into());
+                        break;
+                    },
+                    std::task::Poll::Ready(Ok(written)) => sink.written += written,
+                    std::task::Poll::Ready(Err(err)) => {
+                        sink.fail(err);
+                        break;
+                    },
+                    std::task::Poll::Pending => {
+                        pending = true;
+                        break;
+                    },
+                }
+            }
+        }
+        if pending {
+            return std::task::Poll::Pending;
+        }
+        self.pending.clear();
+        for sink in self.sinks.iter_mut() {
+            sink.written = 0;
+        }
+        std::task::Poll::Ready(())
+    }
+
+    // Polls each sink which has not failed, once the pending bytes are written, until every one is ready; a sink which fails is dropped
+    fn poll_each(&mut self, cx: &mut std::task::Context<'_>, mut poll: impl FnMut(std::pin::Pin<&mut (dyn AsyncWrite + Unpin + Send + 'a)>, &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>>) -> std::task::Poll<std::io::Result<()>> {
+        if self.poll_drain(cx).is_pending() {
+            return std::task::Poll::Pending;
+        }
+        let mut pending = false;
+        for sink in self.sinks.iter_mut().filter(|sink| sink.failure.is_none()) {
+            match poll(std::pin::Pin::new(sink.writer.as_mut()), cx) {
+                std::task::Poll::Ready(Ok(())) => (),
+                std::task::Poll::Ready(Err(err)) => sink.fail(err),
+                std::task::Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            return std::task::Poll::Pending;
+        }
+        self.result(())
+    }
+}
+
+// A write is accepted once the bytes of the previous one have reached every sink, and is copied, so it is written in full even if the caller
+// does not poll again with the same buffer; the bytes still pending are written by the next write, flush, or shutdown
+impl<'a> AsyncWrite for TeeWriter<'a> {
+    fn poll_write(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.poll_drain(cx).is_pending() {
+            return std::task::Poll::Pending;
+        }
+        this.pending.extend_from_slice(buf);
+        // a sink which is not ready yet is left to the next call
+        let _ = this.poll_drain(cx);
+        this.result(buf.len())
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        self.get_mut().poll_each(cx, |writer, cx| writer.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        self.get_mut().poll_each(cx, |writer, cx| writer.poll_shutdown(cx))
+    }
+}
+
+fn lock<S: ClientStore>(client_data: &Mutex<S>) -> std::sync::MutexGuard<'_, S> {
+    match client_data.lock() {
+        Ok(c_d) => c_d,
+        Err(err) => panic!("transaction_csv parser cannot lock the client_data for writing: {:?}", err),
+    }
+}
+
+// Whether the client's record is written under the options
+fn is_written(client: &client_data::ClientData, options: &WriteOptions) -> bool {
+    !options.locked_only || client.is_locked()
+}
+
+// The columns written under the options, in order
+fn output_columns(options: &WriteOptions) -> Vec<Column> {
+    match &options.columns {
+        Some(columns) => columns.clone(),
+        None => Column::ALL.into_iter().filter(|column| options.with_counts || *column != Column::TxCount).collect(),
+    }
+}
+
+// Formats a client's output record, of the columns written, adding the balances it reports to the totals
+fn format_record(client_id: &client_data::ClientID, fields: &OutputFields, options: &WriteOptions, columns: &[Column], totals: &mut Totals) -> Vec<String> {
+    let mut position = fields.position;
+    if !options.quiet_rounding {
+        warn_rounding(client_id, "available", position.available);
+        warn_rounding(client_id, "held", position.held);
+        warn_rounding(client_id, "total", position.total);
+    }
+    if options.clamp_negative {
+        position.available = clamp(client_id, "available", position.available);
+        position.total = clamp(client_id, "total", position.total);
+    }
+    totals.add(&position, fields);
+
+    columns.iter().map(|column| match column {
+        Column::Client => client_id.to_string(),
+        Column::Available => format_balance(position.available),
+        Column::Held => format_balance(position.held),
+        Column::Total => format_balance(position.total),
+        Column::Locked => options.bool_format.format(fields.locked),
+        Column::TxCount => fields.tx_count.to_string(),
+    }).collect()
+}
+
+// Formats a balance rounded for output; a zero is written without its sign, so a balance which is -0, or rounds to it, is written 0.0000 as any other zero is
+fn format_balance(value: Decimal) -> String {
+    let rounded = value.round_dp(OUTPUT_SCALE);
+    if rounded.is_zero() {
+        rounded.abs().to_string()
+    }
+    else {
+        rounded.to_string()
+    }
+}
+
+// The ammount lost when a value is rounded for output, if any
+fn rounding_delta(value: Decimal) -> Option<Decimal> {
+    let delta = value - value.round_dp(OUTPUT_SCALE);
+    if delta.is_zero() {
+        None
+    }
+    else {
+        Some(delta)
+    }
+}
+
+// The balance to report when negative balances are clamped, warning auditors when it differs from the true balance
+fn clamp(client_id: &client_data::ClientID, column: &str, value: Decimal) -> Decimal {
+    if value.is_sign_negative() && !value.is_zero() {
+        logger::warning(&format!("The {} balance of client {} is {}, which is reported as 0.", column, client_id, value));
+        Decimal::ZERO
+    }
+    else {
+        value
+    }
+}
+
+// Warns auditors when rounding for output hides part of a balance
+fn warn_rounding(client_id: &client_data::ClientID, column: &str, value: Decimal) {
+    if let Some(delta) = rounding_delta(value) {
+        logger::warning(&format!("The {} balance of client {} is {}, which loses {} when rounded for output.", column, client_id, value, delta));
+    }
+}
+
+
+
+#[cfg(test)]
+mod transaction_csv_tests {
+    use std::collections::{HashMap};
+    use std::fs::File;
+    use std::io::Write;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use rust_decimal::prelude::Decimal;
+    use rust_decimal_macros::dec;
+    use tempfile::tempdir;
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+    use tokio::time::timeout;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::client_data::{self, ClientData, FrozenPolicy};
+    use crate::command::{Command, CommandType};
+    use crate::mode::Mode;
+    use crate::test_support::assert_balances_eq;
+    use crate::transaction_csv::{BoolFormat, Column, OutputFormat, ParseError, ParseOptions, Schema, TeeWriter, Units, WriteOptions, FIXED_WIDTHS};
+
+    macro_rules! write_str {
+        ($dst:expr, $fmt:expr) => {{
+            if let Ok(result) = $dst.write_fmt(format_args!("{}", $fmt)) {
+                result
+            }
+            else {
+                panic!()
+            }
+        }};
+    }
+
+    // Parses the content as a transaction csv file, collecting the commands sent
+    async fn parse(content: &[u8], options: ParseOptions) -> Vec<Command> {
+        let (commands, result) = parse_until_abort(content, options).await;
+        assert!(result.is_ok());
+        commands
+    }
+
+    // Parses the content as a transaction csv file, collecting the commands sent before parsing finished or aborted, and how it ended
+    async fn parse_until_abort(content: &[u8], options: ParseOptions) -> (Vec<Command>, Result<(), ParseError>) {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("transactions.csv");
+        std::fs::write(&file_path, content).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let parser = tokio::spawn(crate::transaction_csv::parse_csv(file_path.to_str().unwrap().to_owned(), tx, options));
+
+        let mut commands = Vec::new();
+        while let Some(cmd) = rx.recv().await {
+            commands.push(cmd);
+        }
+        (commands, parser.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+
+        // Create a directory inside of `std::env::temp_dir()`.
+        if let Ok(dir) = tempdir() {
+
+            let file_path = dir.path().join("temp_transactions.csv");
+            
+            if let Ok(mut file) = File::create(&file_path) {
+
+                let content = concat!(
+                    "type,  client,     tx, amount\n",
+                    "deposit,    2,     44, 22.125\n",
+                    "deposit,    2,     43, 11.0625\n",
+                    "withdrawal, 1,     40, 15\n", // client won't be found; insufficient funds should be raised
+                    "dispute,    2,     43, 17.0\n", // deposit 43 under dispute, ammount meaningless
+                    "deposit,    1,     45, 20002.0001\n",
+                    "deposit,    3,     44, 9999999.9999\n",
+                    "resolve,    2,     43\n", // 43 not under dispute anymore
+                    "dispute,    2,     43, 17.0\n", // deposit 43 under dispute
+                    "dispute,    2,     43, 17.0\n", // attempt to duplicate dispute
+                    "chargeback, 2  ,  43 , 23.33\n", // ammount should be stored in command but ignored by handler
+                    "dispute,    2,     43, 17.0\n", // account locked; dispute no longer present
+                    "dispute,    1,     11, 17.0\n", // dispute cannot find tx
+                    "  deposit , 1,   50  ,  13  \n",
+                    "deposit,    1,     51, \n", // passed on without an ammount, for the handler's AmountPolicy
+                );
+
+                write_str!(file, content);
+
+                let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+
+                let parser = tokio::spawn( crate::transaction_csv::parse_csv(
+                    file_path.to_str().unwrap().to_owned(),
+                    tx,
+                    ParseOptions::default(),
+                ) );                
+                
+                let tester = tokio::spawn( async move {
+
+                    let mut counter = 0;
+
+                    while let Ok(Some(cmd)) = timeout(Duration::from_millis(1500), rx.recv()).await {
+
+                        match counter {
                             0 => {
                                 assert_eq!(cmd.get_type(), crate::command::CommandType::Deposit);
                                 assert_eq!(cmd.get_client_id(), 2);
@@ -261,7 +1678,7 @@ mod transaction_csv_tests {
                                 assert_eq!(cmd.get_type(), crate::command::CommandType::Resolve);
                                 assert_eq!(cmd.get_client_id(), 2);
                                 assert_eq!(cmd.get_transaction_id(), 43);
-                                assert!(&None == cmd.get_wealth());
+                                assert!(cmd.get_wealth().is_none());
                             },
                             7 => {
                                 assert_eq!(cmd.get_type(), crate::command::CommandType::Dispute);
@@ -303,147 +1720,1148 @@ mod transaction_csv_tests {
                                 assert_eq!(cmd.get_type(), crate::command::CommandType::Deposit);
                                 assert_eq!(cmd.get_client_id(), 1);
                                 assert_eq!(cmd.get_transaction_id(), 51);
-                                assert!(&None == cmd.get_wealth());
+                                assert!(cmd.get_wealth().is_none());
                             },
                             _ => {
                                 panic!("unexpected command parsed in test");
                             }
                         };
 
-                        counter=counter+1;
-                    }
+                        counter += 1;
+                    }
+
+                    assert_eq!(14, counter);
+                } );
+
+                if !matches!(parser.await, Ok(Ok(()))) {
+                    panic!("Couldn't await parse_csv");
+                }
+
+                if tester.await.is_err() {
+                    panic!("Couldn't await parse_csv's tester");
+                }
+
+                drop(file);
+            }
+            else {
+                panic!("Couldn't create temp file")
+            };
+
+            if let Err(err) = dir.close() {
+                panic!("Temp directory did not close properly: {}", err);
+            }
+
+        }
+        else {
+            panic!("Could not get temp dir");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+
+        // client, available, held, total, locked
+        // 4, 0.0, 36.0, 36.0, true
+        // 2, 33.0, 4.0, 37.0, false
+        // 1, 30.0, 2.0, 32.0, false
+        // 5, -6.0, 0.0, -6.0, true
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(4, Box::new(ClientData::builder().wealth(dec!(0.0000)).held_wealth(dec!(36.0000)).frozen(true).build().unwrap()));
+        data.insert(2, Box::new(ClientData::builder().wealth(dec!(33.0000)).held_wealth(dec!(4)).build().unwrap()));
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(30)).held_wealth(dec!(2)).build().unwrap()));
+        data.insert(5, Box::new(ClientData::builder().wealth(dec!(-6)).held_wealth(dec!(0)).frozen(true).build().unwrap()));
+
+        let mut output: Vec<u8> = Vec::new();
+        if let Err(err) = crate::transaction_csv::write_csv(Arc::new(Mutex::new(data)), &mut output, &WriteOptions::default()).await {
+            panic!("write_csv failed: {}", err);
+        }
+
+        let hdr = "client,available,held,total,locked";
+        let c5 = "5,-6,0.0000,-6,true";
+        let c4 = "4,0.0000,36.0000,36.0000,true";
+        let c1 = "1,30,2,32,false";
+        let c2 = "2,33.0000,4,37.0000,false";
+
+        let actual_content = String::from_utf8(output).unwrap();
+        assert_eq!(5, actual_content.lines().count());
+        actual_content.split('\n').for_each(|line| {
+            if !line.is_empty() {
+                let line_content = line.split_once(',');
+                match line_content.unwrap().0 {
+                    "client" => assert_eq!(hdr, line),
+                    "5" => assert_eq!(c5, line),
+                    "4" => assert_eq!(c4, line),
+                    "1" => assert_eq!(c1, line),
+                    "2" => assert_eq!(c2, line),
+                    _ => panic!(),
+                }
+            }
+        });
+    }
+
+    // A writer which fails on its Nth write
+    struct FailingWriter {
+        writes_until_failure: usize,
+    }
+
+    impl AsyncWrite for FailingWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+            if self.writes_until_failure == 0 {
+                Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "failing writer")))
+            }
+            else {
+                self.writes_until_failure -= 1;
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // A writer which takes one byte per write, and is not ready on every other poll
+    #[derive(Default)]
+    struct SlowWriter {
+        output: Vec<u8>,
+        ready: bool,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            self.output.push(buf[0]);
+            Poll::Ready(Ok(1))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_error() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::new()));
+        data.insert(2, Box::new(ClientData::new()));
+        let data = Arc::new(Mutex::new(data));
+
+        // the csv writer buffers small outputs, so they reach the writer in a single write when flushed
+        let mut writer = FailingWriter { writes_until_failure: 0 };
+        let result = crate::transaction_csv::write_csv(data.clone(), &mut writer, &WriteOptions::default()).await;
+        assert_eq!(std::io::ErrorKind::BrokenPipe, result.unwrap_err().kind());
+
+        let mut writer = FailingWriter { writes_until_failure: 1 };
+        assert!(crate::transaction_csv::write_csv(data.clone(), &mut writer, &WriteOptions::default()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_write_tee() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(5)).build().unwrap()));
+        data.insert(2, Box::new(ClientData::builder().wealth(dec!(3)).held_wealth(dec!(1)).frozen(true).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
+
+        let (mut first, mut second): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+        {
+            let mut tee = TeeWriter::new(vec![
+                ("first".to_owned(), Box::new(&mut first)),
+                ("second".to_owned(), Box::new(&mut second)),
+            ]);
+            crate::transaction_csv::write_csv(data.clone(), &mut tee, &WriteOptions::default()).await.unwrap();
+            assert!(tee.failed().is_empty());
+        }
+        assert_eq!("client,available,held,total,locked\n1,5,0.0000,5,false\n2,3,1,4,true\n", String::from_utf8(first.clone()).unwrap());
+        assert_eq!(first, second);
+
+        // a failing sink is reported by name, and the other sinks still complete
+        let mut output: Vec<u8> = Vec::new();
+        {
+            let mut tee = TeeWriter::new(vec![
+                ("broken".to_owned(), Box::new(FailingWriter { writes_until_failure: 0 })),
+                ("output".to_owned(), Box::new(&mut output)),
+            ]);
+            crate::logger::capture::start();
+            crate::transaction_csv::write_csv(data.clone(), &mut tee, &WriteOptions::default()).await.unwrap();
+            assert_eq!(vec!["broken"], tee.failed());
+            assert_eq!(vec!["ERROR!  Writing the output to broken failed: failing writer"], crate::logger::capture::take());
+        }
+        assert_eq!(first, output);
+
+        // once every sink has failed, so does the writer
+        let mut tee = TeeWriter::new(vec![("broken".to_owned(), Box::new(FailingWriter { writes_until_failure: 0 }))]);
+        assert!(crate::transaction_csv::write_csv(data.clone(), &mut tee, &WriteOptions::default()).await.is_err());
+
+        // a write abandoned while a sink is not ready, as by a timeout, still reaches every sink in full
+        let (mut fast, mut slow) = (Vec::new(), SlowWriter::default());
+        {
+            let mut tee = TeeWriter::new(vec![
+                ("fast".to_owned(), Box::new(&mut fast)),
+                ("slow".to_owned(), Box::new(&mut slow)),
+            ]);
+            let _ = timeout(Duration::ZERO, tokio::io::AsyncWriteExt::write_all(&mut tee, b"abc")).await;
+            tokio::io::AsyncWriteExt::write_all(&mut tee, b"def").await.unwrap();
+            tokio::io::AsyncWriteExt::flush(&mut tee).await.unwrap();
+        }
+        assert_eq!(b"abcdef", &fast[..]);
+        assert_eq!(b"abcdef", &slow.output[..]);
+    }
+
+    #[tokio::test]
+    async fn test_write_with_counts() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.withdraw(101, dec!(4)));
+        assert_eq!(Ok(()), client.dispute(1));
+
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(3, Box::new(client));
+
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { with_counts: true, ..WriteOptions::default() };
+        assert!(crate::transaction_csv::write_csv(Arc::new(Mutex::new(data)), &mut output, &options).await.is_ok());
+
+        assert_eq!("client,available,held,total,locked,tx_count\n3,-4,10,6,false,3\n", String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_seed() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10.5)));
+        assert_eq!(Ok(()), client.deposit(2, dec!(3)));
+        assert_eq!(Ok(()), client.dispute(2));
+        data.insert(1, Box::new(client));
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(3, dec!(4)));
+        assert_eq!(Ok(()), client.dispute(3));
+        assert_eq!(Ok(()), client.chargeback(3));
+        data.insert(2, Box::new(client));
+
+        // the output of write_csv seeds the same balances, ignoring the tx_count column and summary row
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { with_counts: true, with_totals: true, ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(Arc::new(Mutex::new(data)), &mut output, &options).await.unwrap();
+        let dir = tempdir().unwrap();
+        let seed_path = dir.path().join("seed.csv");
+        std::fs::write(&seed_path, &output).unwrap();
+
+        let seeded = crate::transaction_csv::read_seed(seed_path.to_str().unwrap(), FrozenPolicy::AllowDeposits).await.unwrap();
+        assert_eq!(2, seeded.len());
+        assert_balances_eq(&seeded[&1], dec!(10.5), dec!(3), dec!(13.5));
+        assert!(!seeded[&1].is_locked());
+        assert_balances_eq(&seeded[&2], dec!(0), dec!(0), dec!(0));
+        assert!(seeded[&2].is_locked());
+        assert_eq!(FrozenPolicy::AllowDeposits, seeded[&2].get_frozen_policy());
 
-                    assert_eq!(14, counter);
-                } );
+        // a total which does not add up is warned of, and the sum is used
+        std::fs::write(&seed_path, "client,available,held,total,locked\n1,5,1,7,false\n").unwrap();
+        crate::logger::capture::start();
+        let seeded = crate::transaction_csv::read_seed(seed_path.to_str().unwrap(), FrozenPolicy::default()).await.unwrap();
+        let logs = crate::logger::capture::take();
+        assert_balances_eq(&seeded[&1], dec!(5), dec!(1), dec!(6));
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
 
-                if let Err(_) = parser.await {
-                    panic!("Couldn't await parse_csv");
-                }
+        // rows which are not valid accounts fail the seed
+        for content in ["client,available,held,total,locked\n1,5,-1,4,false\n", "client,available,held,total,locked\n1,5,0,5,false\n1,2,0,2,false\n", "client,available,held,total,locked\nx,5,0,5,false\n"] {
+            std::fs::write(&seed_path, content).unwrap();
+            assert!(matches!(crate::transaction_csv::read_seed(seed_path.to_str().unwrap(), FrozenPolicy::default()).await, Err(ParseError::Anomaly(_))));
+        }
+    }
 
-                if let Err(_) = tester.await {
-                    panic!("Couldn't await parse_csv's tester");
-                }
+    #[tokio::test]
+    async fn test_write_total_overflow() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(Decimal::MAX).held_wealth(dec!(1)).build().unwrap()));
+        data.insert(2, Box::new(ClientData::builder().wealth(Decimal::MAX).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
 
-                drop(file);
-            }
-            else {
-                panic!("Couldn't create temp file")
-            };
+        // totals which overflow are reported as the largest Decimal, rather than crashing the output
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { with_totals: true, ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(data, &mut output, &options).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(format!("1,{},1,{},false", Decimal::MAX, Decimal::MAX), lines[1]);
+        assert!(lines[3].starts_with(&format!("TOTAL,{},1,{}", Decimal::MAX, Decimal::MAX)));
+    }
 
-            if let Err(err) = dir.close() {
-                panic!("Temp directory did not close properly: {}", err);
-            }
+    #[tokio::test]
+    async fn test_write_with_totals() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(4)));
+        assert_eq!(Ok(()), client.dispute(1));
+        data.insert(1, Box::new(client));
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(3, dec!(2.5)));
+        data.insert(2, Box::new(client));
+        let data = Arc::new(Mutex::new(data));
 
+        for stream_output in [false, true] {
+            let mut output: Vec<u8> = Vec::new();
+            let options = WriteOptions { with_totals: true, with_counts: true, stream_output, ..WriteOptions::default() };
+            crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let lines: Vec<&str> = output.lines().collect();
+
+            // the summary is the last row, and sums the rows above it, including the negative available balance
+            assert_eq!(4, lines.len());
+            assert_eq!("TOTAL,-1.5,10,8.5,,4", lines[3]);
+            let rows: Vec<Vec<Decimal>> = lines[1..3].iter()
+                .map(|line| line.split(',').skip(1).take(3).map(|field| field.parse().unwrap()).collect())
+                .collect();
+            let summary: Vec<Decimal> = lines[3].split(',').skip(1).take(3).map(|field| field.parse().unwrap()).collect();
+            assert_eq!(summary, (0..3).map(|column| rows.iter().map(|row| row[column]).sum()).collect::<Vec<Decimal>>());
         }
-        else {
-            panic!("Could not get temp dir");
+
+        // there is no summary by default
+        let mut output: Vec<u8> = Vec::new();
+        crate::transaction_csv::write_csv(data, &mut output, &WriteOptions::default()).await.unwrap();
+        assert!(!String::from_utf8(output).unwrap().contains(crate::transaction_csv::TOTALS_CLIENT));
+    }
+
+    #[tokio::test]
+    async fn test_read_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let parser = tokio::spawn(crate::transaction_csv::parse_tcp(listener, tx, ParseOptions::default()));
+        let data = Arc::new(Mutex::new(HashMap::<client_data::ClientID, Box<ClientData>>::new()));
+        let handler = tokio::spawn(crate::command_handler::handle_commands(data.clone(), rx, crate::command_handler::HandlerOptions::default()));
+
+        // the rows may arrive in several reads, so the csv is split mid-row
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5").await.unwrap();
+        client.flush().await.unwrap();
+        client.write_all(b".0\nwithdrawal,1,3,4.5\ndispute,2,2,\n").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        // closing the connection finishes parsing, and so handling
+        parser.await.unwrap().unwrap();
+        assert_eq!(4, handler.await.unwrap().unwrap().executed);
+
+        {
+            let data = data.lock().unwrap();
+            crate::test_support::assert_balances_eq(data.get(&1).unwrap(), dec!(5.5), dec!(0), dec!(5.5));
+            crate::test_support::assert_balances_eq(data.get(&2).unwrap(), dec!(0), dec!(5), dec!(5));
         }
+
+        // only one connection is accepted
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
     }
 
-    // where there is a todo!() in this test..
-    //     there isn't a great way to finish this at the moment: https://users.rust-lang.org/t/how-to-test-output-to-stdout/4877/4
-    #[allow(unreachable_code)]
-    #[allow(unused)]
-    #[ignore]
     #[tokio::test]
-    async fn test_write() {
+    async fn test_read_dir() {
+        let dir = tempdir().unwrap();
+        // named so the deposits sort first, though the withdrawals are written first
+        std::fs::write(dir.path().join("2024-01-02.csv"), "type,client,tx,amount\nwithdrawal,1,3,4.5\ndispute,2,2,\n").unwrap();
+        std::fs::write(dir.path().join("2024-01-01.CSV"), "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n").unwrap();
+        // files which are not csv, and directories, are ignored
+        std::fs::write(dir.path().join("notes.txt"), "withdrawal,1,4,5.5\n").unwrap();
+        std::fs::create_dir(dir.path().join("archive.csv")).unwrap();
 
-        // client, available, held, total, locked
-        // 4, 0.0, 36.0, 36.0, true
-        // 2, 33.0, 4.0, 37.0, false
-        // 1, 30.0, 2.0, 32.0, false
-        // 5, -6.0, 0.0, -6.0, true
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let parser = tokio::spawn(crate::transaction_csv::parse_dir(dir.path().to_str().unwrap().to_owned(), tx, ParseOptions::default()));
+        let data = Arc::new(Mutex::new(HashMap::<client_data::ClientID, Box<ClientData>>::new()));
+        let handler = tokio::spawn(crate::command_handler::handle_commands(data.clone(), rx, crate::command_handler::HandlerOptions::default()));
+
+        parser.await.unwrap().unwrap();
+        assert_eq!(4, handler.await.unwrap().unwrap().executed);
+        {
+            let data = data.lock().unwrap();
+            assert_balances_eq(data.get(&1).unwrap(), dec!(5.5), dec!(0), dec!(5.5));
+            assert_balances_eq(data.get(&2).unwrap(), dec!(0), dec!(5), dec!(5));
+        }
+
+        // a missing directory is an error
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let missing = dir.path().join("missing");
+        let result = crate::transaction_csv::parse_dir(missing.to_str().unwrap().to_owned(), tx, ParseOptions::default()).await;
+        assert!(matches!(result, Err(ParseError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_write_columns() {
         let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
-        data.insert(
-            4,
-            Box::new( {
-                let mut ret = ClientData::new();
-                assert_ok!(ret.deposit(3, dec!(3333333.3333)));
-                assert_ok!(ret.deposit(17, dec!(36)));
-                assert_ok!(ret.dispute(3));
-                assert_ok!(ret.dispute(17));
-                assert_ok!(ret.chargeback(3));
-                ret
-            } )
-        );
-        data.insert(
-            2,
-            Box::new( {
-                let mut ret = ClientData::new();
-                assert_ok!(ret.deposit(3, dec!(99999999.9999)));
-                assert_ok!(ret.withdraw(dec!(99999966.9999)));
-                assert_ok!(ret.deposit(8, dec!(4)));
-                assert_ok!(ret.dispute(8));
-                ret
-            } )
-        );
-        data.insert(
-            1,
-            Box::new( {
-                let mut ret = ClientData::new();
-                assert_ok!(ret.deposit(51, dec!(2)));
-                assert_ok!(ret.deposit(52, dec!(30)));
-                assert_ok!(ret.dispute(51));
-                ret
-            } )
-        );
-        data.insert(
-            5,
-            Box::new( {
-                let mut ret = ClientData::new();
-                assert_ok!(ret.deposit(55, dec!(6)));
-                assert_ok!(ret.withdraw(dec!(6)));
-                assert_ok!(ret.dispute(55));
-                assert_ok!(ret.chargeback(55));
-                ret
-            } )
-        );
+        data.insert(2, Box::new(ClientData::builder().wealth(dec!(3)).held_wealth(dec!(1.5)).frozen(true).build().unwrap()));
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(5)).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
 
-        if let Ok(dir) = tempdir() {
+        // a reordered subset, which overrides with_counts, in the header, records, and summary row alike
+        for stream_output in [false, true] {
+            let mut output: Vec<u8> = Vec::new();
+            let columns = vec![Column::Total, Column::Client, Column::Locked];
+            let options = WriteOptions { columns: Some(columns), with_counts: true, with_totals: true, stream_output, ..WriteOptions::default() };
+            crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+            assert_eq!("total,client,locked\n5,1,false\n4.5,2,true\n9.5,TOTAL,\n", String::from_utf8(output).unwrap());
+        }
 
-            let file_path = dir.path().join("temp_output.csv").to_str().unwrap().to_owned();
-            if let Ok(file) = tokio::fs::File::create(file_path).await {
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { columns: Some(vec![Column::TxCount, Column::Held]), ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+        assert_eq!("tx_count,held\n0,0.0000\n0,1.5\n", String::from_utf8(output).unwrap());
 
-                todo!();
-// direct std out to the file at the path.
-// tokio::io::stdout().;
+        assert_eq!(Some(Column::TxCount), Column::from_name("tx_count"));
+        assert_eq!(None, Column::from_name("balance"));
+    }
 
-                let temp = Arc::new(Mutex::new(data));
-                crate::transaction_csv::write_csv(temp.clone()).await;
-            }
-            else {
-                panic!("failed to create tokio file");
+    #[tokio::test]
+    async fn test_write_bool_format() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(5)).build().unwrap()));
+        data.insert(2, Box::new(ClientData::builder().wealth(dec!(3)).frozen(true).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
+
+        for stream_output in [false, true] {
+            let mut output: Vec<u8> = Vec::new();
+            let options = WriteOptions { bool_format: BoolFormat::Int, stream_output, ..WriteOptions::default() };
+            crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+            assert_eq!("client,available,held,total,locked\n1,5,0.0000,5,0\n2,3,0.0000,3,1\n", String::from_utf8(output).unwrap());
+        }
+
+        // text is the default
+        let mut output: Vec<u8> = Vec::new();
+        crate::transaction_csv::write_csv(data, &mut output, &WriteOptions::default()).await.unwrap();
+        assert_eq!("client,available,held,total,locked\n1,5,0.0000,5,false\n2,3,0.0000,3,true\n", String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_write_locked_only() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(5)).build().unwrap()));
+        data.insert(2, Box::new(ClientData::builder().wealth(dec!(3)).frozen(true).build().unwrap()));
+        data.insert(3, Box::new(ClientData::builder().wealth(dec!(1)).build().unwrap()));
+        data.insert(4, Box::new(ClientData::builder().wealth(dec!(2)).frozen(true).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
+
+        for stream_output in [false, true] {
+            let mut output: Vec<u8> = Vec::new();
+            let options = WriteOptions { locked_only: true, with_totals: true, stream_output, ..WriteOptions::default() };
+            crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+            assert_eq!("client,available,held,total,locked\n2,3,0.0000,3,true\n4,2,0.0000,2,true\nTOTAL,5,0.0000,5,\n", String::from_utf8(output).unwrap());
+        }
+
+        // with no locked accounts, only the header is written
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(5)).build().unwrap()));
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { locked_only: true, ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(Arc::new(Mutex::new(data)), &mut output, &options).await.unwrap();
+        assert_eq!("client,available,held,total,locked\n", String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_write_fixed() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(7, Box::new(ClientData::builder().wealth(dec!(-0.25)).held_wealth(dec!(12.5)).frozen(true).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
+
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { format: OutputFormat::Fixed(FIXED_WIDTHS.to_vec()), with_counts: true, ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(concat!(
+            "client available            held                 total                locked tx_count  \n",
+            "7      -0.25                12.5                 12.25                true   0         \n",
+        ), output);
+        for line in output.lines() {
+            assert_eq!(FIXED_WIDTHS.iter().sum::<usize>(), line.len());
+        }
+
+        // a value longer than its column is truncated, with a warning, and a column without a width is not padded
+        let options = WriteOptions { format: OutputFormat::Fixed(vec![3, 4]), ..WriteOptions::default() };
+        let mut output: Vec<u8> = Vec::new();
+        crate::logger::capture::start();
+        crate::transaction_csv::write_csv(data, &mut output, &options).await.unwrap();
+        let logs = crate::logger::capture::take();
+        assert_eq!("cliavaiheldtotallocked\n7  -0.212.512.25true\n", String::from_utf8(output).unwrap());
+        assert_eq!(3, logs.len());
+        assert!(logs[2].ends_with("The value -0.25 is longer than the 4 characters of its column, so it is written as -0.2."));
+    }
+
+    #[tokio::test]
+    async fn test_write_negative_zero() {
+        let negative_zero = |scale| {
+            let mut zero = Decimal::new(0, scale);
+            zero.set_sign_negative(true);
+            zero
+        };
+        assert_eq!("-0", negative_zero(0).to_string());
+
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(negative_zero(0)).build().unwrap()));
+        data.insert(2, Box::new(ClientData::builder().wealth(negative_zero(4)).held_wealth(negative_zero(2)).build().unwrap()));
+        // too small to survive rounding, so it is written as a zero as well
+        data.insert(3, Box::new(ClientData::builder().wealth(dec!(-0.00001)).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
+
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { quiet_rounding: true, with_totals: true, ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(data, &mut output, &options).await.unwrap();
+        assert_eq!(concat!(
+            "client,available,held,total,locked\n",
+            "1,0.0000,0.0000,0.0000,false\n",
+            "2,0.0000,0.0000,0.0000,false\n",
+            "3,0.0000,0.0000,0.0000,false\n",
+            "TOTAL,0.0000,0.0000,0.0000,\n",
+        ), String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_write_clamp_negative() {
+        // a withdrawal followed by a chargeback of the deposit it spent leaves the client negative
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(1, dec!(10)));
+        assert_eq!(Ok(()), client.withdraw(2, dec!(4)));
+        assert_eq!(Ok(()), client.dispute(1));
+        assert_eq!(Ok(()), client.chargeback(1));
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(client));
+        let data = Arc::new(Mutex::new(data));
+
+        crate::logger::capture::start();
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { clamp_negative: true, with_totals: true, ..WriteOptions::default() };
+        crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.unwrap();
+        let logs = crate::logger::capture::take();
+        assert_eq!("client,available,held,total,locked\n1,0.0000,0.0000,0.0000,true\nTOTAL,0.0000,0.0000,0.0000,\n", String::from_utf8(output).unwrap());
+        assert_eq!(2, logs.len());
+        assert!(logs[0].starts_with("Warning!") && logs[0].ends_with("The available balance of client 1 is -4, which is reported as 0."));
+        assert!(logs[1].ends_with("The total balance of client 1 is -4, which is reported as 0."));
+
+        // the client data keeps its true balances
+        crate::test_support::assert_balances_eq(data.lock().unwrap().get(&1).unwrap(), dec!(-4), dec!(0), dec!(-4));
+
+        // without clamping, the true balances are reported
+        let mut output: Vec<u8> = Vec::new();
+        crate::transaction_csv::write_csv(data, &mut output, &WriteOptions::default()).await.unwrap();
+        assert_eq!("client,available,held,total,locked\n1,-4,0.0000,-4,true\n", String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_tab_delimited() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("tab_transactions.csv");
+        std::fs::write(&file_path, "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.5\nwithdrawal\t1\t2\t0.5\ndispute\t1\t1\t\n").unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let options = ParseOptions { delimiter: b'\t', ..ParseOptions::default() };
+        crate::transaction_csv::parse_csv(file_path.to_str().unwrap().to_owned(), tx, options).await.unwrap();
+
+        let cmd = rx.recv().await.unwrap();
+        assert_eq!(cmd.get_type(), CommandType::Deposit);
+        assert_eq!(cmd.get_client_id(), 1);
+        assert_eq!(cmd.get_transaction_id(), 1);
+        assert_eq!(cmd.get_wealth().unwrap(), dec!(1.5));
+
+        let cmd = rx.recv().await.unwrap();
+        assert_eq!(cmd.get_type(), CommandType::Withdraw);
+        assert_eq!(cmd.get_wealth().unwrap(), dec!(0.5));
+
+        let cmd = rx.recv().await.unwrap();
+        assert_eq!(cmd.get_type(), CommandType::Dispute);
+        assert!(cmd.get_wealth().is_none());
+
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_delimited() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(2.5)).held_wealth(dec!(1)).build().unwrap()));
+
+        let mut output: Vec<u8> = Vec::new();
+        let options = WriteOptions { delimiter: b';', ..WriteOptions::default() };
+        assert!(crate::transaction_csv::write_csv(Arc::new(Mutex::new(data)), &mut output, &options).await.is_ok());
+
+        assert_eq!("client;available;held;total;locked\n1;2.5;1;3.5;false\n", String::from_utf8(output).unwrap());
+    }
+
+    // One line of the balance csv, as read back by a csv deserializer
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct Balance {
+        client: client_data::ClientID,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+        locked: bool,
+    }
+
+    #[tokio::test]
+    async fn test_write_round_trip() {
+        for delimiter in [b',', b';', b'\t'] {
+            let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+            data.insert(1, Box::new(ClientData::builder().wealth(dec!(2.5)).held_wealth(dec!(1)).build().unwrap()));
+            data.insert(7, Box::new(ClientData::builder().wealth(dec!(-3.1234)).frozen(true).build().unwrap()));
+
+            let mut output: Vec<u8> = Vec::new();
+            let options = WriteOptions { delimiter, ..WriteOptions::default() };
+            assert!(crate::transaction_csv::write_csv(Arc::new(Mutex::new(data)), &mut output, &options).await.is_ok());
+
+            let mut rdr = csv_async::AsyncReaderBuilder::new()
+                .trim(csv_async::Trim::All)
+                .delimiter(delimiter)
+                .create_deserializer(output.as_slice());
+            let mut balances: Vec<Balance> = tokio_stream::StreamExt::collect::<Vec<_>>(
+                tokio_stream::StreamExt::map(rdr.deserialize::<Balance>(), |record| record.unwrap())
+            ).await;
+            balances.sort_by_key(|balance| balance.client);
+
+            assert_eq!(vec![
+                Balance { client: 1, available: dec!(2.5), held: dec!(1), total: dec!(3.5), locked: false },
+                Balance { client: 7, available: dec!(-3.1234), held: dec!(0), total: dec!(-3.1234), locked: true },
+            ], balances);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_closed_channel() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("closed_transactions.csv");
+        let mut content = String::from("type,client,tx,amount\n");
+        for tx in 0..20 {
+            content += &format!("deposit,1,{},1.0\n", tx);
+        }
+        std::fs::write(&file_path, content).unwrap();
+
+        // a channel small enough that the parser must wait on the receiver
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let parser = tokio::spawn(crate::transaction_csv::parse_csv(file_path.to_str().unwrap().to_owned(), tx, ParseOptions::default()));
+
+        assert!(rx.recv().await.is_some());
+        crate::logger::capture::start();
+        drop(rx);
+
+        // parsing stops at the first record which cannot be sent, without reading the rest of the file
+        assert!(timeout(Duration::from_millis(1500), parser).await.expect("parse_csv did not stop").is_ok());
+        let logs = crate::logger::capture::take();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].contains("; 1 parsed records were not sent, starting with"));
+    }
+
+    #[tokio::test]
+    async fn test_write_statements() {
+        let mut client = ClientData::new();
+        assert_eq!(Ok(()), client.deposit(8, dec!(10)));
+        assert_eq!(Ok(()), client.deposit_at(3, dec!(2.5), Some("2024-05-01T09:30:00Z".parse().unwrap())));
+        assert_eq!(Ok(()), client.dispute(8));
+
+        let mut other = ClientData::new();
+        assert_eq!(Ok(()), other.deposit(1, dec!(4)));
+
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(2, Box::new(other));
+        data.insert(1, Box::new(client));
+
+        let mut output: Vec<u8> = Vec::new();
+        assert!(crate::transaction_csv::write_statements(Arc::new(Mutex::new(data)), &mut output).await.is_ok());
+
+        assert_eq!("client,tx,amount,disputed,time\n1,3,2.5,false,2024-05-01T09:30:00Z\n1,8,10,true,\n2,1,4,false,\n", String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_bom() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("bom_transactions.csv");
+        std::fs::write(&file_path, b"\xEF\xBB\xBFtype,client,tx,amount\ndeposit,1,1,2.0\n").unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        crate::transaction_csv::parse_csv(file_path.to_str().unwrap().to_owned(), tx, ParseOptions::default()).await.unwrap();
+
+        let cmd = rx.recv().await.unwrap();
+        assert_eq!(cmd.get_type(), CommandType::Deposit);
+        assert_eq!(cmd.get_client_id(), 1);
+        assert_eq!(cmd.get_wealth().unwrap(), dec!(2.0));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_empty() {
+        // a zero-byte file, and a file with only headers, have no transactions, however they are read
+        for content in [&b""[..], &b"type,client,tx,amount\n"[..]] {
+            for (schema, shards) in [(Schema::Unchecked, 1), (Schema::Exact, 1), (Schema::Unchecked, 2)] {
+                crate::logger::capture::start();
+                let commands = parse(content, ParseOptions { schema, shards, ..ParseOptions::default() }).await;
+                let logged = crate::logger::capture::take();
+                assert!(commands.is_empty());
+                assert_eq!(1, logged.len(), "{:?}", logged);
+                assert!(logged[0].starts_with("Info:  No transactions were found in "), "{:?}", logged);
             }
+        }
 
-            let hdr = "client,available,held,total,locked";
-            let c5 = "5,-6,0,-6,true";
-            let c4 = "4,0.0000,36.0000,36.0000,true";
-            let c1 = "1,30,2,32,false";
-            let c2 = "2,33.0000,4,37.0000,false";
-
-            if let Ok(actual_content) = tokio::fs::read_to_string(&file_path).await {
-                actual_content.split('\n').for_each(|line| {
-                    if line.len() > 0 {
-                        let line_content = line.split_once(',');
-                        match line_content.unwrap().0 {
-                            "client" => assert_eq!(hdr, line),
-                            "5" => assert_eq!(c5, line),
-                            "4" => assert_eq!(c4, line),
-                            "1" => assert_eq!(c1, line),
-                            "2" => assert_eq!(c2, line),
-                            _ => panic!(),
-                        }
-                    }
-                });
+        // the output still has its header
+        let data = Arc::new(Mutex::new(HashMap::<client_data::ClientID, Box<ClientData>>::new()));
+        let mut output: Vec<u8> = Vec::new();
+        crate::transaction_csv::write_csv(data, &mut output, &WriteOptions::default()).await.unwrap();
+        assert_eq!("client,available,held,total,locked\n", String::from_utf8(output).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_write_rounding() {
+        assert_eq!(Some(dec!(0.00005)), super::rounding_delta(dec!(10.00005)));
+        assert_eq!(Some(dec!(-0.00005)), super::rounding_delta(dec!(10.00015)));
+        assert_eq!(None, super::rounding_delta(dec!(10.0001)));
+        assert_eq!(None, super::rounding_delta(dec!(10)));
+
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        data.insert(1, Box::new(ClientData::builder().wealth(dec!(10.00005)).build().unwrap()));
+        let data = Arc::new(Mutex::new(data));
+
+        // rounding is output the same rather or not it is warned about
+        for quiet_rounding in [false, true] {
+            let mut output: Vec<u8> = Vec::new();
+            let options = WriteOptions { quiet_rounding, ..WriteOptions::default() };
+            assert!(crate::transaction_csv::write_csv(data.clone(), &mut output, &options).await.is_ok());
+            assert_eq!("client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n", String::from_utf8(output).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_final_newline() {
+        for content in ["type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5\n", "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.5"] {
+            let commands = parse(content.as_bytes(), ParseOptions::default()).await;
+            assert_eq!(2, commands.len());
+            assert_eq!(commands[1].get_transaction_id(), 2);
+            assert_eq!(commands[1].get_wealth().unwrap(), dec!(2.5));
+        }
+
+        // the final record may also lack its optional amount
+        let commands = parse(b"type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1", ParseOptions::default()).await;
+        assert_eq!(2, commands.len());
+        assert_eq!(commands[1].get_type(), CommandType::Dispute);
+        assert!(commands[1].get_wealth().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_streamed() {
+        let mut data: HashMap<client_data::ClientID, Box<client_data::ClientData>> = HashMap::new();
+        for client_id in 0..50 {
+            let mut client = ClientData::new();
+            assert_eq!(Ok(()), client.deposit(1, Decimal::from(client_id) / dec!(3)));
+            if client_id % 3 == 0 {
+                assert_eq!(Ok(()), client.dispute(1));
             }
-            else {
-                panic!("Could not read file to string for write test.");
+            data.insert(client_id, Box::new(client));
+        }
+        let data = Arc::new(Mutex::new(data));
+
+        let mut batched: Vec<u8> = Vec::new();
+        let options = WriteOptions { with_counts: true, quiet_rounding: true, ..WriteOptions::default() };
+        assert!(crate::transaction_csv::write_csv(data.clone(), &mut batched, &options).await.is_ok());
+
+        let mut streamed: Vec<u8> = Vec::new();
+        let options = WriteOptions { with_counts: true, quiet_rounding: true, stream_output: true, ..WriteOptions::default() };
+        assert!(crate::transaction_csv::write_csv(data.clone(), &mut streamed, &options).await.is_ok());
+
+        assert_eq!(51, String::from_utf8(streamed.clone()).unwrap().lines().count());
+        assert_eq!(String::from_utf8(batched).unwrap(), String::from_utf8(streamed).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_missing_amount() {
+        // commands missing an ammount are passed on, as whether they are skipped is the handler's AmountPolicy
+        let commands = parse(b"type,client,tx,amount\ndeposit,1,1,\nwithdrawal,1,2\ndeposit,1,3,1.0\ndispute,1,3,\n", ParseOptions::default()).await;
+        assert_eq!(4, commands.len());
+        assert!(commands[0].get_wealth().is_none());
+        assert!(commands[1].get_wealth().is_none());
+        assert_eq!(commands[2].get_transaction_id(), 3);
+        assert_eq!(commands[3].get_type(), CommandType::Dispute);
+    }
+
+    #[tokio::test]
+    async fn test_read_reorder_window() {
+        let content = b"type,client,tx,amount\ndeposit,1,3,1.0\ndispute,1,1,\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n";
+
+        // within the window, commands are dispatched in tx order, with deposits before their disputes
+        let commands = parse(content, ParseOptions { reorder_window: 4, ..ParseOptions::default() }).await;
+        let order: Vec<(u32, CommandType)> = commands.iter().map(|cmd| (cmd.get_transaction_id(), cmd.get_type())).collect();
+        assert_eq!(vec![(1, CommandType::Deposit), (1, CommandType::Dispute), (2, CommandType::Deposit), (3, CommandType::Deposit)], order);
+
+        // without a window, file order is kept
+        let commands = parse(content, ParseOptions::default()).await;
+        let order: Vec<u32> = commands.iter().map(|cmd| cmd.get_transaction_id()).collect();
+        assert_eq!(vec![3, 1, 1, 2], order);
+
+        // a window too small cannot fix commands which arrive too far out of order
+        let commands = parse(b"type,client,tx,amount\ndeposit,1,3,1.0\ndeposit,1,2,1.0\ndeposit,1,1,1.0\n", ParseOptions { reorder_window: 1, ..ParseOptions::default() }).await;
+        let order: Vec<u32> = commands.iter().map(|cmd| cmd.get_transaction_id()).collect();
+        assert_eq!(vec![2, 1, 3], order);
+    }
+
+    #[tokio::test]
+    async fn test_read_reorder_by_time() {
+        let content = concat!(
+            "type,client,tx,amount,time\n",
+            "deposit,1,1,1.0,2024-05-01T10:00:00Z\n",
+            "deposit,1,2,1.0,2024-05-01T09:00:00Z\n",
+            "deposit,1,3,1.0,2024-05-01T11:00:00+02:00\n",
+            "deposit,1,4,1.0,\n",
+        );
+
+        // within the window, commands are dispatched in time order, with those lacking a time first
+        let commands = parse(content.as_bytes(), ParseOptions { reorder_window: 4, reorder_by_time: true, ..ParseOptions::default() }).await;
+        let order: Vec<u32> = commands.iter().map(|cmd| cmd.get_transaction_id()).collect();
+        assert_eq!(vec![4, 2, 3, 1], order);
+
+        // otherwise the time is ignored when reordering
+        let commands = parse(content.as_bytes(), ParseOptions { reorder_window: 4, ..ParseOptions::default() }).await;
+        let order: Vec<u32> = commands.iter().map(|cmd| cmd.get_transaction_id()).collect();
+        assert_eq!(vec![1, 2, 3, 4], order);
+    }
+
+    #[tokio::test]
+    async fn test_read_timestamp() {
+        let content = b"type,client,tx,amount,time\ndeposit,1,1,1.0,2024-05-01T09:30:00Z\ndeposit,1,2,1.0,yesterday\ndeposit,1,3,1.0\n";
+
+        // an invalid time is dropped with a warning, keeping the rest of the row
+        crate::logger::capture::start();
+        let commands = parse(content, ParseOptions::default()).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(3, commands.len());
+        assert_eq!(Some("2024-05-01T09:30:00Z".parse().unwrap()), commands[0].get_timestamp());
+        assert_eq!(None, commands[1].get_timestamp());
+        assert_eq!(None, commands[2].get_timestamp());
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
+        assert!(logs[0].contains("yesterday"));
+
+        // files without a time column parse as before
+        let commands = parse(b"type,client,tx,amount\ndeposit,1,1,1.0\n", ParseOptions::default()).await;
+        assert_eq!(None, commands[0].get_timestamp());
+    }
+
+    #[tokio::test]
+    async fn test_read_strip_currency() {
+        let content = "type,client,tx,amount\ndeposit,1,1,$5.00\ndeposit,1,2,5.00 USD\ndeposit,1,3,5.00 dollars\ndispute,1,1,\n";
+
+        // decorated ammounts parse, while a genuinely bad one is still skipped with a warning
+        crate::logger::capture::start();
+        let commands = parse(content.as_bytes(), ParseOptions { strip_currency: true, ..ParseOptions::default() }).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(vec![1, 2, 1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        assert_eq!(Some(dec!(5.00)), *commands[0].get_wealth());
+        assert_eq!(Some(dec!(5.00)), *commands[1].get_wealth());
+        assert_eq!(None, *commands[2].get_wealth());
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!") && logs[0].contains("line 4"));
+
+        // sharded parsing strips them as well
+        let commands = parse(content.as_bytes(), ParseOptions { strip_currency: true, shards: 2, ..ParseOptions::default() }).await;
+        assert_eq!(3, commands.len());
+
+        // by default, ammounts are parsed strictly
+        let commands = parse(content.as_bytes(), ParseOptions::default()).await;
+        assert_eq!(vec![1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_read_max_record_bytes() {
+        let oversized = format!("deposit,1,2,{}1.0\n", " ".repeat(100));
+        let content = format!("type,client,tx,amount\ndeposit,1,1,1.0\n{}deposit,1,3,2.0\ndeposit,one,4,1.0\n", oversized);
+        let options = || ParseOptions { max_record_bytes: 64, ..ParseOptions::default() };
+
+        // the oversized record is skipped with an error under lenient, without disturbing the lines of later records
+        crate::logger::capture::start();
+        let commands = parse(content.as_bytes(), options()).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(vec![1, 3], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        assert_eq!(2, logs.len());
+        assert!(logs[0].starts_with("ERROR!"));
+        assert!(logs[0].contains("line 3"));
+        assert!(logs[1].contains("line 5"));
+
+        // sharded parsing enforces the same limit
+        let commands = parse(content.as_bytes(), ParseOptions { shards: 2, ..options() }).await;
+        assert_eq!(vec![1, 3], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+
+        // an unterminated quote is one record running to the end of the file
+        let commands = parse(format!("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\"1.0\n{}", oversized).as_bytes(), options()).await;
+        assert_eq!(vec![1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+
+        // and aborts parsing under strict, after the commands before it were sent
+        let (commands, result) = parse_until_abort(content.as_bytes(), ParseOptions { mode: Mode::Strict, ..options() }).await;
+        assert!(matches!(result, Err(ParseError::Anomaly(_))));
+        assert_eq!(vec![1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+
+        // records within the default limit are unaffected
+        let commands = parse(content.as_bytes(), ParseOptions::default()).await;
+        assert_eq!(3, commands.len());
+    }
+
+    #[tokio::test]
+    async fn test_read_column_map() {
+        let column_map: HashMap<String, String> = [("kind", "type"), ("cust", "client"), ("ref", "tx"), ("value", "amount")].iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect();
+        let options = ParseOptions { column_map, ..ParseOptions::default() };
+
+        let commands = parse(b"value, ref, cust, kind\n2.5, 1, 4, deposit\n, 1, 4, dispute\n", options).await;
+        assert_eq!(2, commands.len());
+        assert_eq!(commands[0].get_type(), CommandType::Deposit);
+        assert_eq!(commands[0].get_client_id(), 4);
+        assert_eq!(commands[0].get_transaction_id(), 1);
+        assert_eq!(commands[0].get_wealth().unwrap(), dec!(2.5));
+        assert_eq!(commands[1].get_type(), CommandType::Dispute);
+    }
+
+    #[tokio::test]
+    async fn test_read_schema() {
+        // a header missing the amount column is rejected before any row is parsed
+        let content = b"type, client, tx, value\ndeposit, 1, 1, 2.5\n";
+        let options = || ParseOptions { schema: Schema::Exact, ..ParseOptions::default() };
+        crate::logger::capture::start();
+        let (commands, result) = parse_until_abort(content, options()).await;
+        let logs = crate::logger::capture::take();
+        assert!(commands.is_empty());
+        match result {
+            Err(ParseError::Schema { missing, unexpected }) => {
+                assert_eq!(vec!["amount"], missing);
+                assert_eq!(vec!["value"], unexpected);
+            },
+            _ => panic!("expected a schema error"),
+        }
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("ERROR!") && logs[0].ends_with("the headers do not match the columns type, client, tx, amount; missing amount; unexpected value"));
+
+        // the column map renames headers before they are checked, and the order of the columns does not matter
+        let column_map = HashMap::from([("value".to_owned(), "amount".to_owned())]);
+        assert_eq!(1, parse(content, ParseOptions { column_map, ..options() }).await.len());
+        assert_eq!(1, parse(b"amount, tx, client, type, time\n2.5, 1, 1, deposit,\n", options()).await.len());
+
+        // other columns are only allowed under Schema::AllowExtras, while the canonical columns are still required
+        let content = b"type, client, tx, amount, note\ndeposit, 1, 1, 2.5, hi\n";
+        assert!(matches!(parse_until_abort(content, options()).await.1, Err(ParseError::Schema { .. })));
+        assert_eq!(1, parse(content, ParseOptions { schema: Schema::AllowExtras, ..ParseOptions::default() }).await.len());
+        let result = parse_until_abort(b"type, client, tx\ndispute, 1, 1\n", ParseOptions { schema: Schema::AllowExtras, ..ParseOptions::default() }).await.1;
+        assert!(matches!(result, Err(ParseError::Schema { missing, .. }) if missing == vec!["amount"]));
+
+        // sharded parsing checks the headers as well
+        let result = parse_until_abort(b"type, client, tx\ndispute, 1, 1\n", ParseOptions { shards: 2, ..options() }).await.1;
+        assert!(matches!(result, Err(ParseError::Schema { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_read_shards() {
+        let mut content = String::from("\u{FEFF}type, client, tx, amount\n");
+        for tx in 1..=50 {
+            content += &format!("deposit, {}, {}, {}.5\n", tx % 7, tx, tx);
+            if tx % 5 == 0 {
+                content += &format!("dispute, {}, {},\n", tx % 7, tx);
             }
         }
+        content += "withdrawal, 1, 51, 1.0";
+
+        let sequential = parse(content.as_bytes(), ParseOptions::default()).await;
+        assert_eq!(61, sequential.len());
+        for shards in [2, 3, 8, 100] {
+            assert_eq!(sequential, parse(content.as_bytes(), ParseOptions { shards, ..ParseOptions::default() }).await);
+        }
 
+        // records keep their line in the whole file
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("sharded_transactions.csv");
+        std::fs::write(&file_path, &content).unwrap();
+        let records = crate::transaction_csv::parse_shards(file_path.to_str().unwrap(), &ParseOptions { shards: 4, ..ParseOptions::default() }).await.unwrap();
+        let lines: Vec<u64> = records.iter().map(|record| record.line).collect();
+        assert_eq!((2..=62).collect::<Vec<u64>>(), lines);
     }
 
-}
+    #[tokio::test]
+    async fn test_read_excess_precision() {
+        let content = b"type,client,tx,amount\ndeposit,1,1,5.12345\ndeposit,1,2,1.5\n";
+
+        let commands = parse(content, ParseOptions::default()).await;
+        assert_eq!(2, commands.len());
+        assert_eq!(commands[0].get_wealth().unwrap(), dec!(5.1234));
+
+        let (commands, result) = parse_until_abort(content, ParseOptions { mode: Mode::Strict, ..ParseOptions::default() }).await;
+        assert!(matches!(result, Err(ParseError::Anomaly(_))));
+        assert!(commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_extra_fields() {
+        let content = b"type,client,tx,amount\ndeposit,1,1,5.0,bogus\ndeposit,1,2,1.5\ndispute,1,2\n";
+
+        // rows may omit the ammount, and extra fields are ignored by default
+        let commands = parse(content, ParseOptions::default()).await;
+        assert_eq!(3, commands.len());
+        assert_eq!(commands[0].get_wealth().unwrap(), dec!(5.0));
+
+        let (commands, result) = parse_until_abort(content, ParseOptions { mode: Mode::Strict, ..ParseOptions::default() }).await;
+        assert!(matches!(result, Err(ParseError::Anomaly(_))));
+        assert!(commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_mode() {
+        let content = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,one,2,1.5\ndeposit,1,3,2.0\n";
+
+        // a bad row is skipped with a warning under lenient
+        crate::logger::capture::start();
+        let commands = parse(content, ParseOptions::default()).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(vec![1, 3], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!"));
+
+        // and aborts parsing under strict, after the commands before it were sent
+        let (commands, result) = parse_until_abort(content, ParseOptions { mode: Mode::Strict, ..ParseOptions::default() }).await;
+        assert!(matches!(result, Err(ParseError::Anomaly(_))));
+        assert_eq!(vec![1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+    }
 
+    #[tokio::test]
+    async fn test_read_missing_file() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let result = crate::transaction_csv::parse_csv("no/such/transactions.csv".to_owned(), tx, ParseOptions::default()).await;
+        assert!(matches!(result, Err(ParseError::Io(_))));
+
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let result = crate::transaction_csv::parse_csv("no/such/transactions.csv".to_owned(), tx, ParseOptions { shards: 2, ..ParseOptions::default() }).await;
+        assert!(matches!(result, Err(ParseError::Io(_))));
+
+        // a missing file is not retried
+        crate::logger::capture::start();
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let result = crate::transaction_csv::parse_csv("no/such/transactions.csv".to_owned(), tx, ParseOptions { open_retries: 3, ..ParseOptions::default() }).await;
+        let logs = crate::logger::capture::take();
+        assert!(matches!(result, Err(ParseError::Io(_))));
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("ERROR!"));
+    }
+
+    #[tokio::test]
+    async fn test_read_open_retries() {
+        // the file's directory is briefly a plain file, so opening fails with an error which is not NotFound until it is replaced
+        let dir = tempdir().unwrap();
+        let parent = dir.path().join("share");
+        let file_path = parent.join("transactions.csv");
+        std::fs::write(&parent, b"").unwrap();
+        let mount = {
+            let parent = parent.clone();
+            let file_path = file_path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                std::fs::remove_file(&parent).unwrap();
+                std::fs::create_dir(&parent).unwrap();
+                std::fs::write(&file_path, b"type,client,tx,amount\ndeposit,1,1,5.0\n").unwrap();
+            })
+        };
+
+        crate::logger::capture::start();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let result = crate::transaction_csv::parse_csv(file_path.to_str().unwrap().to_owned(), tx, ParseOptions { open_retries: 3, ..ParseOptions::default() }).await;
+        let logs = crate::logger::capture::take();
+        mount.await.unwrap();
+        assert!(result.is_ok());
+        assert_eq!(1, rx.recv().await.unwrap().get_transaction_id());
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!") && logs[0].contains("retrying in 100ms (1 of 3)"));
+
+        // without retries, the same failure is immediate
+        std::fs::remove_dir_all(&parent).unwrap();
+        std::fs::write(&parent, b"").unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let result = crate::transaction_csv::parse_csv(file_path.to_str().unwrap().to_owned(), tx, ParseOptions::default()).await;
+        assert!(matches!(result, Err(ParseError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_cents() {
+        let content = b"type,client,tx,amount\ndeposit,1,1,500\ndeposit,1,2,12.5\nwithdrawal,1,3,-100\ndispute,1,1,\n";
+
+        crate::logger::capture::start();
+        let commands = parse(content, ParseOptions { units: Units::Cents, ..ParseOptions::default() }).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(vec![1, 1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        assert_eq!(Some(dec!(5.00)), *commands[0].get_wealth());
+        assert_eq!(2, logs.len());
+        assert!(logs.iter().all(|log| log.starts_with("Warning!") && log.contains("InvalidCents")));
+
+        // dollars are read as they are
+        let commands = parse(content, ParseOptions::default()).await;
+        assert_eq!(Some(dec!(500)), *commands[0].get_wealth());
+    }
+
+    #[tokio::test]
+    async fn test_read_invalid_amount() {
+        let content = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,abc\ndeposit,1,3,1.0\n";
+
+        // under lenient, the row is skipped, naming the amount and its line
+        crate::logger::capture::start();
+        let commands = parse(content, ParseOptions::default()).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(vec![1, 3], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!") && logs[0].contains("The amount abc on line 3 of") && logs[0].ends_with("is not a number; skipping the row."));
+
+        // under strict, parsing stops with the amount and its line, after the commands before it were sent
+        let (commands, result) = parse_until_abort(content, ParseOptions { mode: Mode::Strict, ..ParseOptions::default() }).await;
+        assert_eq!(vec![1], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        match result {
+            Err(ParseError::InvalidAmount { line, value }) => {
+                assert_eq!(3, line);
+                assert_eq!("abc", value);
+            },
+            _ => panic!("expected an invalid amount"),
+        }
+
+        // other fields which cannot be deserialized are still reported as anomalies
+        let (_, result) = parse_until_abort(b"type,client,tx,amount\ndeposit,one,1,5.0\n", ParseOptions { mode: Mode::Strict, ..ParseOptions::default() }).await;
+        assert!(matches!(result, Err(ParseError::Anomaly(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_trim() {
+        let content = b"type,client,tx,amount\ndeposit,1,1, 5.0\ndeposit,1,2,3.0\n";
 
+        // padding is trimmed by default
+        let commands = parse(content, ParseOptions::default()).await;
+        assert_eq!(Some(dec!(5.0)), *commands[0].get_wealth());
+        assert_eq!(2, commands.len());
 
+        // without trimming, the padded ammount is not a decimal, so its row is skipped
+        crate::logger::capture::start();
+        let commands = parse(content, ParseOptions { trim: csv_async::Trim::None, ..ParseOptions::default() }).await;
+        let logs = crate::logger::capture::take();
+        assert_eq!(vec![2], commands.iter().map(|cmd| cmd.get_transaction_id()).collect::<Vec<_>>());
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("Warning!") && logs[0].contains("The amount  5.0 on line 2"));
+
+        // trimming only the headers leaves the fields padded as well
+        let commands = parse(content, ParseOptions { trim: csv_async::Trim::Headers, ..ParseOptions::default() }).await;
+        assert_eq!(1, commands.len());
+    }
+
+    #[tokio::test]
+    async fn test_read_cancelled() {
+        let content = b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,5.0\n";
+
+        // a cancelled parse sends nothing more, and is not an error
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        crate::logger::capture::start();
+        let commands = parse(content, ParseOptions { cancel: Some(cancel), ..ParseOptions::default() }).await;
+        let logs = crate::logger::capture::take();
+        assert!(commands.is_empty());
+        assert!(logs[0].contains("cancelled."));
+
+        // a token which is never cancelled changes nothing
+        let commands = parse(content, ParseOptions { cancel: Some(CancellationToken::new()), reorder_window: 4, ..ParseOptions::default() }).await;
+        assert_eq!(2, commands.len());
+    }
+}
@@ -0,0 +1,133 @@
+//! Runs the built binary against the csv files in tests/fixtures, comparing its output to the matching .expected files
+//!
+//! Output rows come from a HashMap, so their order is normalized by sorting every row after the header.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+}
+
+// Sorts the records of a csv, leaving the header first
+fn normalize(csv: &str) -> Vec<String> {
+    let mut lines = csv.lines().map(|line| line.to_owned());
+    let header = lines.next().unwrap_or_default();
+    let mut records: Vec<String> = lines.filter(|line| !line.is_empty()).collect();
+    records.sort();
+    std::iter::once(header).chain(records).collect()
+}
+
+// Runs the binary with the arguments, returning its exit code
+fn exit_code(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_transaction_parser"))
+        .args(args)
+        .output()
+        .expect("Could not run transaction_parser")
+        .status
+        .code()
+}
+
+fn run(name: &str) {
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction_parser"))
+        .arg(fixture(&format!("{}.csv", name)))
+        .output()
+        .expect("Could not run transaction_parser");
+    assert!(output.status.success(), "transaction_parser failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let expected = std::fs::read_to_string(fixture(&format!("{}.expected", name))).expect("Could not read expected output");
+    assert_eq!(normalize(&expected), normalize(&String::from_utf8(output.stdout).unwrap()));
+}
+
+#[test]
+fn test_happy_path() {
+    run("happy_path");
+}
+
+#[test]
+fn test_disputes() {
+    run("disputes");
+}
+
+#[test]
+fn test_exit_codes() {
+    let dir = tempfile::tempdir().expect("Could not get temp dir");
+    let malformed = dir.path().join("malformed.csv");
+    std::fs::write(&malformed, "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,one,2,1.0\n").expect("Could not write temp file");
+    let overdrawn = dir.path().join("overdrawn.csv");
+    std::fs::write(&overdrawn, "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\n").expect("Could not write temp file");
+    let malformed = malformed.to_str().unwrap();
+    let overdrawn = overdrawn.to_str().unwrap();
+    let happy_path = fixture("happy_path.csv");
+    let happy_path = happy_path.to_str().unwrap();
+
+    assert_eq!(Some(0), exit_code(&[happy_path]));
+    assert_eq!(Some(1), exit_code(&[]));
+    assert_eq!(Some(1), exit_code(&[happy_path, "--unknown"]));
+    assert_eq!(Some(2), exit_code(&["no/such/transactions.csv"]));
+    assert_eq!(Some(0), exit_code(&[malformed]));
+    assert_eq!(Some(2), exit_code(&[malformed, "--mode", "strict"]));
+    assert_eq!(Some(0), exit_code(&[overdrawn]));
+    assert_eq!(Some(3), exit_code(&[overdrawn, "--mode", "strict"]));
+}
+
+#[test]
+fn test_seed_from() {
+    let dir = tempfile::tempdir().expect("Could not get temp dir");
+    let day_one = dir.path().join("day_one.csv");
+    std::fs::write(&day_one, "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,4.0\ndeposit,1,3,2.5\ndispute,1,3,\n").expect("Could not write temp file");
+    let day_two = dir.path().join("day_two.csv");
+    std::fs::write(&day_two, "type,client,tx,amount\ndeposit,1,4,1.0\nwithdrawal,2,5,3.0\ndeposit,3,6,7.0\n").expect("Could not write temp file");
+
+    // the first day's output seeds the second day, whose balances compound on it
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction_parser"))
+        .arg(&day_one)
+        .output()
+        .expect("Could not run transaction_parser");
+    assert!(output.status.success(), "transaction_parser failed: {}", String::from_utf8_lossy(&output.stderr));
+    let seed = dir.path().join("seed.csv");
+    std::fs::write(&seed, &output.stdout).expect("Could not write temp file");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction_parser"))
+        .arg(&day_two)
+        .arg("--seed-from")
+        .arg(&seed)
+        .output()
+        .expect("Could not run transaction_parser");
+    assert!(output.status.success(), "transaction_parser failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(
+        normalize("client,available,held,total,locked\n1,11,2.5,13.5,false\n2,1,0.0000,1,false\n3,7,0.0000,7,false\n"),
+        normalize(&String::from_utf8(output.stdout).unwrap())
+    );
+
+    // a seed which cannot be read is a usage failure
+    assert_eq!(Some(1), exit_code(&[day_two.to_str().unwrap(), "--seed-from", "no/such/seed.csv"]));
+}
+
+#[test]
+fn test_manifest() {
+    let dir = tempfile::tempdir().expect("Could not get temp dir");
+    let overdrawn = dir.path().join("overdrawn.csv");
+    std::fs::write(&overdrawn, "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndeposit,2,3,1.0\n").expect("Could not write temp file");
+    let manifest = dir.path().join("manifest.json");
+    let overdrawn = overdrawn.to_str().unwrap();
+    let manifest = manifest.to_str().unwrap();
+
+    // the failed withdrawal is a warning, but the run still succeeds
+    assert_eq!(Some(0), exit_code(&[overdrawn, "--manifest", manifest]));
+    let written: serde_json::Value = serde_json::from_slice(&std::fs::read(manifest).expect("Could not read manifest")).expect("Could not parse manifest");
+    assert_eq!(overdrawn, written["input"]);
+    assert_eq!(3, written["commands"]);
+    assert_eq!(2, written["executed"]);
+    assert_eq!(1, written["failed"]);
+    assert_eq!(serde_json::json!({ "deposit": 2, "withdrawal": 1 }), written["by_type"]);
+    assert_eq!(1, written["warnings"]);
+    assert_eq!(0, written["exit_status"]);
+
+    // under strict mode the same input halts, which the manifest records
+    assert_eq!(Some(3), exit_code(&[overdrawn, "--manifest", manifest, "--mode", "strict"]));
+    let written: serde_json::Value = serde_json::from_slice(&std::fs::read(manifest).expect("Could not read manifest")).expect("Could not parse manifest");
+    assert_eq!(true, written["halted"]);
+    assert_eq!(3, written["exit_status"]);
+}
@@ -0,0 +1,53 @@
+//! Drives the transaction engine end to end through its public library API
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rust_decimal_macros::dec;
+use tokio::sync::mpsc;
+
+use transaction_parser::client_data::{ClientData, ClientID};
+use transaction_parser::command::Command;
+use transaction_parser::command_handler::HandlerOptions;
+use transaction_parser::transaction_csv::ParseOptions;
+
+#[tokio::test]
+async fn test_parse_and_handle() {
+
+    let dir = tempfile::tempdir().expect("Could not get temp dir");
+    let file_path = dir.path().join("library_transactions.csv");
+
+    std::fs::write(&file_path, concat!(
+        "type, client, tx, amount\n",
+        "deposit,    1,  1, 10.0\n",
+        "deposit,    2,  2, 5.5\n",
+        "withdrawal, 1,  3, 2.5\n",
+        "dispute,    2,  2,\n",
+        "deposit,    1,  4, 1.25\n",
+        "dispute,    1,  4,\n",
+        "chargeback, 1,  4,\n",
+    )).expect("Could not write temp file");
+
+    let (tx, rx) = mpsc::channel::<Command>(16);
+    let data = Arc::new(Mutex::new(HashMap::<ClientID, Box<ClientData>>::new()));
+
+    let parse = tokio::spawn(transaction_parser::parse_csv(file_path.to_str().unwrap().to_owned(), tx, ParseOptions::default()));
+    let handle = tokio::spawn(transaction_parser::handle_commands(data.clone(), rx, HandlerOptions::default()));
+
+    parse.await.expect("Couldn't await parse_csv").expect("parse_csv failed");
+    handle.await.expect("Couldn't await handle_commands").expect("handle_commands failed");
+
+    let clients = data.lock().unwrap();
+    assert_eq!(2, clients.len());
+
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.get_wealth(), dec!(7.5));
+    assert_eq!(client.get_held_wealth(), dec!(0));
+    assert!(client.is_locked());
+
+    let client = clients.get(&2).unwrap();
+    assert_eq!(client.get_wealth(), dec!(0));
+    assert_eq!(client.get_held_wealth(), dec!(5.5));
+    assert_eq!(client.get_total(), dec!(5.5));
+    assert!(!client.is_locked());
+}